@@ -5,6 +5,7 @@ use std::fs;
 use std::io::Read;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use toml::{self, Value};
 
 /// Structure representing the configuration along with
@@ -31,16 +32,40 @@ pub struct ModuleDef {
     pub subscriptions: HashMap<String, Vec<EventType>>,
 }
 
+/// What to do with the messages sent by the bot itself, when a source reports them back
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OwnMessagePolicy {
+    /// Don't deliver them to the modules
+    #[default]
+    Drop,
+    /// Deliver them with the `is_own` flag set
+    Flag,
+    /// Deliver them as any other message, with the `is_own` flag cleared
+    Deliver,
+}
+
 /// Inner structure with configuration data, read by Serde from a file
 /// in JSON format
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigInner<T> {
     pub log_folder: String,
+    /// The prefix marking text messages as command invocations
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    #[serde(default)]
+    pub own_messages: OwnMessagePolicy,
+    /// The folder where the timers are saved to survive restarts - they aren't saved if unset
+    #[serde(default)]
+    pub timer_folder: Option<String>,
     pub sources: HashMap<String, SourceDef>,
     pub modules: HashMap<String, ModuleDef>,
     pub custom: T,
 }
 
+fn default_command_prefix() -> String {
+    "!".to_owned()
+}
+
 impl<T> Config<T> {
     /// Loads configuration from a file and returns the resulting Config object
     pub fn new<P: AsRef<Path>>(path: P) -> Config<T>
@@ -61,6 +86,18 @@ impl<T> Config<T> {
     }
 }
 
+impl<T: DeserializeOwned> FromStr for Config<T> {
+    type Err = toml::de::Error;
+
+    /// Parses the configuration from a string, with no file behind it
+    fn from_str(config: &str) -> Result<Self, Self::Err> {
+        Ok(Config {
+            path: PathBuf::new(),
+            inner: toml::from_str(config)?,
+        })
+    }
+}
+
 impl<T> Deref for Config<T> {
     type Target = ConfigInner<T>;
     fn deref(&self) -> &Self::Target {
@@ -0,0 +1,70 @@
+use chrono::{DateTime, Duration, Local};
+use std::sync::Mutex;
+
+/// A callback run when a clock is moved by hand
+pub type AdvanceCallback = Box<dyn Fn() + Send + Sync>;
+
+/// The source of the current time for the timers and the logger
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+    /// Whether the time passes by itself - if not, the timers are checked only when the clock is
+    /// moved, instead of by a background thread
+    fn is_real(&self) -> bool {
+        true
+    }
+    /// Registers a callback to run after the clock is moved by hand
+    fn on_advance(&self, _callback: AdvanceCallback) {}
+}
+
+/// The system's wall clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests
+///
+/// Moving it fires the timers that became due synchronously, before `advance` returns.
+pub struct ManualClock {
+    now: Mutex<DateTime<Local>>,
+    callbacks: Mutex<Vec<AdvanceCallback>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Local>) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+            callbacks: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let now = *self.now.lock().unwrap() + by;
+        self.set(now);
+    }
+
+    pub fn set(&self, now: DateTime<Local>) {
+        *self.now.lock().unwrap() = now;
+        for callback in self.callbacks.lock().unwrap().iter() {
+            callback();
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        *self.now.lock().unwrap()
+    }
+
+    fn is_real(&self) -> bool {
+        false
+    }
+
+    fn on_advance(&self, callback: AdvanceCallback) {
+        self.callbacks.lock().unwrap().push(callback);
+    }
+}
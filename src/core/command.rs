@@ -0,0 +1,158 @@
+use crate::core::Channel;
+use std::collections::HashMap;
+
+/// Types of command options
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandOptionType {
+    String,
+    Integer,
+    Boolean,
+    User,
+    Channel,
+}
+
+/// A definition of a single command option
+#[derive(Clone, Debug)]
+pub struct CommandOption {
+    pub name: String,
+    pub description: String,
+    pub option_type: CommandOptionType,
+    pub required: bool,
+}
+
+/// A definition of a command that can be invoked by users
+#[derive(Clone, Debug)]
+pub struct CommandDef {
+    pub name: String,
+    pub description: String,
+    pub options: Vec<CommandOption>,
+}
+
+/// A value of a command argument
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandArg {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    User(String),
+    Channel(String),
+}
+
+/// An invocation of a command
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub args: HashMap<String, CommandArg>,
+    pub author: String,
+    pub channel: Channel,
+    /// Source-specific data identifying the invocation, for sources that support native commands
+    pub token: Option<String>,
+}
+
+impl CommandOptionType {
+    fn parse(self, arg: &str) -> Option<CommandArg> {
+        match self {
+            CommandOptionType::String => Some(CommandArg::String(arg.to_owned())),
+            CommandOptionType::Integer => arg.parse().ok().map(CommandArg::Integer),
+            CommandOptionType::Boolean => match arg {
+                "true" | "yes" | "on" => Some(CommandArg::Boolean(true)),
+                "false" | "no" | "off" => Some(CommandArg::Boolean(false)),
+                _ => None,
+            },
+            CommandOptionType::User => {
+                Some(CommandArg::User(arg.trim_start_matches('@').to_owned()))
+            }
+            CommandOptionType::Channel => {
+                Some(CommandArg::Channel(arg.trim_start_matches('#').to_owned()))
+            }
+        }
+    }
+}
+
+impl CommandDef {
+    /// Parses the arguments of a command given as text
+    /// The arguments are assigned to options in order; the last option takes the rest of the
+    /// line if it is a string. Returns `None` if the arguments don't match the options.
+    pub fn parse(&self, args: &str, author: String, channel: Channel) -> Option<Command> {
+        let mut parsed = HashMap::new();
+        let mut rest = args.trim();
+        for (i, option) in self.options.iter().enumerate() {
+            let last = i + 1 == self.options.len();
+            let arg = if last && option.option_type == CommandOptionType::String {
+                rest
+            } else {
+                rest.split_whitespace().next().unwrap_or("")
+            };
+            rest = rest[arg.len()..].trim_start();
+            if arg.is_empty() {
+                if option.required {
+                    return None;
+                }
+                continue;
+            }
+            let _ = parsed.insert(option.name.clone(), option.option_type.parse(arg)?);
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Command {
+            name: self.name.clone(),
+            args: parsed,
+            author,
+            channel,
+            token: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn option(name: &str, option_type: CommandOptionType, required: bool) -> CommandOption {
+        CommandOption {
+            name: name.to_owned(),
+            description: String::new(),
+            option_type,
+            required,
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let def = CommandDef {
+            name: "remind".to_owned(),
+            description: String::new(),
+            options: vec![
+                option("who", CommandOptionType::User, true),
+                option("minutes", CommandOptionType::Integer, true),
+                option("what", CommandOptionType::String, false),
+            ],
+        };
+        let cmd = def
+            .parse(
+                "@bob 15 take out the trash",
+                "alice".to_owned(),
+                Channel::None,
+            )
+            .unwrap();
+        assert_eq!(cmd.args["who"], CommandArg::User("bob".to_owned()));
+        assert_eq!(cmd.args["minutes"], CommandArg::Integer(15));
+        assert_eq!(
+            cmd.args["what"],
+            CommandArg::String("take out the trash".to_owned())
+        );
+
+        let cmd = def
+            .parse("bob 15", "alice".to_owned(), Channel::None)
+            .unwrap();
+        assert!(!cmd.args.contains_key("what"));
+
+        assert!(def
+            .parse("bob soon", "alice".to_owned(), Channel::None)
+            .is_none());
+        assert!(def
+            .parse("bob", "alice".to_owned(), Channel::None)
+            .is_none());
+    }
+}
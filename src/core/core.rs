@@ -1,32 +1,78 @@
-use crate::config::Config;
-use crate::core::{Event, EventType, Message, MessageContent, SourceEvent, SourceId};
+use crate::config::{Config, ModuleDef, OwnMessagePolicy};
+use crate::core::scheduler::Scheduler;
+use crate::core::timer_store::TimerStore;
+use crate::core::{
+    Clock, Command, CommandDef, Event, EventType, Identity, Message, MessageContent, SourceEvent,
+    SourceId, SystemClock, TimerEvent, TimerSchedule,
+};
 use crate::logger::*;
 use crate::modules::*;
 use crate::sources::*;
-use chrono::Duration;
+use chrono::{DateTime, Duration, Local};
 use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use timer::{Guard, MessageTimer};
+use std::sync::Arc;
 use toml::Value;
 
-struct ModuleDef {
+struct LoadedModule {
+    /// The name of the module in the config
+    id: String,
+    /// The type the module was created from
+    module_type: String,
     object: Box<dyn Module>,
     priority: u8,
     subscriptions: HashMap<SourceId, HashSet<EventType>>,
 }
 
+impl LoadedModule {
+    fn new(id: String, def: &ModuleDef, object: Box<dyn Module>) -> Self {
+        LoadedModule {
+            id,
+            module_type: def.module_type.clone(),
+            object,
+            priority: def.priority,
+            subscriptions: def
+                .subscriptions
+                .iter()
+                .map(|(id, set)| (SourceId(id.clone()), set.iter().cloned().collect()))
+                .collect(),
+        }
+    }
+}
+
+/// A change to the set of modules requested through the API
+enum ModuleChange {
+    Load(String, ModuleDef, Box<dyn Module>),
+    Unload(String),
+}
+
 pub struct CoreAPI {
     sources: HashMap<SourceId, Box<dyn EventSource>>,
     logger: Logger,
-    timer: MessageTimer<SourceEvent>,
-    timer_guards: HashMap<String, Guard>,
+    scheduler: Scheduler,
+    commands: HashMap<String, CommandDef>,
+    command_prefix: String,
+    /// The module whose handler is running, which owns the timers it schedules
+    current_module: Option<String>,
+    /// Set when a module asks the core to stop
+    quitting: bool,
+    /// The registry the modules are created from at runtime
+    mod_builders: HashMap<String, ModuleBuilder>,
+    /// The definitions of the loaded modules, by IDs - updated as soon as the changes are
+    /// requested
+    module_defs: HashMap<String, ModuleDef>,
+    /// The changes to apply once the current event is handled
+    module_changes: Vec<ModuleChange>,
 }
 
 /// The core of the bot
 pub struct Core {
     event_rx: Receiver<SourceEvent>,
-    modules: Vec<ModuleDef>,
+    modules: Vec<LoadedModule>,
+    own_messages: OwnMessagePolicy,
     api: CoreAPI,
+    /// Whether the modules' shutdown hooks have been called
+    shut_down: bool,
 }
 
 pub type EventSourceBuilder =
@@ -37,6 +83,15 @@ impl Core {
     /// Sets up the event passing channel, reads the config and
     /// creates and configures appropriate event sources and modules
     pub fn new<T>(mod_builders: &HashMap<String, ModuleBuilder>, config: &Config<T>) -> Self {
+        Self::with_clock(mod_builders, config, Arc::new(SystemClock))
+    }
+
+    /// Creates the core with the timers and the logger driven by the given clock
+    pub fn with_clock<T>(
+        mod_builders: &HashMap<String, ModuleBuilder>,
+        config: &Config<T>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         let (sender, receiver) = channel();
 
         let mut sources = HashMap::new();
@@ -52,78 +107,246 @@ impl Core {
             }
         }
 
+        let store = config.timer_folder.as_ref().map(|folder| {
+            TimerStore::new(folder).expect(&format!("Couldn't create timer folder {:?}", folder))
+        });
+        let scheduler = Scheduler::new(clock.clone(), sender, store);
+        let logger = Logger::with_clock(config.log_folder.clone(), LogMode::Both, clock);
+        Self::with_parts(mod_builders, config, receiver, sources, scheduler, logger)
+    }
+
+    /// Creates the core around already created sources, and the modules defined in the config
+    pub(crate) fn with_parts<T>(
+        mod_builders: &HashMap<String, ModuleBuilder>,
+        config: &Config<T>,
+        event_rx: Receiver<SourceEvent>,
+        sources: HashMap<SourceId, Box<dyn EventSource>>,
+        scheduler: Scheduler,
+        logger: Logger,
+    ) -> Self {
         let mut modules = vec![];
+        let mut module_defs = HashMap::new();
         {
             let modules_def = &config.modules;
             for (id, def) in modules_def {
                 if let Some(builder) = mod_builders.get(&def.module_type) {
                     let module: Box<dyn Module> = builder(id.clone(), def.config.clone());
-                    modules.push(ModuleDef {
-                        priority: def.priority,
-                        subscriptions: def
-                            .subscriptions
-                            .iter()
-                            .map(|(id, set)| (SourceId(id.clone()), set.iter().cloned().collect()))
-                            .collect(),
-                        object: module,
-                    });
+                    modules.push(LoadedModule::new(id.clone(), def, module));
+                    let _ = module_defs.insert(id.clone(), def.clone());
                 }
             }
         }
+        modules.sort_by_key(|def| def.priority);
 
-        let timer = MessageTimer::new(sender.clone());
-        let log_folder = config.log_folder.clone();
-
-        Core {
-            event_rx: receiver,
+        let mut core = Core {
+            event_rx,
             modules,
+            own_messages: config.own_messages,
             api: CoreAPI {
                 sources,
-                logger: Logger::new(log_folder),
-                timer,
-                timer_guards: HashMap::new(),
+                logger,
+                scheduler,
+                commands: HashMap::new(),
+                command_prefix: config.command_prefix.clone(),
+                current_module: None,
+                quitting: false,
+                mod_builders: mod_builders.clone(),
+                module_defs,
+                module_changes: vec![],
             },
+            shut_down: false,
+        };
+        core.check_subscriptions();
+        core
+    }
+
+    /// Warns about subscriptions to sources which never deliver any events
+    fn check_subscriptions(&mut self) {
+        let send_only: HashSet<_> = self
+            .modules
+            .iter()
+            .flat_map(|def| def.subscriptions.keys())
+            .filter(|id| self.api.sources.contains_key(id) && !self.api.can_receive(id))
+            .cloned()
+            .collect();
+        for source_id in send_only {
+            let _ = self.api.logger.log(
+                "core",
+                "WARNING",
+                format!(
+                    "{:?} is send-only, subscribing to it has no effect",
+                    source_id
+                ),
+            );
         }
     }
 
-    /// Calls connect() on all sources
+    /// Calls connect() on all sources, and then the modules' start hooks
     pub fn connect_all(&mut self) {
         for (s_id, source) in self.api.sources.iter_mut() {
             source
                 .connect()
                 .expect(&format!("connect() failed for source {:?}", s_id));
         }
+        self.call_hooks(|module, api| module.on_start(api));
+        self.apply_module_changes();
     }
 
-    /// Runs the event loop, processing them
+    /// Runs the event loop, processing them, until a module calls `CoreAPI::quit`
     pub fn run(&mut self) {
-        loop {
+        while !self.api.quitting {
             let event = self.event_rx.recv();
             if let Ok(event) = event {
-                self.log_event(&event);
-                self.handle_event(event);
+                self.process(event);
             } else {
                 println!("Channel error! {}", event.unwrap_err());
             }
         }
+        self.shutdown();
+    }
+
+    /// Calls the modules' shutdown hooks, unless they have been called already - done when the
+    /// core is dropped, too
+    pub fn shutdown(&mut self) {
+        if !self.shut_down {
+            self.shut_down = true;
+            self.call_hooks(|module, api| module.on_shutdown(api));
+        }
+    }
+
+    /// Calls a hook of every module, in the order of priorities
+    fn call_hooks<F: FnMut(&mut Box<dyn Module>, &mut CoreAPI)>(&mut self, mut hook: F) {
+        for module in &mut self.modules {
+            Self::call_hook(&mut self.api, module, &mut hook);
+        }
+    }
+
+    fn call_hook<F: FnMut(&mut Box<dyn Module>, &mut CoreAPI)>(
+        api: &mut CoreAPI,
+        module: &mut LoadedModule,
+        mut hook: F,
+    ) {
+        api.current_module = Some(module.id.clone());
+        hook(&mut module.object, api);
+        api.current_module = None;
+    }
+
+    /// Loads and unloads the modules as requested through the API
+    fn apply_module_changes(&mut self) {
+        while !self.api.module_changes.is_empty() {
+            let changes: Vec<_> = self.api.module_changes.drain(..).collect();
+            for change in changes {
+                match change {
+                    ModuleChange::Load(id, def, object) => {
+                        // a module of another type doesn't inherit the timers under the ID
+                        if let Some(old) = self.remove_module(&id) {
+                            if old.module_type != def.module_type {
+                                self.api.scheduler.cancel_owned(&id);
+                            }
+                        }
+                        let mut module = LoadedModule::new(id, &def, object);
+                        Self::call_hook(&mut self.api, &mut module, |module, api| {
+                            module.on_start(api)
+                        });
+                        let _ = self.api.logger.log(
+                            "core",
+                            "[notice]",
+                            format!("Loaded module {}", module.id),
+                        );
+                        let index = self
+                            .modules
+                            .iter()
+                            .position(|other| other.priority > module.priority)
+                            .unwrap_or(self.modules.len());
+                        self.modules.insert(index, module);
+                    }
+                    ModuleChange::Unload(id) => {
+                        let _ = self.remove_module(&id);
+                        self.api.scheduler.cancel_owned(&id);
+                        let _ = self.api.logger.log(
+                            "core",
+                            "[notice]",
+                            format!("Unloaded module {}", id),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the module, calling its shutdown hook - returns it, if it was loaded
+    fn remove_module(&mut self, id: &str) -> Option<LoadedModule> {
+        let index = self.modules.iter().position(|module| module.id == id)?;
+        let mut module = self.modules.remove(index);
+        Self::call_hook(&mut self.api, &mut module, |module, api| {
+            module.on_shutdown(api)
+        });
+        Some(module)
+    }
+
+    /// Logs and handles a single event
+    fn process(&mut self, event: SourceEvent) {
+        self.log_event(&event);
+        self.handle_event(event);
+        self.apply_module_changes();
+    }
+
+    /// Handles one queued event without waiting - returns whether there was any
+    pub(crate) fn step(&mut self) -> bool {
+        self.apply_module_changes();
+        match self.event_rx.try_recv() {
+            Ok(event) => {
+                self.process(event);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn api_mut(&mut self) -> &mut CoreAPI {
+        &mut self.api
+    }
+
+    fn format_message(&self, source_id: &SourceId, msg: &Message) -> String {
+        match msg.content {
+            MessageContent::Text(ref txt) => format!("<{}> {}", msg.author, txt),
+            MessageContent::Me(ref txt) => format!("* {} {}", self.api.get_nick(source_id), txt),
+            MessageContent::Image => format!("[Image]"),
+            MessageContent::Embed(ref embed) => format!("<{}> {}", msg.author, embed.to_text()),
+        }
     }
 
     fn log_event(&mut self, event: &SourceEvent) {
         let (channel, text) = match event.event {
             Event::ReceivedMessage(ref msg) => (
                 msg.channel.as_str(),
-                match msg.content {
-                    MessageContent::Text(ref txt) => format!("<{}> {}", msg.author, txt),
-                    MessageContent::Me(ref txt) => {
-                        format!("* {} {}", self.api.get_nick(&event.source), txt)
-                    }
-                    MessageContent::Image => format!("[Image]"),
-                },
+                self.format_message(&event.source, msg),
+            ),
+            Event::EditedMessage(ref msg) => (
+                msg.channel.as_str(),
+                format!("[edited] {}", self.format_message(&event.source, msg)),
+            ),
+            Event::Reaction(ref reaction) => (
+                reaction.channel.as_str(),
+                format!(
+                    "* {} {} :{}:",
+                    reaction.author,
+                    if reaction.removed {
+                        "removed reaction"
+                    } else {
+                        "reacted with"
+                    },
+                    reaction.emoji
+                ),
             ),
             Event::Disconnected(ref txt) => (
                 format!("[notice]"),
                 format!("Disconnected; reason: {}", txt),
             ),
+            Event::Command(ref cmd) => (
+                cmd.channel.as_str(),
+                format!("<{}> [command] {} {:?}", cmd.author, cmd.name, cmd.args),
+            ),
             Event::Other(ref txt) => (format!("[notice]"), txt.clone()),
             _ => (format!("[notice]"), format!("{:?}", event.event)),
         };
@@ -136,9 +359,9 @@ impl Core {
 
     fn get_subscribers<'a, 'b>(
         source_id: &'a SourceId,
-        modules: &'b mut Vec<ModuleDef>,
+        modules: &'b mut Vec<LoadedModule>,
         event: EventType,
-    ) -> Vec<&'b mut Box<dyn Module>> {
+    ) -> Vec<&'b mut LoadedModule> {
         let mut subscribing_modules: Vec<_> = modules
             .iter_mut()
             .filter(|def| {
@@ -147,17 +370,55 @@ impl Core {
                     .map(|events| events.contains(&event))
                     .unwrap_or(false)
             })
-            .map(|def| (def.priority, &mut def.object))
+            .map(|def| (def.priority, def))
             .collect();
         subscribing_modules.sort_by_key(|x| x.0);
         subscribing_modules.into_iter().map(|x| x.1).collect()
     }
 
+    /// Applies the configured policy to the messages sent by the bot itself
+    fn filter_own_message(&self, mut event: SourceEvent) -> Option<SourceEvent> {
+        match event.event {
+            Event::ReceivedMessage(ref mut msg) | Event::EditedMessage(ref mut msg)
+                if msg.is_own =>
+            {
+                match self.own_messages {
+                    OwnMessagePolicy::Drop => return None,
+                    OwnMessagePolicy::Flag => (),
+                    OwnMessagePolicy::Deliver => msg.is_own = false,
+                }
+            }
+            _ => (),
+        }
+        Some(event)
+    }
+
     fn handle_event(&mut self, event: SourceEvent) {
-        let subscribers =
-            Self::get_subscribers(&event.source, &mut self.modules, event.event.get_type());
-        for module in subscribers {
-            if module.handle_event(&mut self.api, event.clone()) == ResumeEventHandling::Stop {
+        let event = match self.filter_own_message(event) {
+            Some(event) => self.api.parse_command(event),
+            None => return,
+        };
+        if let Event::Connected = event.event {
+            let source = event.source.clone();
+            self.call_hooks(|module, api| module.on_source_connected(api, &source));
+        }
+        let subscribers = match event.event {
+            // timers go to the module which scheduled them, regardless of the subscriptions
+            Event::Timer(TimerEvent {
+                owner: Some(ref owner),
+                ..
+            }) => self
+                .modules
+                .iter_mut()
+                .filter(|def| def.id == *owner)
+                .collect(),
+            _ => Self::get_subscribers(&event.source, &mut self.modules, event.event.get_type()),
+        };
+        for def in subscribers {
+            self.api.current_module = Some(def.id.clone());
+            let resume = def.object.handle_event(&mut self.api, event.clone());
+            self.api.current_module = None;
+            if resume == ResumeEventHandling::Stop {
                 break;
             }
         }
@@ -179,15 +440,201 @@ impl CoreAPI {
             .unwrap_or_else(|| "no-nick".to_string())
     }
 
+    /// Creates a module from the registry and loads it under the ID, in place of the module
+    /// loaded under it - it starts handling the events after the current one
+    pub fn load_module(&mut self, id: &str, def: ModuleDef) -> Result<(), ModuleError> {
+        let builder = *self
+            .mod_builders
+            .get(&def.module_type)
+            .ok_or_else(|| ModuleError::UnknownType(def.module_type.clone()))?;
+        let object = builder(id.to_owned(), def.config.clone());
+        let _ = self.module_defs.insert(id.to_owned(), def.clone());
+        self.module_changes
+            .push(ModuleChange::Load(id.to_owned(), def, object));
+        Ok(())
+    }
+
+    /// Creates the module anew from its definition, eg. to reset a misbehaving one - its timers
+    /// are kept, unlike when a module of another type is loaded under the ID
+    pub fn reload_module(&mut self, id: &str) -> Result<(), ModuleError> {
+        let def = self
+            .module_defs
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ModuleError::NotLoaded(id.to_owned()))?;
+        self.load_module(id, def)
+    }
+
+    /// Unloads the module once the current event is handled, calling its shutdown hook and
+    /// cancelling its timers
+    pub fn unload_module(&mut self, id: &str) -> Result<(), ModuleError> {
+        if self.module_defs.remove(id).is_none() {
+            return Err(ModuleError::NotLoaded(id.to_owned()));
+        }
+        self.module_changes
+            .push(ModuleChange::Unload(id.to_owned()));
+        Ok(())
+    }
+
+    /// The IDs and the definitions of the loaded modules, in the order of priorities
+    ///
+    /// The loads and unloads requested while handling the current event are already included,
+    /// although they take effect only after it.
+    pub fn loaded_modules(&self) -> Vec<(String, ModuleDef)> {
+        let mut modules: Vec<_> = self
+            .module_defs
+            .iter()
+            .map(|(id, def)| (id.clone(), def.clone()))
+            .collect();
+        modules.sort_by(|a, b| (a.1.priority, &a.0).cmp(&(b.1.priority, &b.0)));
+        modules
+    }
+
+    /// Makes `Core::run` stop after handling the current event, calling the shutdown hooks
+    pub fn quit(&mut self) {
+        self.quitting = true;
+    }
+
+    /// The current time, according to the core's clock
+    pub fn now(&self) -> DateTime<Local> {
+        self.scheduler.now()
+    }
+
     pub fn schedule_timer(&mut self, id: String, after: Duration) {
-        let guard = self.timer.schedule_with_delay(
-            after,
-            SourceEvent {
-                source: SourceId("core".to_owned()),
-                event: Event::Timer(id.clone()),
+        let _ = self.set_timer(id, TimerSchedule::After(after), None);
+    }
+
+    /// Schedules a timer, replacing the one with the same ID - the payload is passed back in the
+    /// Timer events. Returns false if the schedule never fires.
+    ///
+    /// The timers are private to the calling module: the IDs don't clash with other modules'
+    /// timers, and the events are delivered only to it.
+    pub fn set_timer(
+        &mut self,
+        id: String,
+        schedule: TimerSchedule,
+        payload: Option<String>,
+    ) -> bool {
+        let timer = TimerEvent {
+            id,
+            payload,
+            owner: self.current_module.clone(),
+            late: false,
+        };
+        self.scheduler.schedule(timer, schedule)
+    }
+
+    /// Cancels a timer of the calling module - returns whether it was scheduled
+    pub fn cancel_timer(&mut self, id: &str) -> bool {
+        self.scheduler
+            .cancel(&(self.current_module.clone(), id.to_owned()))
+    }
+
+    pub(crate) fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Joins a channel on the source
+    pub fn join(&mut self, source_id: &SourceId, channel: &str) {
+        let source = self
+            .sources
+            .get_mut(source_id)
+            .expect(&format!("Couldn't find source {:?}", source_id));
+        if let Err(e) = source.join(channel) {
+            let _ = self.logger.log(&source_id.0, "ERROR", format!("{:?}", e));
+        }
+    }
+
+    /// Registers a command, making it available on all sources - natively on sources that
+    /// support it, and as prefixed text messages everywhere
+    pub fn register_command(&mut self, def: CommandDef) {
+        let _ = self.commands.insert(def.name.clone(), def);
+        let defs: Vec<_> = self.commands.values().cloned().collect();
+        for (source_id, source) in self.sources.iter_mut() {
+            if let Err(e) = source.register_commands(&defs) {
+                let _ = self.logger.log(&source_id.0, "ERROR", format!("{:?}", e));
+            }
+        }
+    }
+
+    /// Responds to a command invoked by a user
+    pub fn respond(
+        &mut self,
+        source_id: &SourceId,
+        command: &Command,
+        msg: MessageContent,
+        ephemeral: bool,
+    ) {
+        let source = self
+            .sources
+            .get_mut(source_id)
+            .expect(&format!("Couldn't find source {:?}", source_id));
+        let _ = self.logger.log(
+            &source_id.0,
+            command.channel.as_str(),
+            msg.display_with_nick(&source.get_nick()),
+        );
+        if let Err(e) = source.respond(command, msg, ephemeral) {
+            let _ = self.logger.log(&source_id.0, "ERROR", format!("{:?}", e));
+        }
+    }
+
+    /// Turns text messages invoking registered commands into command events
+    fn parse_command(&self, event: SourceEvent) -> SourceEvent {
+        let command = match event.event {
+            Event::ReceivedMessage(Message {
+                ref author,
+                ref channel,
+                content: MessageContent::Text(ref txt),
+                ..
+            }) if txt.starts_with(&self.command_prefix) => {
+                let txt = &txt[self.command_prefix.len()..];
+                let name = txt.split_whitespace().next().unwrap_or("");
+                self.commands.get(name).and_then(|def| {
+                    def.parse(
+                        txt.trim_start()[name.len()..].trim(),
+                        author.clone(),
+                        channel.clone(),
+                    )
+                })
+            }
+            _ => None,
+        };
+        match command {
+            Some(command) => SourceEvent {
+                source: event.source,
+                event: Event::Command(command),
             },
+            None => event,
+        }
+    }
+
+    /// Whether the source delivers events, or can only be sent to
+    pub fn can_receive(&self, source_id: &SourceId) -> bool {
+        self.sources
+            .get(&source_id)
+            .map(|source| source.can_receive())
+            .unwrap_or(false)
+    }
+
+    /// Sends a message under the given name and avatar, on sources that allow overriding them
+    pub fn send_as(&mut self, source_id: &SourceId, msg: Message, identity: Identity) {
+        let source = self
+            .sources
+            .get_mut(source_id)
+            .expect(&format!("Couldn't find source {:?}", source_id));
+        let nick = identity
+            .username
+            .clone()
+            .unwrap_or_else(|| source.get_nick());
+        let _ = self.logger.log(
+            &source_id.0,
+            msg.channel.as_str(),
+            msg.content.display_with_nick(&nick),
         );
-        let _ = self.timer_guards.insert(id, guard);
+        if let Err(e) = source.send_as(msg.channel, msg.content, &identity) {
+            let _ = self.logger.log(&source_id.0, "ERROR", format!("{:?}", e));
+        }
     }
 
     pub fn send(&mut self, source_id: &SourceId, msg: Message) {
@@ -205,3 +652,15 @@ impl CoreAPI {
         }
     }
 }
+
+impl Drop for Core {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Drop for CoreAPI {
+    fn drop(&mut self) {
+        self.scheduler.stop();
+    }
+}
@@ -0,0 +1,520 @@
+use crate::config::Config;
+use crate::core::scheduler::Scheduler;
+use crate::core::{Clock, Core, CoreAPI, Event, ManualClock, SourceId};
+use crate::logger::{LogMode, Logger};
+use crate::modules::ModuleBuilder;
+use crate::sources::{EventSource, LoopbackHandle, LoopbackSource};
+use chrono::{DateTime, Duration, Local};
+use std::collections::HashMap;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+/// The number of events `run_until_idle` handles before deciding that the modules are stuck in
+/// a loop
+const MAX_EVENTS: usize = 10_000;
+
+/// A core for testing modules, with every source replaced by a loopback one
+///
+/// Nothing runs in the background - the events are handled one at a time by `step`, and the
+/// time only passes when the clock is advanced.
+pub struct TestHarness {
+    core: Core,
+    clock: Arc<ManualClock>,
+    sources: HashMap<SourceId, LoopbackHandle>,
+}
+
+impl TestHarness {
+    /// Creates the core with the modules and the source IDs from the config, and connects the
+    /// sources - the clock starts at the current time
+    pub fn new<T>(mod_builders: &HashMap<String, ModuleBuilder>, config: &Config<T>) -> Self {
+        Self::starting_at(mod_builders, config, Local::now())
+    }
+
+    /// Creates the harness with the clock starting at the given time
+    pub fn starting_at<T>(
+        mod_builders: &HashMap<String, ModuleBuilder>,
+        config: &Config<T>,
+        start: DateTime<Local>,
+    ) -> Self {
+        let (sender, receiver) = channel();
+        let mut handles = HashMap::new();
+        let mut sources = HashMap::new();
+        for id in config.sources.keys() {
+            let source_id = SourceId(id.clone());
+            let (source, handle) =
+                LoopbackSource::with_handle(source_id.clone(), sender.clone(), None);
+            let source: Box<dyn EventSource> = Box::new(source);
+            let _ = sources.insert(source_id.clone(), source);
+            let _ = handles.insert(source_id, handle);
+        }
+
+        let clock = Arc::new(ManualClock::new(start));
+        let scheduler = Scheduler::new(clock.clone(), sender, None);
+        let logger = Logger::with_clock(config.log_folder.clone(), LogMode::Off, clock.clone());
+        let mut core = Core::with_parts(mod_builders, config, receiver, sources, scheduler, logger);
+        core.connect_all();
+        TestHarness {
+            core,
+            clock,
+            sources: handles,
+        }
+    }
+
+    /// The handle of a source - panics if there is no such source
+    pub fn source(&self, id: &str) -> &LoopbackHandle {
+        self.sources
+            .get(&SourceId(id.to_owned()))
+            .expect(&format!("Couldn't find source {:?}", id))
+    }
+
+    /// Queues an event from the source
+    pub fn inject(&self, source: &str, event: Event) {
+        self.source(source).inject(event);
+    }
+
+    /// Handles the next queued event - returns whether there was any
+    pub fn step(&mut self) -> bool {
+        self.core.step()
+    }
+
+    /// Handles the queued events, including the ones queued while handling them, and returns
+    /// their number
+    pub fn run_until_idle(&mut self) -> usize {
+        let mut count = 0;
+        while self.step() {
+            count += 1;
+            assert!(
+                count < MAX_EVENTS,
+                "more than {} events handled, the modules seem to be looping",
+                MAX_EVENTS
+            );
+        }
+        count
+    }
+
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    /// Moves the clock forward, queueing the events of the timers that became due
+    pub fn advance(&mut self, by: Duration) {
+        self.clock.advance(by);
+    }
+
+    /// The module's timers scheduled and not fired yet, with the time left until they fire,
+    /// soonest first
+    pub fn scheduled_timers(&mut self, module: &str) -> Vec<(String, Duration)> {
+        let now = self.clock.now();
+        self.core
+            .api_mut()
+            .scheduler()
+            .pending()
+            .into_iter()
+            .filter(|&((ref owner, _), _)| owner.as_ref().map(String::as_str) == Some(module))
+            .map(|((_, id), due)| (id, due - now))
+            .collect()
+    }
+
+    /// Queues the event of a module's scheduled timer right away - returns whether it was
+    /// scheduled
+    pub fn fire_timer(&mut self, module: &str, id: &str) -> bool {
+        let key = (Some(module.to_owned()), id.to_owned());
+        self.core.api_mut().scheduler().fire(&key)
+    }
+
+    /// Calls the modules' shutdown hooks, as when the bot stops
+    pub fn shutdown(&mut self) {
+        self.core.shutdown();
+    }
+
+    /// The API the modules see, for calling it directly
+    pub fn api(&mut self) -> &mut CoreAPI {
+        self.core.api_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ModuleDef;
+    use crate::core::EventType;
+    use crate::core::{Channel, Message, MessageContent, SourceEvent, TimerSchedule};
+    use crate::modules::{Module, ResumeEventHandling};
+    use crate::sources::LoopbackAction;
+    use toml::Value;
+
+    /// Answers "ping", joins on "join <channel>", reminds about things a minute later and nags
+    /// about them every hour until stopped
+    struct TestModule;
+
+    impl Module for TestModule {
+        fn handle_event(&mut self, core: &mut CoreAPI, event: SourceEvent) -> ResumeEventHandling {
+            let msg = match event.event {
+                Event::ReceivedMessage(msg) => msg,
+                Event::Timer(timer) => {
+                    let msg = Message {
+                        author: String::new(),
+                        channel: Channel::Channel("general".to_owned()),
+                        content: MessageContent::Text(format!(
+                            "reminder: {}",
+                            timer.payload.unwrap_or(timer.id)
+                        )),
+                        is_own: false,
+                    };
+                    core.send(&SourceId("chat".to_owned()), msg);
+                    return ResumeEventHandling::Resume;
+                }
+                _ => return ResumeEventHandling::Resume,
+            };
+            let text = match msg.content {
+                MessageContent::Text(ref text) => text.clone(),
+                _ => return ResumeEventHandling::Resume,
+            };
+            if text == "ping" {
+                let reply = Message {
+                    content: MessageContent::Text("pong".to_owned()),
+                    ..msg
+                };
+                core.send(&event.source, reply);
+            } else if let Some(channel) = text.strip_prefix("join ") {
+                core.join(&event.source, channel);
+            } else if let Some(what) = text.strip_prefix("remind ") {
+                core.schedule_timer(what.to_owned(), Duration::minutes(1));
+            } else if let Some(what) = text.strip_prefix("nag ") {
+                let schedule = TimerSchedule::Every(Duration::hours(1));
+                let _ = core.set_timer("nag".to_owned(), schedule, Some(what.to_owned()));
+            } else if text == "stop" {
+                let _ = core.cancel_timer("nag");
+            }
+            ResumeEventHandling::Resume
+        }
+    }
+
+    fn build(_: String, _: Option<Value>) -> Box<dyn Module> {
+        Box::new(TestModule)
+    }
+
+    /// Loads ("load <id> <type>"), unloads, reloads and lists the modules on command
+    struct AdminModule;
+
+    impl Module for AdminModule {
+        fn handle_event(&mut self, core: &mut CoreAPI, event: SourceEvent) -> ResumeEventHandling {
+            let msg = match event.event {
+                Event::ReceivedMessage(msg) => msg,
+                _ => return ResumeEventHandling::Resume,
+            };
+            let text = match msg.content {
+                MessageContent::Text(ref text) => text.clone(),
+                _ => return ResumeEventHandling::Resume,
+            };
+            let result = if let Some(args) = text.strip_prefix("load ") {
+                let mut args = args.split_whitespace();
+                let id = args.next().unwrap_or_default();
+                let mut subscriptions = HashMap::new();
+                let _ = subscriptions.insert("chat".to_owned(), vec![EventType::TextMessage]);
+                let def = ModuleDef {
+                    module_type: args.next().unwrap_or_default().to_owned(),
+                    config: None,
+                    priority: 5,
+                    subscriptions,
+                };
+                core.load_module(id, def)
+            } else if let Some(id) = text.strip_prefix("unload ") {
+                core.unload_module(id)
+            } else if let Some(id) = text.strip_prefix("reload ") {
+                core.reload_module(id)
+            } else if text == "list" {
+                let list: Vec<_> = core
+                    .loaded_modules()
+                    .into_iter()
+                    .map(|(id, def)| format!("{}:{}", id, def.priority))
+                    .collect();
+                let reply = Message {
+                    content: MessageContent::Text(list.join(" ")),
+                    ..msg
+                };
+                core.send(&event.source, reply);
+                return ResumeEventHandling::Resume;
+            } else {
+                return ResumeEventHandling::Resume;
+            };
+            if let Err(e) = result {
+                let reply = Message {
+                    content: MessageContent::Text(format!("{:?}", e)),
+                    ..msg
+                };
+                core.send(&event.source, reply);
+            }
+            ResumeEventHandling::Resume
+        }
+    }
+
+    fn build_admin(_: String, _: Option<Value>) -> Box<dyn Module> {
+        Box::new(AdminModule)
+    }
+
+    fn build_lifecycle(_: String, _: Option<Value>) -> Box<dyn Module> {
+        Box::new(LifecycleModule)
+    }
+
+    /// Joins a channel on start, and greets on connecting and on shutdown
+    struct LifecycleModule;
+
+    impl LifecycleModule {
+        fn say(core: &mut CoreAPI, source: &SourceId, text: &str) {
+            let msg = Message {
+                author: String::new(),
+                channel: Channel::Channel("lobby".to_owned()),
+                content: MessageContent::Text(text.to_owned()),
+                is_own: false,
+            };
+            core.send(source, msg);
+        }
+    }
+
+    impl Module for LifecycleModule {
+        fn handle_event(&mut self, _: &mut CoreAPI, _: SourceEvent) -> ResumeEventHandling {
+            ResumeEventHandling::Resume
+        }
+
+        fn on_start(&mut self, core: &mut CoreAPI) {
+            core.join(&SourceId("chat".to_owned()), "#lobby");
+        }
+
+        fn on_source_connected(&mut self, core: &mut CoreAPI, source: &SourceId) {
+            Self::say(core, source, "hello");
+        }
+
+        fn on_shutdown(&mut self, core: &mut CoreAPI) {
+            Self::say(core, &SourceId("chat".to_owned()), "bye");
+        }
+    }
+
+    const CONFIG: &str = r#"
+        log_folder = "logs"
+
+        [sources.chat]
+        source_type = "Irc"
+
+        [modules.test]
+        module_type = "Test"
+        priority = 0
+        [modules.test.subscriptions]
+        chat = ["TextMessage"]
+
+        [custom]
+    "#;
+
+    #[test]
+    fn test_harness() {
+        let mut builders = HashMap::new();
+        let _ = builders.insert("Test".to_owned(), build as ModuleBuilder);
+        let config: Config<Value> = CONFIG.parse().unwrap();
+        let mut harness = TestHarness::new(&builders, &config);
+        // the Connected event
+        assert_eq!(harness.run_until_idle(), 1);
+
+        let general = Channel::Channel("general".to_owned());
+        let chat = harness.source("chat").clone();
+        chat.inject_message("alice", general.clone(), "ping");
+        chat.inject_message("alice", general.clone(), "join #random");
+        chat.inject_message("alice", general.clone(), "remind tea");
+        chat.inject_message("alice", general.clone(), "remind cake");
+        assert!(harness.step());
+        assert_eq!(
+            chat.sent(),
+            vec![(general.clone(), MessageContent::Text("pong".to_owned()))]
+        );
+        assert_eq!(harness.run_until_idle(), 3);
+        assert!(!harness.step());
+        assert_eq!(chat.joined(), vec!["#random".to_owned()]);
+        assert_eq!(
+            harness.scheduled_timers("test"),
+            vec![
+                ("cake".to_owned(), Duration::minutes(1)),
+                ("tea".to_owned(), Duration::minutes(1)),
+            ]
+        );
+
+        chat.clear();
+        assert!(harness.fire_timer("test", "tea"));
+        assert!(!harness.fire_timer("test", "tea"));
+        let _ = harness.run_until_idle();
+        assert_eq!(
+            chat.actions(),
+            vec![LoopbackAction::Send {
+                channel: general.clone(),
+                content: MessageContent::Text("reminder: tea".to_owned()),
+                identity: None,
+            }]
+        );
+
+        chat.clear();
+        harness.advance(Duration::seconds(59));
+        assert_eq!(harness.run_until_idle(), 0);
+        harness.advance(Duration::seconds(1));
+        assert_eq!(harness.run_until_idle(), 1);
+        assert_eq!(
+            chat.sent(),
+            vec![(
+                general.clone(),
+                MessageContent::Text("reminder: cake".to_owned())
+            )]
+        );
+        assert!(harness.scheduled_timers("test").is_empty());
+
+        chat.clear();
+        chat.inject_message("alice", general.clone(), "nag laundry");
+        let _ = harness.run_until_idle();
+        harness.advance(Duration::minutes(150));
+        let _ = harness.run_until_idle();
+        // fell behind, so fired only once - and still going
+        assert_eq!(
+            chat.sent(),
+            vec![(
+                general.clone(),
+                MessageContent::Text("reminder: laundry".to_owned())
+            )]
+        );
+        assert_eq!(
+            harness.scheduled_timers("test"),
+            vec![("nag".to_owned(), Duration::minutes(30))]
+        );
+        chat.inject_message("alice", general, "stop");
+        let _ = harness.run_until_idle();
+        assert!(harness.scheduled_timers("test").is_empty());
+    }
+
+    #[test]
+    fn test_timer_owners() {
+        let mut builders = HashMap::new();
+        let _ = builders.insert("Test".to_owned(), build as ModuleBuilder);
+        let config: Config<Value> = r#"
+            log_folder = "logs"
+            [sources.chat]
+            source_type = "Irc"
+            [modules.first]
+            module_type = "Test"
+            priority = 0
+            subscriptions = { chat = ["TextMessage"] }
+            [modules.second]
+            module_type = "Test"
+            priority = 1
+            subscriptions = { chat = ["TextMessage"] }
+            [custom]
+        "#
+        .parse()
+        .unwrap();
+        let mut harness = TestHarness::new(&builders, &config);
+        let chat = harness.source("chat").clone();
+        let general = Channel::Channel("general".to_owned());
+        chat.inject_message("alice", general.clone(), "remind tea");
+        let _ = harness.run_until_idle();
+        assert_eq!(harness.scheduled_timers("first").len(), 1);
+        assert_eq!(harness.scheduled_timers("second").len(), 1);
+
+        assert!(harness.fire_timer("second", "tea"));
+        let _ = harness.run_until_idle();
+        assert_eq!(
+            chat.sent(),
+            vec![(general, MessageContent::Text("reminder: tea".to_owned()))]
+        );
+        assert_eq!(harness.scheduled_timers("first").len(), 1);
+    }
+
+    #[test]
+    fn test_lifecycle_hooks() {
+        let mut builders = HashMap::new();
+        let _ = builders.insert("Lifecycle".to_owned(), build_lifecycle as ModuleBuilder);
+        let config: Config<Value> = r#"
+            log_folder = "logs"
+            [sources.chat]
+            source_type = "Irc"
+            [modules.lifecycle]
+            module_type = "Lifecycle"
+            priority = 0
+            subscriptions = {}
+            [custom]
+        "#
+        .parse()
+        .unwrap();
+        let mut harness = TestHarness::new(&builders, &config);
+        let chat = harness.source("chat").clone();
+        let lobby = Channel::Channel("lobby".to_owned());
+        assert_eq!(chat.joined(), vec!["#lobby".to_owned()]);
+        assert!(chat.sent().is_empty());
+        assert_eq!(harness.run_until_idle(), 1);
+        assert_eq!(
+            chat.sent(),
+            vec![(lobby.clone(), MessageContent::Text("hello".to_owned()))]
+        );
+
+        chat.clear();
+        harness.shutdown();
+        drop(harness);
+        assert_eq!(
+            chat.sent(),
+            vec![(lobby, MessageContent::Text("bye".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn test_module_loading() {
+        let mut builders = HashMap::new();
+        let _ = builders.insert("Test".to_owned(), build as ModuleBuilder);
+        let _ = builders.insert("Admin".to_owned(), build_admin as ModuleBuilder);
+        let config: Config<Value> = r#"
+            log_folder = "logs"
+            [sources.chat]
+            source_type = "Irc"
+            [modules.admin]
+            module_type = "Admin"
+            priority = 0
+            subscriptions = { chat = ["TextMessage"] }
+            [custom]
+        "#
+        .parse()
+        .unwrap();
+        let mut harness = TestHarness::new(&builders, &config);
+        let chat = harness.source("chat").clone();
+        let general = Channel::Channel("general".to_owned());
+        let text = |text: &str| (general.clone(), MessageContent::Text(text.to_owned()));
+        let _ = harness.run_until_idle();
+
+        chat.inject_message("alice", general.clone(), "ping");
+        chat.inject_message("alice", general.clone(), "load pinger Test");
+        chat.inject_message("alice", general.clone(), "ping");
+        chat.inject_message("alice", general.clone(), "remind tea");
+        chat.inject_message("alice", general.clone(), "list");
+        let _ = harness.run_until_idle();
+        assert_eq!(chat.sent(), vec![text("pong"), text("admin:0 pinger:5")]);
+        assert_eq!(harness.scheduled_timers("pinger").len(), 1);
+
+        chat.clear();
+        chat.inject_message("alice", general.clone(), "reload pinger");
+        chat.inject_message("alice", general.clone(), "ping");
+        chat.inject_message("alice", general.clone(), "unload pinger");
+        chat.inject_message("alice", general.clone(), "ping");
+        chat.inject_message("alice", general.clone(), "unload pinger");
+        chat.inject_message("alice", general.clone(), "load other Unknown");
+        let _ = harness.run_until_idle();
+        assert_eq!(
+            chat.sent(),
+            vec![
+                text("pong"),
+                text("NotLoaded(\"pinger\")"),
+                text("UnknownType(\"Unknown\")"),
+            ]
+        );
+        assert!(harness.scheduled_timers("pinger").is_empty());
+        assert_eq!(harness.api().loaded_modules().len(), 1);
+
+        // another type under the same ID starts without the timers
+        chat.inject_message("alice", general.clone(), "load pinger Test");
+        chat.inject_message("alice", general.clone(), "remind tea");
+        chat.inject_message("alice", general.clone(), "load pinger Admin");
+        let _ = harness.run_until_idle();
+        assert!(harness.scheduled_timers("pinger").is_empty());
+        assert_eq!(harness.api().loaded_modules().len(), 2);
+    }
+}
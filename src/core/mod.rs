@@ -1,5 +1,15 @@
+mod clock;
+mod command;
 mod core;
+mod harness;
+mod scheduler;
+mod timer;
+mod timer_store;
 mod types;
 
+pub use self::clock::{AdvanceCallback, Clock, ManualClock, SystemClock};
+pub use self::command::*;
 pub use self::core::{Core, CoreAPI, EventSourceBuilder};
+pub use self::harness::TestHarness;
+pub use self::timer::{TimerError, TimerSchedule};
 pub use self::types::*;
@@ -0,0 +1,345 @@
+use crate::core::timer_store::{TimerEntry, TimerStore};
+use crate::core::{Clock, Event, SourceEvent, SourceId, TimerEvent, TimerSchedule};
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+/// How long the scheduler thread sleeps when there is nothing scheduled
+const IDLE_WAIT: StdDuration = StdDuration::from_secs(3600);
+
+/// Timers are identified by the module which scheduled them, if any, and their IDs
+pub(crate) type TimerKey = (Option<String>, String);
+
+fn timer_key(timer: &TimerEvent) -> TimerKey {
+    (timer.owner.clone(), timer.id.clone())
+}
+
+struct SchedulerData {
+    entries: HashMap<TimerKey, TimerEntry>,
+    sender: Sender<SourceEvent>,
+    store: Option<TimerStore>,
+    stopped: bool,
+}
+
+impl SchedulerData {
+    /// Sends a notice from the core, which ends up in its log
+    fn report(&self, text: String) {
+        let _ = self.sender.send(SourceEvent {
+            source: SourceId("core".to_owned()),
+            event: Event::Other(text),
+        });
+    }
+
+    /// Sends the event of the entry, and either moves it to its next due time or removes it
+    fn fire_entry(&mut self, key: &TimerKey, now: DateTime<Local>) {
+        let next = match self.entries.get_mut(key) {
+            Some(entry) => {
+                let _ = self.sender.send(SourceEvent {
+                    source: SourceId("core".to_owned()),
+                    event: Event::Timer(entry.timer.clone()),
+                });
+                entry.timer.late = false;
+                entry.schedule.next(entry.due, now)
+            }
+            None => return,
+        };
+        match next {
+            Some(due) => self.entries.get_mut(key).unwrap().due = due,
+            None => {
+                let _ = self.entries.remove(key);
+            }
+        }
+    }
+
+    /// Writes the timers to the store, if there is one
+    fn save(&self) {
+        if let Some(ref store) = self.store {
+            if let Err(e) = store.save(self.entries.values()) {
+                self.report(format!("Couldn't save the timers: {}", e));
+            }
+        }
+    }
+}
+
+/// Sends the Timer events to the core when they become due, according to the clock
+#[derive(Clone)]
+pub(crate) struct Scheduler {
+    clock: Arc<dyn Clock>,
+    shared: Arc<(Mutex<SchedulerData>, Condvar)>,
+}
+
+impl Scheduler {
+    /// Creates the scheduler - a real clock gets a thread waiting for the timers, a manual one
+    /// fires them when moved
+    ///
+    /// With a store, the timers saved in it are scheduled again, and the ones which became due in
+    /// the meantime fire right away, flagged as late. A file which can't be loaded is moved aside,
+    /// and the scheduler starts with no timers.
+    pub fn new(
+        clock: Arc<dyn Clock>,
+        sender: Sender<SourceEvent>,
+        store: Option<TimerStore>,
+    ) -> Self {
+        let now = clock.now();
+        let mut data = SchedulerData {
+            entries: HashMap::new(),
+            sender,
+            store,
+            stopped: false,
+        };
+        let saved = match data.store {
+            Some(ref store) => store.load().map_err(|e| (e, store.set_aside())),
+            None => Ok(vec![]),
+        };
+        match saved {
+            Ok(saved) => {
+                for mut entry in saved {
+                    entry.timer.late = entry.due <= now;
+                    let _ = data.entries.insert(timer_key(&entry.timer), entry);
+                }
+            }
+            Err((e, Ok(path))) => data.report(format!(
+                "Couldn't load the saved timers, moved them to {:?}: {}",
+                path, e
+            )),
+            Err((e, Err(rename_error))) => data.report(format!(
+                "Couldn't load the saved timers: {}, nor move them aside: {}",
+                e, rename_error
+            )),
+        }
+        let scheduler = Scheduler {
+            clock: clock.clone(),
+            shared: Arc::new((Mutex::new(data), Condvar::new())),
+        };
+        if clock.is_real() {
+            let cloned = scheduler.clone();
+            let _ = thread::spawn(move || cloned.run());
+        } else {
+            // the clock keeps the callback, so it refers to the scheduler only weakly
+            let weak_clock = Arc::downgrade(&clock);
+            let weak_shared = Arc::downgrade(&scheduler.shared);
+            clock.on_advance(Box::new(move || {
+                if let (Some(clock), Some(shared)) = (weak_clock.upgrade(), weak_shared.upgrade()) {
+                    Scheduler { clock, shared }.fire_due();
+                }
+            }));
+            scheduler.fire_due();
+        }
+        scheduler
+    }
+
+    pub fn now(&self) -> DateTime<Local> {
+        self.clock.now()
+    }
+
+    /// Schedules the timer, replacing the one with the same owner and ID - returns false, and
+    /// only cancels the old one, if the schedule never fires
+    pub fn schedule(&self, timer: TimerEvent, schedule: TimerSchedule) -> bool {
+        let (ref data, ref wakeup) = *self.shared;
+        let key = timer_key(&timer);
+        let due = match schedule.first(self.now()) {
+            Some(due) => due,
+            None => {
+                let _ = self.cancel(&key);
+                return false;
+            }
+        };
+        let entry = TimerEntry {
+            due,
+            schedule,
+            timer,
+        };
+        {
+            let mut data = data.lock().unwrap();
+            let _ = data.entries.insert(key, entry);
+            data.save();
+        }
+        wakeup.notify_all();
+        if !self.clock.is_real() {
+            self.fire_due();
+        }
+        true
+    }
+
+    /// Cancels the timer - returns whether it was scheduled
+    pub fn cancel(&self, key: &TimerKey) -> bool {
+        let (ref data, _) = *self.shared;
+        let mut data = data.lock().unwrap();
+        let removed = data.entries.remove(key).is_some();
+        if removed {
+            data.save();
+        }
+        removed
+    }
+
+    /// Cancels all the timers of the module
+    pub fn cancel_owned(&self, owner: &str) {
+        let (ref data, _) = *self.shared;
+        let mut data = data.lock().unwrap();
+        let count = data.entries.len();
+        data.entries
+            .retain(|&(ref key_owner, _), _| key_owner.as_ref().map(String::as_str) != Some(owner));
+        if data.entries.len() != count {
+            data.save();
+        }
+    }
+
+    /// The keys and due times of the scheduled timers, soonest first
+    pub fn pending(&self) -> Vec<(TimerKey, DateTime<Local>)> {
+        let (ref data, _) = *self.shared;
+        let mut pending: Vec<_> = data
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.due))
+            .collect();
+        pending.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        pending
+    }
+
+    /// Fires the timer right away, regardless of its due time, as if it was due - returns whether
+    /// it was scheduled
+    pub fn fire(&self, key: &TimerKey) -> bool {
+        let now = self.now();
+        let (ref data, _) = *self.shared;
+        let mut data = data.lock().unwrap();
+        if data.entries.contains_key(key) {
+            data.fire_entry(key, now);
+            data.save();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Fires the timers which are due, in the order of their due times
+    fn fire_due(&self) {
+        let now = self.clock.now();
+        let (ref data, _) = *self.shared;
+        let mut data = data.lock().unwrap();
+        let mut due: Vec<_> = data
+            .entries
+            .iter()
+            .filter(|&(_, entry)| entry.due <= now)
+            .map(|(key, entry)| (entry.due, key.clone()))
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        due.sort();
+        for (_, key) in due {
+            data.fire_entry(&key, now);
+        }
+        data.save();
+    }
+
+    /// Waits for the timers to become due, until stopped
+    fn run(&self) {
+        let (ref data, ref wakeup) = *self.shared;
+        loop {
+            let guard = data.lock().unwrap();
+            if guard.stopped {
+                return;
+            }
+            let now = self.clock.now();
+            let wait =
+                guard
+                    .entries
+                    .values()
+                    .map(|entry| entry.due)
+                    .min()
+                    .map_or(IDLE_WAIT, |due| {
+                        (due - now)
+                            .to_std()
+                            .unwrap_or_else(|_| StdDuration::from_secs(0))
+                    });
+            let (guard, _) = wakeup.wait_timeout(guard, wait).unwrap();
+            drop(guard);
+            self.fire_due();
+        }
+    }
+
+    /// Stops the scheduler thread
+    pub fn stop(&self) {
+        let (ref data, ref wakeup) = *self.shared;
+        data.lock().unwrap().stopped = true;
+        wakeup.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::ManualClock;
+    use chrono::{Duration, TimeZone};
+    use std::fs;
+    use std::sync::mpsc::channel;
+
+    fn timer(id: &str, payload: &str) -> TimerEvent {
+        TimerEvent {
+            id: id.to_owned(),
+            payload: Some(payload.to_owned()),
+            owner: Some("reminders".to_owned()),
+            late: false,
+        }
+    }
+
+    #[test]
+    fn test_saved_timers() {
+        let dir = std::env::temp_dir().join(format!("timer-store-test-{}", std::process::id()));
+        let start = Local.with_ymd_and_hms(2020, 3, 1, 12, 0, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (sender, receiver) = channel();
+        let store = TimerStore::new(&dir).unwrap();
+        let scheduler = Scheduler::new(clock.clone(), sender, Some(store));
+        let _ = scheduler.schedule(timer("tea", "{}"), TimerSchedule::After(Duration::hours(1)));
+        let _ = scheduler.schedule(
+            timer("digest", "daily"),
+            TimerSchedule::cron("0 0 18 * * *", "Europe/Warsaw").unwrap(),
+        );
+        let _ = scheduler.schedule(timer("cake", ""), TimerSchedule::Every(Duration::hours(2)));
+        assert!(scheduler.cancel(&(Some("reminders".to_owned()), "cake".to_owned())));
+        let pending = scheduler.pending();
+
+        // restarted after the tea became due
+        let (sender, restarted_receiver) = channel();
+        let store = TimerStore::new(&dir).unwrap();
+        let clock = Arc::new(ManualClock::new(start + Duration::hours(2)));
+        let restarted = Scheduler::new(clock, sender, Some(store));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(receiver.try_recv().is_err());
+        let event = restarted_receiver.try_recv().unwrap();
+        assert!(restarted_receiver.try_recv().is_err());
+        match event.event {
+            Event::Timer(timer) => {
+                assert_eq!(timer.id, "tea");
+                assert_eq!(timer.payload, Some("{}".to_owned()));
+                assert!(timer.late);
+            }
+            _ => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(restarted.pending(), pending[1..].to_vec());
+    }
+
+    #[test]
+    fn test_corrupt_timers() {
+        let dir = std::env::temp_dir().join(format!("timer-corrupt-test-{}", std::process::id()));
+        let store = TimerStore::new(&dir).unwrap();
+        fs::write(dir.join("timers.json"), "[{").unwrap();
+        let clock = Arc::new(ManualClock::new(Local::now()));
+        let (sender, receiver) = channel();
+        let scheduler = Scheduler::new(clock, sender, Some(store));
+        let moved = fs::read_to_string(dir.join("timers.json.bad"));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(moved.unwrap(), "[{");
+        assert!(scheduler.pending().is_empty());
+        match receiver.try_recv().unwrap().event {
+            Event::Other(text) => assert!(text.starts_with("Couldn't load the saved timers")),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::cmp;
+use std::str::FromStr;
+
+/// The reasons a timer schedule can't be created
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimerError {
+    InvalidCron(String),
+    UnknownTimeZone(String),
+    /// The local time doesn't exist in the time zone, eg. it's skipped by a DST change
+    NonexistentTime(NaiveDateTime),
+}
+
+/// When a timer fires
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimerSchedule {
+    /// Once, after the delay
+    After(Duration),
+    /// Repeatedly, the first time after one interval
+    Every(Duration),
+    /// Once, at the given time - right away if it has already passed
+    At(DateTime<Tz>),
+    /// Whenever the cron expression matches, evaluated in the time zone
+    Cron(Box<Schedule>, Tz),
+}
+
+fn parse_time_zone(time_zone: &str) -> Result<Tz, TimerError> {
+    time_zone
+        .parse()
+        .map_err(|_| TimerError::UnknownTimeZone(time_zone.to_owned()))
+}
+
+impl TimerSchedule {
+    /// Fires at the wall-clock time in the time zone, given by its IANA name (eg. "Europe/Warsaw")
+    pub fn at(time: NaiveDateTime, time_zone: &str) -> Result<TimerSchedule, TimerError> {
+        let tz = parse_time_zone(time_zone)?;
+        tz.from_local_datetime(&time)
+            .earliest()
+            .map(TimerSchedule::At)
+            .ok_or(TimerError::NonexistentTime(time))
+    }
+
+    /// Fires according to the cron expression - "sec min hour day month weekday [year]" - in the
+    /// time zone, given by its IANA name
+    pub fn cron(expression: &str, time_zone: &str) -> Result<TimerSchedule, TimerError> {
+        let schedule = Schedule::from_str(expression)
+            .map_err(|e| TimerError::InvalidCron(format!("{}: {}", expression, e)))?;
+        Ok(TimerSchedule::Cron(
+            Box::new(schedule),
+            parse_time_zone(time_zone)?,
+        ))
+    }
+
+    /// The first time the timer fires, if scheduled at `now`
+    pub fn first(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match *self {
+            TimerSchedule::After(delay) => Some(now + delay),
+            TimerSchedule::Every(interval) => Some(now + interval),
+            TimerSchedule::At(ref time) => Some(time.with_timezone(&Local)),
+            TimerSchedule::Cron(ref schedule, ref tz) => next_cron(schedule, tz, now),
+        }
+    }
+
+    /// The next time a timer which was due at `due` fires, if it's still running at `now`
+    ///
+    /// The occurrences which have already passed are skipped, so a timer that fell behind fires
+    /// only once to catch up.
+    pub fn next(&self, due: DateTime<Local>, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match *self {
+            TimerSchedule::After(_) | TimerSchedule::At(_) => None,
+            TimerSchedule::Every(interval) if interval <= Duration::zero() => None,
+            TimerSchedule::Every(interval) => {
+                // in milliseconds, as the count of the missed occurrences doesn't have to fit
+                // in the i32 which durations can be multiplied by
+                let interval = interval.num_milliseconds().max(1);
+                let missed = cmp::max(now - due, Duration::zero()).num_milliseconds() / interval;
+                let step = missed.checked_add(1)?.checked_mul(interval)?;
+                due.checked_add_signed(Duration::milliseconds(step))
+            }
+            TimerSchedule::Cron(ref schedule, ref tz) => {
+                next_cron(schedule, tz, cmp::max(due, now))
+            }
+        }
+    }
+}
+
+fn next_cron(schedule: &Schedule, tz: &Tz, after: DateTime<Local>) -> Option<DateTime<Local>> {
+    schedule
+        .after(&after.with_timezone(tz))
+        .next()
+        .map(|time| time.with_timezone(&Local))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{NaiveDate, Utc};
+
+    #[test]
+    fn test_schedules() {
+        let now = Utc
+            .with_ymd_and_hms(2020, 3, 1, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Local);
+        let every = TimerSchedule::Every(Duration::minutes(10));
+        assert_eq!(every.first(now), Some(now + Duration::minutes(10)));
+        // fell behind by 25 minutes - the next one keeps the phase
+        let due = now + Duration::minutes(10);
+        assert_eq!(
+            every.next(due, due + Duration::minutes(25)),
+            Some(due + Duration::minutes(30))
+        );
+        assert_eq!(
+            TimerSchedule::After(Duration::minutes(1)).next(due, due),
+            None
+        );
+        // weeks of downtime with short intervals
+        let downtime = Duration::weeks(6) + Duration::milliseconds(500);
+        assert_eq!(
+            TimerSchedule::Every(Duration::seconds(1)).next(due, due + downtime),
+            Some(due + Duration::weeks(6) + Duration::seconds(1))
+        );
+        assert_eq!(
+            TimerSchedule::Every(Duration::milliseconds(1)).next(due, due + downtime),
+            Some(due + downtime + Duration::milliseconds(1))
+        );
+
+        let daily = TimerSchedule::cron("0 30 9 * * *", "UTC").unwrap();
+        let first = daily.first(now).unwrap();
+        assert_eq!(first, Utc.with_ymd_and_hms(2020, 3, 2, 9, 30, 0).unwrap());
+        assert_eq!(daily.next(first, first), Some(first + Duration::days(1)));
+
+        let time = NaiveDate::from_ymd_opt(2020, 3, 29)
+            .and_then(|date| date.and_hms_opt(2, 30, 0))
+            .unwrap();
+        assert_eq!(
+            TimerSchedule::at(time, "Europe/Warsaw"),
+            Err(TimerError::NonexistentTime(time))
+        );
+        assert_eq!(
+            TimerSchedule::cron("0 0 9 * * *", "Mars/Olympus"),
+            Err(TimerError::UnknownTimeZone("Mars/Olympus".to_owned()))
+        );
+        assert!(TimerSchedule::cron("every day", "UTC").is_err());
+    }
+}
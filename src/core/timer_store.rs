@@ -0,0 +1,137 @@
+use crate::core::{TimerEvent, TimerSchedule};
+use chrono::{DateTime, Duration, Local};
+use chrono_tz::Tz;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const FILE_NAME: &str = "timers.json";
+
+/// A scheduled timer
+pub(crate) struct TimerEntry {
+    pub due: DateTime<Local>,
+    pub schedule: TimerSchedule,
+    pub timer: TimerEvent,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StoredSchedule {
+    /// Fires only at the due time
+    Once,
+    /// The interval in milliseconds
+    Every { interval: i64 },
+    Cron {
+        expression: String,
+        time_zone: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredTimer {
+    owner: Option<String>,
+    id: String,
+    /// In the RFC 3339 format
+    due: String,
+    payload: Option<String>,
+    schedule: StoredSchedule,
+}
+
+impl StoredTimer {
+    fn from_entry(entry: &TimerEntry) -> Self {
+        let schedule = match entry.schedule {
+            TimerSchedule::After(_) | TimerSchedule::At(_) => StoredSchedule::Once,
+            TimerSchedule::Every(interval) => StoredSchedule::Every {
+                interval: interval.num_milliseconds(),
+            },
+            TimerSchedule::Cron(ref schedule, ref tz) => StoredSchedule::Cron {
+                expression: schedule.to_string(),
+                time_zone: tz.name().to_owned(),
+            },
+        };
+        StoredTimer {
+            owner: entry.timer.owner.clone(),
+            id: entry.timer.id.clone(),
+            due: entry.due.to_rfc3339(),
+            payload: entry.timer.payload.clone(),
+            schedule,
+        }
+    }
+
+    fn into_entry(self) -> Result<TimerEntry, String> {
+        let id = self.id;
+        let due = DateTime::parse_from_rfc3339(&self.due)
+            .map_err(|e| format!("invalid due time of timer {:?}: {}", id, e))?
+            .with_timezone(&Local);
+        let schedule = match self.schedule {
+            StoredSchedule::Once => TimerSchedule::At(due.with_timezone(&Tz::UTC)),
+            StoredSchedule::Every { interval } => {
+                TimerSchedule::Every(Duration::milliseconds(interval))
+            }
+            StoredSchedule::Cron {
+                expression,
+                time_zone,
+            } => TimerSchedule::cron(&expression, &time_zone)
+                .map_err(|e| format!("invalid schedule of timer {:?}: {:?}", id, e))?,
+        };
+        Ok(TimerEntry {
+            due,
+            schedule,
+            timer: TimerEvent {
+                id,
+                payload: self.payload,
+                owner: self.owner,
+                late: false,
+            },
+        })
+    }
+}
+
+/// Saves the scheduled timers to a file in a folder, so that they survive restarts
+pub(crate) struct TimerStore {
+    path: PathBuf,
+}
+
+impl TimerStore {
+    pub fn new<P: AsRef<Path>>(folder: P) -> io::Result<TimerStore> {
+        fs::create_dir_all(&folder)?;
+        Ok(TimerStore {
+            path: folder.as_ref().join(FILE_NAME),
+        })
+    }
+
+    /// Reads the saved timers - there are none if nothing has been saved yet
+    pub fn load(&self) -> io::Result<Vec<TimerEntry>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let stored: Vec<StoredTimer> = serde_json::from_str(&contents)?;
+        stored
+            .into_iter()
+            .map(|timer| {
+                timer
+                    .into_entry()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+
+    /// Moves the saved timers out of the way, eg. when they can't be loaded - returns the new path
+    pub fn set_aside(&self) -> io::Result<PathBuf> {
+        let bad_path = self.path.with_extension("json.bad");
+        fs::rename(&self.path, &bad_path)?;
+        Ok(bad_path)
+    }
+
+    /// Replaces the saved timers
+    pub fn save<'a, I: IntoIterator<Item = &'a TimerEntry>>(&self, entries: I) -> io::Result<()> {
+        let stored: Vec<_> = entries.into_iter().map(StoredTimer::from_entry).collect();
+        let contents = serde_json::to_string_pretty(&stored)?;
+        // written aside and renamed, so that a crash doesn't leave a truncated file
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
@@ -1,3 +1,5 @@
+use crate::core::Command;
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct SourceId(pub String);
 
@@ -8,6 +10,8 @@ pub enum Channel {
     Channel(String),
     User(String),
     Group(Vec<String>),
+    /// A thread or topic within a channel - the channel name and the thread name
+    Thread(String, String),
 }
 
 impl Channel {
@@ -16,6 +20,7 @@ impl Channel {
             Channel::None => format!("[none]"),
             Channel::Channel(ref s) => format!("#{}", s),
             Channel::User(ref u) => format!("{}(priv)", u),
+            Channel::Thread(ref c, ref t) => format!("#{}/{}", c, t),
             Channel::Group(ref v) => {
                 let mut v2 = v.clone();
                 v2.sort();
@@ -26,7 +31,7 @@ impl Channel {
 }
 
 /// Content of a message
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MessageContent {
     /// Simple text message
     Text(String),
@@ -34,6 +39,8 @@ pub enum MessageContent {
     Image,
     /// A /me type message
     Me(String),
+    /// A structured card, rendered natively by sources that support it
+    Embed(Embed),
 }
 
 impl MessageContent {
@@ -42,16 +49,136 @@ impl MessageContent {
             MessageContent::Text(ref txt) => format!("<{}> {}", nick, txt),
             MessageContent::Me(ref txt) => format!("* {} {}", nick, txt),
             MessageContent::Image => format!("<{}> [Image]", nick),
+            MessageContent::Embed(ref embed) => format!("<{}> {}", nick, embed.to_text()),
         }
     }
 }
 
+/// A single named field of an embed
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    /// Whether the field can be displayed side by side with other inline fields
+    pub inline: bool,
+}
+
+/// Structured message content: a card with a title, description, fields etc.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Embed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub fields: Vec<EmbedField>,
+    /// The colour of the card as 0xRRGGBB
+    pub colour: Option<u32>,
+    pub footer: Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+
+impl Embed {
+    /// Returns the lines of a compact plain text representation of the embed, for sources
+    /// that can't display it natively
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some(ref title) = self.title {
+            lines.push(format!("[{}]", title));
+        }
+        if let Some(ref description) = self.description {
+            lines.extend(description.lines().map(|line| line.to_owned()));
+        }
+        let mut inline_fields: Vec<String> = vec![];
+        for field in &self.fields {
+            let text = format!("{}: {}", field.name, field.value);
+            if field.inline {
+                inline_fields.push(text);
+            } else {
+                if !inline_fields.is_empty() {
+                    lines.push(inline_fields.join(" | "));
+                    inline_fields.clear();
+                }
+                lines.push(text);
+            }
+        }
+        if !inline_fields.is_empty() {
+            lines.push(inline_fields.join(" | "));
+        }
+        if let Some(ref footer) = self.footer {
+            lines.push(format!("-- {}", footer));
+        }
+        lines
+    }
+
+    /// Returns a compact plain text representation of the embed
+    pub fn to_text(&self) -> String {
+        self.to_lines().join("\n")
+    }
+
+    /// Returns the embed as a Slack message attachment, which Mattermost understands as well
+    #[cfg(any(feature = "mattermost", feature = "slack"))]
+    pub(crate) fn to_attachment(&self) -> serde_json::Value {
+        let fields: Vec<_> = self
+            .fields
+            .iter()
+            .map(|field| {
+                serde_json::json!({
+                    "title": field.name,
+                    "value": field.value,
+                    "short": field.inline,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "fallback": self.to_text(),
+            "title": self.title,
+            "text": self.description,
+            "color": self.colour.map(|colour| format!("#{:06x}", colour)),
+            "fields": fields,
+            "footer": self.footer,
+            "thumb_url": self.thumbnail_url,
+        })
+    }
+}
+
+/// The name and avatar a message is posted under, on sources which allow overriding them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Identity {
+    pub username: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
 /// Message content bundled with the author and the source channel
 #[derive(Clone, Debug)]
 pub struct Message {
     pub author: String,
     pub channel: Channel,
     pub content: MessageContent,
+    /// Whether the message was sent by the bot itself
+    pub is_own: bool,
+}
+
+/// A reaction added to or removed from a message
+#[derive(Clone, Debug)]
+pub struct Reaction {
+    pub author: String,
+    pub channel: Channel,
+    /// The name of the emoji
+    pub emoji: String,
+    /// Whether the reaction was removed rather than added
+    pub removed: bool,
+}
+
+/// A scheduled timer that fired
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimerEvent {
+    pub id: String,
+    /// The data given when scheduling the timer, serialized by the module
+    pub payload: Option<String>,
+    /// The module which scheduled the timer, and the only one receiving the event - the timers
+    /// scheduled outside of the modules' handlers go to the modules subscribed to the "core"
+    /// source's Timer events
+    pub owner: Option<String>,
+    /// Whether the timer became due while the bot wasn't running, and fired after a restart
+    pub late: bool,
 }
 
 /// Type representing events that can be sent by the sources
@@ -61,11 +188,19 @@ pub enum Event {
     Disconnected(String),
     DirectInput(String),
     ReceivedMessage(Message),
+    /// A message that was edited - carries the new content
+    EditedMessage(Message),
+    Reaction(Reaction),
     UserOnline(String),
     UserOffline(String, Option<String>),
     UserTyping(String),
+    /// A user who joined a server or a team the bot is in - unrelated to their presence
+    UserJoined(String),
+    /// A user who left, or was removed from, a server or a team the bot is in
+    UserLeft(String),
     NickChange(String, String),
-    Timer(String),
+    Timer(TimerEvent),
+    Command(Command),
     Other(String),
 }
 
@@ -76,8 +211,12 @@ pub enum EventType {
     TextMessage,
     MeMessage,
     ImageMessage,
+    EmbedMessage,
+    EditedMessage,
+    Reaction,
     UserStatus,
     Timer,
+    Command,
     Other,
 }
 
@@ -90,12 +229,18 @@ impl Event {
                 MessageContent::Text(_) => EventType::TextMessage,
                 MessageContent::Me(_) => EventType::MeMessage,
                 MessageContent::Image => EventType::ImageMessage,
+                MessageContent::Embed(_) => EventType::EmbedMessage,
             },
+            Event::EditedMessage(_) => EventType::EditedMessage,
+            Event::Reaction(_) => EventType::Reaction,
             Event::UserOnline(_)
             | Event::UserOffline(_, _)
             | Event::UserTyping(_)
+            | Event::UserJoined(_)
+            | Event::UserLeft(_)
             | Event::NickChange(_, _) => EventType::UserStatus,
             Event::Timer(_) => EventType::Timer,
+            Event::Command(_) => EventType::Command,
             Event::Other(_) => EventType::Other,
         }
     }
@@ -9,7 +9,7 @@ mod logger;
 mod modules;
 mod sources;
 
-pub use crate::config::Config;
+pub use crate::config::{Config, OwnMessagePolicy};
 pub use crate::core::*;
 pub use crate::modules::*;
 pub use crate::sources::*;
@@ -1,13 +1,17 @@
+use crate::core::Clock;
 use chrono::{Date, DateTime, Local, Timelike};
 use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum LogMode {
     File,
     Console,
     Both,
+    /// Logs nothing
+    Off,
 }
 
 pub struct Logger {
@@ -15,6 +19,9 @@ pub struct Logger {
     cur_date: Date<Local>,
     last_log: DateTime<Local>,
     day_passed: bool,
+    /// The mode used by `log`
+    mode: LogMode,
+    clock: Arc<dyn Clock>,
 }
 
 impl Logger {
@@ -30,15 +37,21 @@ impl Logger {
             .join(month_str)
             .join(day_str);
         fs::create_dir_all(&path)?;
-        Ok(path.join(format!("{}.txt", channel)))
+        // channel names can contain slashes (eg. Discord's `guild/channel`)
+        let file_name = channel.replace(['/', '\\'], "_");
+        Ok(path.join(format!("{}.txt", file_name)))
     }
 
-    pub fn new<P: AsRef<Path>>(path: P) -> Logger {
+    /// Creates a logger whose `log` uses the given mode, and which takes the time from the clock
+    pub fn with_clock<P: AsRef<Path>>(path: P, mode: LogMode, clock: Arc<dyn Clock>) -> Logger {
+        let now = clock.now();
         Logger {
             base_dir: path.as_ref().to_path_buf(),
-            cur_date: Local::today(),
-            last_log: Local::now(),
+            cur_date: now.date(),
+            last_log: now,
             day_passed: false,
+            mode,
+            clock,
         }
     }
 
@@ -49,7 +62,7 @@ impl Logger {
         what: P3,
         mode: LogMode,
     ) -> io::Result<()> {
-        let now = Local::now();
+        let now = self.clock.now();
         let now_str = now.format("%Y-%m-%d %H:%M:%S");
         let time_diff = now.signed_duration_since(self.last_log);
 
@@ -95,6 +108,45 @@ impl Logger {
         channel: P2,
         what: P3,
     ) -> io::Result<()> {
-        self.log_with_mode(source, channel, what, LogMode::Both)
+        let mode = self.mode;
+        self.log_with_mode(source, channel, what, mode)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::ManualClock;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_day_rollover() {
+        let dir = std::env::temp_dir().join(format!("logger-test-{}", std::process::id()));
+        let clock = Arc::new(ManualClock::new(
+            Local.with_ymd_and_hms(2020, 3, 1, 23, 0, 0).unwrap(),
+        ));
+        let mut logger = Logger::with_clock(&dir, LogMode::File, clock.clone());
+        let file = |day: &str| {
+            dir.join("src")
+                .join("2020")
+                .join("03")
+                .join(day)
+                .join("chan.txt")
+        };
+
+        logger.log("src", "chan", "evening").unwrap();
+        // a conversation going on past midnight stays in the same file
+        clock.advance(Duration::hours(4));
+        logger.log("src", "chan", "night").unwrap();
+        assert!(!file("02").exists());
+        clock.advance(Duration::minutes(210));
+        logger.log("src", "chan", "morning").unwrap();
+
+        let first = fs::read_to_string(file("01")).unwrap();
+        let second = fs::read_to_string(file("02")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(first.lines().count(), 2);
+        assert!(first.ends_with("[2020-03-02 03:00:00] src/chan: night\n"));
+        assert_eq!(second, "[2020-03-02 06:30:00] src/chan: morning\n");
     }
 }
@@ -1,4 +1,4 @@
-use crate::core::{CoreAPI, SourceEvent};
+use crate::core::{CoreAPI, SourceEvent, SourceId};
 use toml::Value;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -9,6 +9,24 @@ pub enum ResumeEventHandling {
 
 pub type ModuleBuilder = fn(String, Option<Value>) -> Box<dyn Module>;
 
+/// The reasons a module can't be loaded or unloaded at runtime
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModuleError {
+    /// There is no builder for the module type
+    UnknownType(String),
+    NotLoaded(String),
+}
+
 pub trait Module {
     fn handle_event(&mut self, core: &mut CoreAPI, event: SourceEvent) -> ResumeEventHandling;
+
+    /// Called once the sources are connected, before any events are handled
+    fn on_start(&mut self, _core: &mut CoreAPI) {}
+
+    /// Called whenever a source connects or reconnects, before the Connected event is handled -
+    /// regardless of the subscriptions
+    fn on_source_connected(&mut self, _core: &mut CoreAPI, _source: &SourceId) {}
+
+    /// Called once when the core stops
+    fn on_shutdown(&mut self, _core: &mut CoreAPI) {}
 }
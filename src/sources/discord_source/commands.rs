@@ -0,0 +1,222 @@
+//! Conversions between the core command types and Discord's application commands and
+//! interactions, which are not supported by serenity, and thus handled as raw JSON
+
+use crate::core::*;
+use serde_json::{json, Value};
+use serenity::model::id::{ChannelId, GuildId};
+use std::collections::HashMap;
+
+pub const API_BASE: &str = "https://discord.com/api/v8";
+
+/// The interaction type of a slash command invocation
+const APPLICATION_COMMAND: u64 = 2;
+/// The response type of a message sent in response to an interaction
+const CHANNEL_MESSAGE_WITH_SOURCE: u64 = 4;
+/// The message flag making a response visible only to the invoking user
+const EPHEMERAL: u64 = 1 << 6;
+
+fn option_type_to_json(option_type: CommandOptionType) -> u64 {
+    match option_type {
+        CommandOptionType::String => 3,
+        CommandOptionType::Integer => 4,
+        CommandOptionType::Boolean => 5,
+        CommandOptionType::User => 6,
+        CommandOptionType::Channel => 7,
+    }
+}
+
+pub fn command_to_json(def: &CommandDef) -> Value {
+    let options: Vec<_> = def
+        .options
+        .iter()
+        .map(|option| {
+            json!({
+                "name": option.name,
+                "description": option.description,
+                "type": option_type_to_json(option.option_type),
+                "required": option.required,
+            })
+        })
+        .collect();
+    json!({
+        "name": def.name,
+        "description": def.description,
+        "options": options,
+    })
+}
+
+pub fn embed_to_json(embed: &Embed) -> Value {
+    let fields: Vec<_> = embed
+        .fields
+        .iter()
+        .map(|field| {
+            json!({
+                "name": field.name,
+                "value": field.value,
+                "inline": field.inline,
+            })
+        })
+        .collect();
+    let mut result = json!({
+        "title": embed.title,
+        "description": embed.description,
+        "fields": fields,
+        "color": embed.colour,
+    });
+    if let Some(ref footer) = embed.footer {
+        result["footer"] = json!({ "text": footer });
+    }
+    if let Some(ref url) = embed.thumbnail_url {
+        result["thumbnail"] = json!({ "url": url });
+    }
+    result
+}
+
+/// Creates the body of an interaction response carrying the given message
+pub fn response_to_json(msg: &MessageContent, ephemeral: bool) -> Option<Value> {
+    let mut data = match *msg {
+        MessageContent::Text(ref t) | MessageContent::Me(ref t) => json!({ "content": t }),
+        MessageContent::Embed(ref embed) => json!({ "embeds": [embed_to_json(embed)] }),
+        _ => return None,
+    };
+    if ephemeral {
+        data["flags"] = json!(EPHEMERAL);
+    }
+    Some(json!({
+        "type": CHANNEL_MESSAGE_WITH_SOURCE,
+        "data": data,
+    }))
+}
+
+fn parse_id(value: &Value) -> Option<u64> {
+    value.as_str().and_then(|id| id.parse().ok())
+}
+
+/// Converts a raw INTERACTION_CREATE payload into a command invocation
+/// `channel_name` is used to look up the names of guild channels
+pub fn interaction_to_command<F>(raw: &Value, channel_name: F) -> Option<Command>
+where
+    F: Fn(GuildId, ChannelId) -> Option<String>,
+{
+    if raw["type"].as_u64() != Some(APPLICATION_COMMAND) {
+        return None;
+    }
+    let user = if raw["member"].is_object() {
+        &raw["member"]["user"]
+    } else {
+        &raw["user"]
+    };
+    let author = user["username"].as_str()?.to_owned();
+    let channel_id = ChannelId(parse_id(&raw["channel_id"])?);
+    let channel = match parse_id(&raw["guild_id"]) {
+        Some(gid) => Channel::Channel(
+            channel_name(GuildId(gid), channel_id).unwrap_or_else(|| channel_id.0.to_string()),
+        ),
+        None => Channel::User(author.clone()),
+    };
+
+    let data = &raw["data"];
+    let mut args = HashMap::new();
+    for option in data["options"].as_array().into_iter().flatten() {
+        let value = &option["value"];
+        let arg = match option["type"].as_u64() {
+            Some(3) => value.as_str().map(|s| CommandArg::String(s.to_owned())),
+            Some(4) => value.as_i64().map(CommandArg::Integer),
+            Some(5) => value.as_bool().map(CommandArg::Boolean),
+            Some(6) => value.as_str().map(|id| {
+                let name = data["resolved"]["users"][id]["username"].as_str();
+                CommandArg::User(name.unwrap_or(id).to_owned())
+            }),
+            Some(7) => parse_id(value).map(|cid| {
+                let name = parse_id(&raw["guild_id"])
+                    .and_then(|gid| channel_name(GuildId(gid), ChannelId(cid)));
+                CommandArg::Channel(name.unwrap_or_else(|| cid.to_string()))
+            }),
+            _ => None,
+        };
+        if let (Some(name), Some(arg)) = (option["name"].as_str(), arg) {
+            let _ = args.insert(name.to_owned(), arg);
+        }
+    }
+
+    Some(Command {
+        name: data["name"].as_str()?.to_owned(),
+        args,
+        author,
+        channel,
+        token: Some(format!(
+            "{}/{}",
+            raw["id"].as_str()?,
+            raw["token"].as_str()?
+        )),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interaction_to_command() {
+        let raw = json!({
+            "id": "100",
+            "token": "tok",
+            "type": APPLICATION_COMMAND,
+            "guild_id": "1",
+            "channel_id": "2",
+            "member": { "user": { "id": "3", "username": "alice" } },
+            "data": {
+                "name": "greet",
+                "options": [
+                    { "name": "who", "type": 6, "value": "4" },
+                    { "name": "where", "type": 7, "value": "2" },
+                    { "name": "times", "type": 4, "value": 2 },
+                    { "name": "loud", "type": 5, "value": true },
+                ],
+                "resolved": { "users": { "4": { "id": "4", "username": "bob" } } },
+            },
+        });
+        let channel_name = |gid: GuildId, cid: ChannelId| {
+            if (gid, cid) == (GuildId(1), ChannelId(2)) {
+                Some("guild/general".to_owned())
+            } else {
+                None
+            }
+        };
+        let command = interaction_to_command(&raw, channel_name).unwrap();
+        assert_eq!(command.name, "greet");
+        assert_eq!(command.author, "alice");
+        assert_eq!(
+            command.channel,
+            Channel::Channel("guild/general".to_owned())
+        );
+        assert_eq!(command.token, Some("100/tok".to_owned()));
+        assert_eq!(command.args["who"], CommandArg::User("bob".to_owned()));
+        assert_eq!(
+            command.args["where"],
+            CommandArg::Channel("guild/general".to_owned())
+        );
+        assert_eq!(command.args["times"], CommandArg::Integer(2));
+        assert_eq!(command.args["loud"], CommandArg::Boolean(true));
+
+        // direct messages carry the user at the top level
+        let mut raw = raw;
+        raw["guild_id"] = Value::Null;
+        raw["user"] = raw["member"]["user"].take();
+        raw["member"] = Value::Null;
+        let command = interaction_to_command(&raw, channel_name).unwrap();
+        assert_eq!(command.channel, Channel::User("alice".to_owned()));
+
+        raw["type"] = json!(1);
+        assert!(interaction_to_command(&raw, channel_name).is_none());
+    }
+
+    #[test]
+    fn test_response_to_json() {
+        assert_eq!(
+            response_to_json(&MessageContent::Me("waves".to_owned()), false),
+            Some(json!({ "type": 4, "data": { "content": "waves" } }))
+        );
+        assert_eq!(response_to_json(&MessageContent::Image, true), None);
+    }
+}
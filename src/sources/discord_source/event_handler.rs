@@ -1,34 +1,139 @@
+use super::commands;
 use crate::core::*;
+use crate::sources::http;
 use crate::sources::*;
+use serde_json::{json, Value};
+use serenity::builder::CreateEmbed;
+use serenity::cache::CacheRwLock;
 use serenity::http::client::Http;
-use serenity::model::channel::Message;
+use serenity::model::channel::{Channel as DiscordChannel, GuildChannel, Message};
+use serenity::model::event::{PresenceUpdateEvent, TypingStartEvent};
 use serenity::model::gateway::Ready;
-use serenity::model::id::ChannelId;
-use serenity::model::user::{CurrentUser, User};
-use serenity::prelude::{Context, EventHandler};
+use serenity::model::guild::{Guild, Member, PartialGuild};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::user::{CurrentUser, OnlineStatus, User};
+use serenity::prelude::{Context, EventHandler, RwLock as DiscordRwLock};
 use std::collections::HashMap;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// How many times loading data on `ready` is attempted before giving up
+const READY_RETRIES: u32 = 3;
+
+/// The name and the channels of a single guild
+#[derive(Clone, Default)]
+struct GuildData {
+    name: String,
+    channels: HashMap<ChannelId, String>,
+}
 
 #[derive(Clone, Default)]
 struct DiscordData {
     user: Option<CurrentUser>,
-    channels: HashMap<String, ChannelId>,
+    default_guild: Option<String>,
+    guilds: HashMap<GuildId, GuildData>,
+    users: HashMap<String, UserId>,
+    groups: HashMap<Vec<String>, ChannelId>,
     http: Option<Arc<Http>>,
+    cache: Option<CacheRwLock>,
+    commands: Vec<CommandDef>,
+    /// The ID of the bot's application, once fetched
+    application_id: Option<String>,
+}
+
+impl DiscordData {
+    fn add_guild(&mut self, gid: GuildId, name: String) {
+        self.guilds.entry(gid).or_insert_with(Default::default).name = name;
+    }
+
+    fn add_channel(&mut self, channel: &GuildChannel) {
+        let _ = self
+            .guilds
+            .entry(channel.guild_id)
+            .or_insert_with(Default::default)
+            .channels
+            .insert(channel.id, channel.name.clone());
+    }
+
+    fn remove_channel(&mut self, channel: &GuildChannel) {
+        if let Some(guild) = self.guilds.get_mut(&channel.guild_id) {
+            let _ = guild.channels.remove(&channel.id);
+        }
+    }
+
+    /// Returns the channel name qualified with the guild name, in the form `guild/channel`
+    fn qualified_name(&self, gid: GuildId, channel_name: &str) -> String {
+        let guild_name = self
+            .guilds
+            .get(&gid)
+            .map(|guild| guild.name.clone())
+            .unwrap_or_else(|| gid.0.to_string());
+        format!("{}/{}", guild_name, channel_name)
+    }
+
+    /// Finds a channel by its name - either qualified (`guild/channel`) or short, in which case
+    /// the default guild is searched, or all guilds if there is no default one and the name is
+    /// unambiguous
+    fn find_channel(&self, name: &str) -> Option<ChannelId> {
+        let (guild_name, channel_name) = match name.rfind('/') {
+            Some(pos) => (Some(&name[..pos]), &name[pos + 1..]),
+            None => (self.default_guild.as_ref().map(|g| g as &str), name),
+        };
+        let mut found = self
+            .guilds
+            .values()
+            .filter(|guild| guild_name.map(|n| n == guild.name).unwrap_or(true))
+            .flat_map(|guild| guild.channels.iter())
+            .filter(|&(_, name)| name == channel_name)
+            .map(|(cid, _)| *cid);
+        match (found.next(), found.next()) {
+            (Some(cid), None) => Some(cid),
+            _ => None,
+        }
+    }
+
+    fn find_user(&self, name: &str) -> Option<UserId> {
+        self.users.get(name).cloned().or_else(|| {
+            self.cache.as_ref().and_then(|cache| {
+                cache
+                    .read()
+                    .users
+                    .values()
+                    .map(|user| user.read())
+                    .find(|user| user.name == name)
+                    .map(|user| user.id)
+            })
+        })
+    }
 }
 
 struct DiscordEventHandlerImpl {
     id: SourceId,
+    token: String,
+    api_url: String,
     sender: Mutex<Sender<SourceEvent>>,
     data: RwLock<DiscordData>,
 }
 
 impl DiscordEventHandlerImpl {
-    pub fn new(id: SourceId, sender: Sender<SourceEvent>) -> Self {
+    pub fn new(
+        id: SourceId,
+        sender: Sender<SourceEvent>,
+        token: String,
+        default_guild: Option<String>,
+        api_url: String,
+    ) -> Self {
         Self {
             id,
+            token,
+            api_url,
             sender: Mutex::new(sender),
-            data: Default::default(),
+            data: RwLock::new(DiscordData {
+                default_guild,
+                ..Default::default()
+            }),
         }
     }
 }
@@ -39,9 +144,21 @@ pub struct DiscordEventHandler {
 }
 
 impl DiscordEventHandler {
-    pub fn new(id: SourceId, sender: Sender<SourceEvent>) -> Self {
+    pub fn new(
+        id: SourceId,
+        sender: Sender<SourceEvent>,
+        token: String,
+        default_guild: Option<String>,
+        api_url: String,
+    ) -> Self {
         Self {
-            inner: Arc::new(DiscordEventHandlerImpl::new(id, sender)),
+            inner: Arc::new(DiscordEventHandlerImpl::new(
+                id,
+                sender,
+                token,
+                default_guild,
+                api_url,
+            )),
         }
     }
 
@@ -56,43 +173,254 @@ impl DiscordEventHandler {
             .unwrap_or("".to_string())
     }
 
-    fn send_to_channel(&mut self, dst: String, msg: MessageContent) -> SourceResult<()> {
+    fn say(&self, channel: ChannelId, msg: MessageContent) -> SourceResult<()> {
         let data = self.inner.data.read().unwrap();
-        let channel = match data.channels.get(&dst) {
-            None => {
-                return Err(SourceError::InvalidChannel(
+        let http = match data.http {
+            Some(ref http) => http,
+            None => return Ok(()),
+        };
+        match msg {
+            MessageContent::Text(t) | MessageContent::Me(t) => {
+                channel.say(&http, t)?;
+            }
+            MessageContent::Embed(embed) => {
+                channel.send_message(&http, |m| m.embed(|e| Self::build_embed(e, &embed)))?;
+            }
+            _ => {
+                return Err(SourceError::InvalidMessage(
                     self.inner.id.clone(),
-                    Channel::Channel(dst),
-                ));
+                    Box::new(msg),
+                ))
             }
-            Some(ch) => ch,
-        };
-        let msg = match msg {
-            MessageContent::Text(t) => t,
-            MessageContent::Me(t) => t,
-            _ => return Err(SourceError::InvalidMessage(self.inner.id.clone(), msg)),
-        };
-        {
+        }
+        Ok(())
+    }
+
+    fn build_embed<'a>(e: &'a mut CreateEmbed, embed: &Embed) -> &'a mut CreateEmbed {
+        if let Some(ref title) = embed.title {
+            e.title(title);
+        }
+        if let Some(ref description) = embed.description {
+            e.description(description);
+        }
+        for field in &embed.fields {
+            e.field(&field.name, &field.value, field.inline);
+        }
+        if let Some(colour) = embed.colour {
+            e.colour(colour);
+        }
+        if let Some(ref footer) = embed.footer {
+            e.footer(|f| f.text(footer));
+        }
+        if let Some(ref url) = embed.thumbnail_url {
+            e.thumbnail(url);
+        }
+        e
+    }
+
+    fn send_to_channel(&mut self, dst: String, msg: MessageContent) -> SourceResult<()> {
+        let channel = self.inner.data.read().unwrap().find_channel(&dst);
+        match channel {
+            Some(ch) => self.say(ch, msg),
+            None => Err(SourceError::InvalidChannel(
+                self.inner.id.clone(),
+                Channel::Channel(dst),
+            )),
+        }
+    }
+
+    fn send_to_user(&mut self, dst: String, msg: MessageContent) -> SourceResult<()> {
+        let private_channel = {
             let data = self.inner.data.read().unwrap();
-            if let Some(ref http) = data.http {
-                channel.say(&http, msg)?;
+            match (data.find_user(&dst), data.http.as_ref()) {
+                (Some(uid), Some(http)) => Some(uid.create_dm_channel(&**http)?),
+                _ => None,
             }
+        };
+        match private_channel {
+            Some(ch) => self.say(ch.id, msg),
+            None => Err(SourceError::InvalidChannel(
+                self.inner.id.clone(),
+                Channel::User(dst),
+            )),
         }
-        Ok(())
     }
 
-    fn send_to_user(&mut self, _dst: String, _msg: MessageContent) -> SourceResult<()> {
-        Ok(())
+    fn send_to_group(&mut self, mut dst: Vec<String>, msg: MessageContent) -> SourceResult<()> {
+        dst.sort();
+        let channel = self.inner.data.read().unwrap().groups.get(&dst).cloned();
+        match channel {
+            Some(ch) => self.say(ch, msg),
+            None => Err(SourceError::InvalidChannel(
+                self.inner.id.clone(),
+                Channel::Group(dst),
+            )),
+        }
     }
 
     pub fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
         match dst {
             Channel::Channel(ch) => self.send_to_channel(ch, msg),
             Channel::User(usr) => self.send_to_user(usr, msg),
+            Channel::Group(users) => self.send_to_group(users, msg),
             _ => return Err(SourceError::InvalidChannel(self.inner.id.clone(), dst)),
         }
     }
 
+    fn send_event(&self, event: Event) {
+        let _ = self.inner.sender.lock().unwrap().send(SourceEvent {
+            source: self.inner.id.clone(),
+            event,
+        });
+    }
+
+    /// Returns the name of the user with the given ID, remembering it for sending private messages
+    fn user_name(&self, ctx: &Context, user_id: UserId) -> Option<String> {
+        let user = user_id.to_user(ctx).ok()?;
+        let mut data = self.inner.data.write().unwrap();
+        let _ = data.users.insert(user.name.clone(), user.id);
+        Some(user.name)
+    }
+
+    /// Calls `f` until it succeeds, at most `READY_RETRIES` times, sleeping between the attempts -
+    /// the last failure is reported as an event
+    fn with_retries<T, F>(&self, what: &str, mut f: F) -> Option<T>
+    where
+        F: FnMut() -> SourceResult<T>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(result) => return Some(result),
+                Err(e) if attempt == READY_RETRIES => {
+                    self.send_event(Event::Other(format!(
+                        "Giving up trying to {} after {} attempts: {:?}",
+                        what, READY_RETRIES, e
+                    )));
+                    return None;
+                }
+                Err(_) => {
+                    thread::sleep(Duration::from_secs(1 << attempt));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Loads the guilds which haven't been created yet and registers the commands, retrying on
+    /// failures, then reports the source as connected
+    ///
+    /// It's meant to run in its own thread, as the retries would hold up the gateway events.
+    fn finish_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        for gid in guilds {
+            // GUILD_CREATE may have arrived in the meantime
+            if self.inner.data.read().unwrap().guilds.contains_key(&gid) {
+                continue;
+            }
+            let _ = self.with_retries(&format!("load guild {}", gid.0), || {
+                self.load_guild(&ctx, gid)
+            });
+        }
+        let commands = self.inner.data.read().unwrap().commands.clone();
+        if !commands.is_empty() {
+            let _ = self.with_retries("register commands", || self.upload_commands(&commands));
+        }
+        self.send_event(Event::Connected);
+    }
+
+    /// Loads the name and the channels of a guild
+    fn load_guild(&self, ctx: &Context, gid: GuildId) -> SourceResult<()> {
+        let name = gid.to_partial_guild(ctx)?.name;
+        let channels = gid.channels(ctx)?;
+        let mut data = self.inner.data.write().unwrap();
+        data.add_guild(gid, name);
+        for channel in channels.values() {
+            data.add_channel(channel);
+        }
+        Ok(())
+    }
+
+    /// Registers the commands as Discord application commands - immediately if the bot is
+    /// ready, or as soon as it becomes ready otherwise
+    pub fn register_commands(&mut self, commands: &[CommandDef]) -> SourceResult<()> {
+        let ready = {
+            let mut data = self.inner.data.write().unwrap();
+            data.commands = commands.to_vec();
+            data.user.is_some()
+        };
+        if ready {
+            self.upload_commands(commands)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the ID of the bot's application, which isn't necessarily the ID of the bot user -
+    /// it's fetched once and remembered
+    fn application_id(&self) -> SourceResult<String> {
+        if let Some(ref id) = self.inner.data.read().unwrap().application_id {
+            return Ok(id.clone());
+        }
+        let url = format!("{}/oauth2/applications/@me", self.inner.api_url);
+        let request = ureq::get(&url).set("Authorization", &format!("Bot {}", self.inner.token));
+        let application = http::call(&self.inner.id, request, None)?;
+        let id = match application["id"].as_str() {
+            Some(id) => id.to_owned(),
+            None => {
+                return Err(SourceError::ConnectionError(
+                    self.inner.id.clone(),
+                    "no application ID in the response".to_owned(),
+                ))
+            }
+        };
+        self.inner.data.write().unwrap().application_id = Some(id.clone());
+        Ok(id)
+    }
+
+    fn upload_commands(&self, defs: &[CommandDef]) -> SourceResult<()> {
+        let url = format!(
+            "{}/applications/{}/commands",
+            self.inner.api_url,
+            self.application_id()?
+        );
+        let body: Vec<_> = defs.iter().map(commands::command_to_json).collect();
+        let _ = ureq::put(&url)
+            .set("Authorization", &format!("Bot {}", self.inner.token))
+            .send_json(json!(body))
+            .map_err(|err| {
+                SourceError::ConnectionError(self.inner.id.clone(), http::describe(err))
+            })?;
+        Ok(())
+    }
+
+    pub fn respond(
+        &mut self,
+        command: &Command,
+        msg: MessageContent,
+        ephemeral: bool,
+    ) -> SourceResult<()> {
+        let token = match command.token {
+            Some(ref token) => token,
+            None if ephemeral => return self.send(Channel::User(command.author.clone()), msg),
+            None => return self.send(command.channel.clone(), msg),
+        };
+        let body = match commands::response_to_json(&msg, ephemeral) {
+            Some(body) => body,
+            None => {
+                return Err(SourceError::InvalidMessage(
+                    self.inner.id.clone(),
+                    Box::new(msg),
+                ))
+            }
+        };
+        // the URL carries the interaction token, so it's kept out of the errors
+        let url = format!("{}/interactions/{}/callback", self.inner.api_url, token);
+        let _ = ureq::post(&url).send_json(body).map_err(|err| {
+            SourceError::ConnectionError(self.inner.id.clone(), http::describe(err))
+        })?;
+        Ok(())
+    }
+
     fn replace_mentions(msg: String, mentions: &[User]) -> String {
         let mut result = msg;
         for mention in mentions {
@@ -104,21 +432,162 @@ impl DiscordEventHandler {
         }
         result
     }
+
+    /// Translates a Discord channel ID into a `Channel`, remembering group DMs so that they
+    /// can be addressed later
+    fn resolve_channel(&self, ctx: &Context, channel_id: ChannelId, author: &User) -> Channel {
+        match channel_id.to_channel(ctx) {
+            Ok(DiscordChannel::Guild(channel)) => {
+                let channel = channel.read();
+                let data = self.inner.data.read().unwrap();
+                Channel::Channel(data.qualified_name(channel.guild_id, &channel.name))
+            }
+            Ok(DiscordChannel::Private(_)) => Channel::User(author.name.clone()),
+            Ok(DiscordChannel::Group(group)) => {
+                let mut names: Vec<_> = group
+                    .read()
+                    .recipients
+                    .values()
+                    .map(|user| user.read().name.clone())
+                    .collect();
+                names.sort();
+                let mut data = self.inner.data.write().unwrap();
+                let _ = data.groups.insert(names.clone(), channel_id);
+                Channel::Group(names)
+            }
+            _ => Channel::Channel("[no channel]".to_string()),
+        }
+    }
 }
 
 impl EventHandler for DiscordEventHandler {
     fn ready(&self, ctx: Context, ready: Ready) {
+        {
+            let mut data = self.inner.data.write().unwrap();
+            data.http = Some(ctx.http.clone());
+            data.cache = Some(ctx.cache.clone());
+            data.user = Some(ready.user.clone());
+        }
+        let handler = self.clone();
+        let guilds = ready.guilds.iter().map(|guild| guild.id()).collect();
+        let _ = thread::spawn(move || handler.finish_ready(ctx, guilds));
+    }
+
+    fn unknown(&self, _ctx: Context, name: String, raw: Value) {
+        if name != "INTERACTION_CREATE" {
+            return;
+        }
+        let command = {
+            let data = self.inner.data.read().unwrap();
+            commands::interaction_to_command(&raw, |gid, cid| {
+                data.guilds
+                    .get(&gid)
+                    .and_then(|guild| guild.channels.get(&cid))
+                    .map(|name| data.qualified_name(gid, name))
+            })
+        };
+        if let Some(command) = command {
+            self.send_event(Event::Command(command));
+        }
+    }
+
+    fn guild_create(&self, _ctx: Context, guild: Guild, _is_new: bool) {
+        let mut data = self.inner.data.write().unwrap();
+        data.add_guild(guild.id, guild.name.clone());
+        for channel in guild.channels.values() {
+            data.add_channel(&channel.read());
+        }
+    }
+
+    fn guild_update(
+        &self,
+        _ctx: Context,
+        _old: Option<Arc<DiscordRwLock<Guild>>>,
+        new: PartialGuild,
+    ) {
+        let mut data = self.inner.data.write().unwrap();
+        data.add_guild(new.id, new.name);
+    }
+
+    fn guild_delete(
+        &self,
+        _ctx: Context,
+        guild: PartialGuild,
+        _full: Option<Arc<DiscordRwLock<Guild>>>,
+    ) {
         let mut data = self.inner.data.write().unwrap();
-        data.http = Some(ctx.http.clone());
-        data.user = Some(ready.user);
-        for guild in &ready.guilds {
-            let gid = guild.id();
-            for (cid, channel) in gid.channels(&ctx).unwrap() {
-                let _ = data.channels.insert(channel.name.clone(), cid);
+        let _ = data.guilds.remove(&guild.id);
+    }
+
+    fn channel_create(&self, _ctx: Context, channel: Arc<DiscordRwLock<GuildChannel>>) {
+        let mut data = self.inner.data.write().unwrap();
+        data.add_channel(&channel.read());
+    }
+
+    fn channel_update(&self, _ctx: Context, _old: Option<DiscordChannel>, new: DiscordChannel) {
+        if let DiscordChannel::Guild(channel) = new {
+            let mut data = self.inner.data.write().unwrap();
+            data.add_channel(&channel.read());
+        }
+    }
+
+    fn presence_update(&self, ctx: Context, update: PresenceUpdateEvent) {
+        let presence = update.presence;
+        let name = match presence.user {
+            Some(ref user) => Some(user.read().name.clone()),
+            None => self.user_name(&ctx, presence.user_id),
+        };
+        if let Some(name) = name {
+            match presence.status {
+                OnlineStatus::Offline | OnlineStatus::Invisible => {
+                    self.send_event(Event::UserOffline(name, None))
+                }
+                _ => self.send_event(Event::UserOnline(name)),
+            }
+        }
+    }
+
+    fn typing_start(&self, ctx: Context, event: TypingStartEvent) {
+        if let Some(name) = self.user_name(&ctx, event.user_id) {
+            self.send_event(Event::UserTyping(name));
+        }
+    }
+
+    fn guild_member_addition(&self, _ctx: Context, _guild_id: GuildId, member: Member) {
+        let name = member.user.read().name.clone();
+        self.send_event(Event::UserJoined(name));
+    }
+
+    fn guild_member_removal(
+        &self,
+        _ctx: Context,
+        _guild_id: GuildId,
+        user: User,
+        _member: Option<Member>,
+    ) {
+        self.send_event(Event::UserLeft(user.name));
+    }
+
+    fn guild_member_update(&self, _ctx: Context, old: Option<Member>, new: Member) {
+        let display_name = |member: &Member| {
+            member
+                .nick
+                .clone()
+                .unwrap_or_else(|| member.user.read().name.clone())
+        };
+        if let Some(old) = old {
+            let (old_name, new_name) = (display_name(&old), display_name(&new));
+            if old_name != new_name {
+                self.send_event(Event::NickChange(old_name, new_name));
             }
         }
     }
 
+    fn channel_delete(&self, _ctx: Context, channel: Arc<DiscordRwLock<GuildChannel>>) {
+        let mut data = self.inner.data.write().unwrap();
+        data.remove_channel(&channel.read());
+    }
+
     fn message(&self, ctx: Context, msg: Message) {
         let Message {
             author,
@@ -127,23 +596,173 @@ impl EventHandler for DiscordEventHandler {
             mentions,
             ..
         } = msg;
-        if author.name == self.nick() {
-            return;
-        }
+        let is_own = {
+            let mut data = self.inner.data.write().unwrap();
+            let _ = data.users.insert(author.name.clone(), author.id);
+            for user in &mentions {
+                let _ = data.users.insert(user.name.clone(), user.id);
+            }
+            data.user.as_ref().map(|u| u.id) == Some(author.id)
+        };
+        let channel = self.resolve_channel(&ctx, channel_id, &author);
         let content_mentions_replaced = Self::replace_mentions(content, &mentions);
         let msg = crate::core::Message {
             author: author.name,
-            channel: Channel::Channel(
-                channel_id
-                    .name(&ctx)
-                    .clone()
-                    .unwrap_or("[no channel]".to_string()),
-            ),
+            channel,
             content: MessageContent::Text(content_mentions_replaced),
+            is_own,
         };
-        let _ = self.inner.sender.lock().unwrap().send(SourceEvent {
-            source: self.inner.id.clone(),
-            event: Event::ReceivedMessage(msg),
+        self.send_event(Event::ReceivedMessage(msg));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{next_event, MockServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn handler(api_url: String) -> (DiscordEventHandler, Receiver<SourceEvent>) {
+        let (tx, rx) = channel();
+        let handler = DiscordEventHandler::new(
+            SourceId("discord".to_owned()),
+            tx,
+            "secret".to_owned(),
+            None,
+            api_url,
+        );
+        (handler, rx)
+    }
+
+    fn roll() -> CommandDef {
+        CommandDef {
+            name: "roll".to_owned(),
+            description: "Rolls a die".to_owned(),
+            options: vec![CommandOption {
+                name: "sides".to_owned(),
+                description: "The number of sides".to_owned(),
+                option_type: CommandOptionType::Integer,
+                required: false,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_upload_commands() {
+        let server = MockServer::start(|req| {
+            if req.url == "/oauth2/applications/@me" {
+                r#"{"id": "42"}"#.to_owned()
+            } else {
+                "[]".to_owned()
+            }
+        });
+        let (mut handler, _rx) = handler(server.url());
+
+        // not ready yet, so the commands are only remembered
+        handler.register_commands(&[roll()]).unwrap();
+        assert!(server.requests("/").is_empty());
+        assert_eq!(handler.inner.data.read().unwrap().commands.len(), 1);
+
+        handler.upload_commands(&[roll()]).unwrap();
+        handler.upload_commands(&[roll()]).unwrap();
+        // the application ID is fetched only once
+        assert_eq!(server.requests("/oauth2").len(), 1);
+        let uploads = server.requests("/applications/42/commands");
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0].method, "PUT");
+        let body: Value = serde_json::from_str(&uploads[0].body).unwrap();
+        assert_eq!(
+            body,
+            json!([{
+                "name": "roll",
+                "description": "Rolls a die",
+                "options": [{
+                    "name": "sides",
+                    "description": "The number of sides",
+                    "type": 4,
+                    "required": false,
+                }],
+            }])
+        );
+    }
+
+    #[test]
+    fn test_respond() {
+        let server = MockServer::start_with_status(|req| {
+            if req.url.starts_with("/interactions/1/gone/") {
+                (404, r#"{"message": "Unknown interaction"}"#.to_owned())
+            } else {
+                (204, String::new())
+            }
+        });
+        let (mut handler, _rx) = handler(server.url());
+        let mut command = Command {
+            name: "roll".to_owned(),
+            args: HashMap::new(),
+            author: "alice".to_owned(),
+            channel: Channel::Channel("guild/general".to_owned()),
+            token: Some("1/tok".to_owned()),
+        };
+
+        handler
+            .respond(&command, MessageContent::Text("4".to_owned()), true)
+            .unwrap();
+        let responses = server.requests("/interactions/1/tok/callback");
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].method, "POST");
+        let body: Value = serde_json::from_str(&responses[0].body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "type": 4, "data": { "content": "4", "flags": 64 } })
+        );
+
+        match handler.respond(&command, MessageContent::Image, false) {
+            Err(SourceError::InvalidMessage(_, _)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // the interaction token is a credential, so it must not show up in errors
+        command.token = Some("1/gone".to_owned());
+        match handler.respond(&command, MessageContent::Text("4".to_owned()), false) {
+            Err(SourceError::ConnectionError(_, ref message)) => {
+                assert!(message.contains("404"));
+                assert!(!message.contains("gone"));
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_ready_retries() {
+        // the application ID can't be fetched 4 times in a row
+        let failures = AtomicUsize::new(0);
+        let server = MockServer::start_with_status(move |req| {
+            if req.url != "/oauth2/applications/@me" {
+                (200, "[]".to_owned())
+            } else if failures.fetch_add(1, Ordering::SeqCst) < 4 {
+                (500, "{}".to_owned())
+            } else {
+                (200, r#"{"id": "42"}"#.to_owned())
+            }
         });
+        let (handler, rx) = handler(server.url());
+
+        let uploaded =
+            handler.with_retries("register commands", || handler.upload_commands(&[roll()]));
+        assert!(uploaded.is_none());
+        assert_eq!(server.requests("/oauth2").len(), READY_RETRIES as usize);
+        match next_event(&rx) {
+            Event::Other(ref text) => {
+                assert!(text.starts_with("Giving up trying to register commands after 3 attempts"))
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        let uploaded =
+            handler.with_retries("register commands", || handler.upload_commands(&[roll()]));
+        assert!(uploaded.is_some());
+        assert_eq!(server.requests("/oauth2").len(), 5);
+        assert_eq!(server.requests("/applications/42/commands").len(), 1);
     }
 }
@@ -1,7 +1,9 @@
+mod commands;
 mod event_handler;
 
 use crate::core::*;
 use crate::sources::*;
+use commands::API_BASE;
 use event_handler::DiscordEventHandler;
 use serenity::client::Client;
 use std::sync::mpsc::Sender;
@@ -13,9 +15,20 @@ enum DiscordState {
     Running(JoinHandle<()>),
 }
 
+fn default_api_url() -> String {
+    API_BASE.to_owned()
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct DiscordConfig {
     token: String,
+    /// The base URL of the HTTP API used for application commands, which serenity doesn't
+    /// handle
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    /// The guild in which channels given by short names (without the `guild/` prefix) are looked
+    /// up
+    default_guild: Option<String>,
 }
 
 pub struct DiscordSource {
@@ -41,7 +54,13 @@ impl DiscordSource {
             source_id
         ));
 
-        let handler = DiscordEventHandler::new(source_id.clone(), sender.clone());
+        let handler = DiscordEventHandler::new(
+            source_id.clone(),
+            sender.clone(),
+            config.token.clone(),
+            config.default_guild.clone(),
+            config.api_url.clone(),
+        );
         Box::new(DiscordSource {
             id: source_id,
             sender,
@@ -87,4 +106,17 @@ impl EventSource for DiscordSource {
     fn reconnect(&mut self) -> SourceResult<()> {
         self.connect()
     }
+
+    fn register_commands(&mut self, commands: &[CommandDef]) -> SourceResult<()> {
+        self.handler.register_commands(commands)
+    }
+
+    fn respond(
+        &mut self,
+        command: &Command,
+        msg: MessageContent,
+        ephemeral: bool,
+    ) -> SourceResult<()> {
+        self.handler.respond(command, msg, ephemeral)
+    }
 }
@@ -0,0 +1,196 @@
+//! A minimal IMAP client - just enough to watch a mailbox with IDLE
+
+use native_tls::TlsConnector;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::time::Duration;
+
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+fn other_error<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// A response line, along with the contents of the literals embedded in it
+pub struct Response {
+    pub line: String,
+    pub literals: Vec<Vec<u8>>,
+}
+
+pub struct ImapConnection {
+    reader: BufReader<Box<dyn Stream>>,
+    /// The underlying socket, used for setting the timeouts
+    socket: TcpStream,
+    /// The start of a line whose reading timed out, completed by the next read
+    partial: Vec<u8>,
+    tag: u32,
+}
+
+impl ImapConnection {
+    /// Connects to the server, optionally over TLS, and reads the greeting
+    pub fn connect(host: &str, port: u16, tls: bool) -> io::Result<Self> {
+        let socket = TcpStream::connect((host, port))?;
+        let stream: Box<dyn Stream> = if tls {
+            let connector = TlsConnector::new().map_err(other_error)?;
+            Box::new(
+                connector
+                    .connect(host, socket.try_clone()?)
+                    .map_err(other_error)?,
+            )
+        } else {
+            Box::new(socket.try_clone()?)
+        };
+        let mut conn = ImapConnection {
+            reader: BufReader::new(stream),
+            socket,
+            partial: vec![],
+            tag: 0,
+        };
+        let greeting = conn.read_response()?;
+        if !greeting.line.starts_with("* OK") {
+            return Err(other_error(greeting.line));
+        }
+        Ok(conn)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        // on errors, the bytes read so far stay in the buffer
+        if self.reader.read_until(b'\n', &mut self.partial)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let buf = mem::take(&mut self.partial);
+        let line = String::from_utf8_lossy(&buf);
+        Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+    }
+
+    fn read_response(&mut self) -> io::Result<Response> {
+        let mut line = self.read_line()?;
+        let mut literals = vec![];
+        while let Some(len) = literal_len(&line) {
+            let mut literal = vec![0; len];
+            self.reader.read_exact(&mut literal)?;
+            literals.push(literal);
+            let rest = self.read_line()?;
+            line.push_str(&rest);
+        }
+        Ok(Response { line, literals })
+    }
+
+    fn send_command(&mut self, command: &str) -> io::Result<String> {
+        self.tag += 1;
+        let tag = format!("A{}", self.tag);
+        let stream = self.reader.get_mut();
+        write!(stream, "{} {}\r\n", tag, command)?;
+        stream.flush()?;
+        Ok(tag)
+    }
+
+    /// Reads the responses up to the tagged one, failing unless it's OK
+    fn read_until_tagged(&mut self, tag: &str) -> io::Result<Vec<Response>> {
+        let mut responses = vec![];
+        loop {
+            let resp = self.read_response()?;
+            if resp.line.starts_with(&format!("{} ", tag)) {
+                if resp.line[tag.len() + 1..].starts_with("OK") {
+                    return Ok(responses);
+                }
+                return Err(other_error(resp.line));
+            }
+            responses.push(resp);
+        }
+    }
+
+    /// Sends a command and returns the untagged responses to it
+    pub fn command(&mut self, command: &str) -> io::Result<Vec<Response>> {
+        let tag = self.send_command(command)?;
+        self.read_until_tagged(&tag)
+    }
+
+    pub fn login(&mut self, user: &str, password: &str) -> io::Result<()> {
+        let _ = self.command(&format!("LOGIN {} {}", quote(user), quote(password)))?;
+        Ok(())
+    }
+
+    /// Selects the mailbox and returns the UID the next message will get
+    pub fn select(&mut self, mailbox: &str) -> io::Result<u32> {
+        let responses = self.command(&format!("SELECT {}", quote(mailbox)))?;
+        Ok(responses
+            .iter()
+            .filter_map(|resp| find_number(&resp.line, "[UIDNEXT "))
+            .next()
+            .unwrap_or(1))
+    }
+
+    /// Fetches the messages with UIDs starting from `first_uid`, returning the UIDs along with
+    /// the raw messages
+    pub fn fetch_from(&mut self, first_uid: u32) -> io::Result<Vec<(u32, Vec<u8>)>> {
+        let responses = self.command(&format!("UID FETCH {}:* (UID BODY.PEEK[])", first_uid))?;
+        Ok(responses
+            .into_iter()
+            .filter_map(|mut resp| {
+                let uid = find_number(&resp.line, "UID ")?;
+                // `n:*` always matches the last message, even if its UID is lower than n
+                if uid < first_uid || resp.literals.is_empty() {
+                    return None;
+                }
+                Some((uid, resp.literals.remove(0)))
+            })
+            .collect())
+    }
+
+    /// Waits until a message arrives to the selected mailbox, or until `timeout` passes
+    pub fn idle(&mut self, timeout: Duration) -> io::Result<()> {
+        let tag = self.send_command("IDLE")?;
+        let resp = self.read_response()?;
+        if !resp.line.starts_with('+') {
+            return Err(other_error(resp.line));
+        }
+        self.socket.set_read_timeout(Some(timeout))?;
+        let result = loop {
+            match self.read_response() {
+                Ok(ref resp) if resp.line.ends_with("EXISTS") => break Ok(()),
+                Ok(_) => (),
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    break Ok(())
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.socket.set_read_timeout(None)?;
+        result?;
+        let stream = self.reader.get_mut();
+        stream.write_all(b"DONE\r\n")?;
+        stream.flush()?;
+        let _ = self.read_until_tagged(&tag)?;
+        Ok(())
+    }
+}
+
+/// Returns the length of the literal announced at the end of the line, if any
+fn literal_len(line: &str) -> Option<usize> {
+    if !line.ends_with('}') {
+        return None;
+    }
+    let start = line.rfind('{')?;
+    line[start + 1..line.len() - 1].parse().ok()
+}
+
+/// Finds the number following `prefix` in the line
+fn find_number(line: &str, prefix: &str) -> Option<u32> {
+    let start = line.find(prefix)? + prefix.len();
+    let digits: String = line[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+/// Makes a quoted string out of a command argument
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,580 @@
+mod imap;
+
+use self::imap::ImapConnection;
+use crate::core::*;
+use crate::sources::*;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{SmtpTransport, Transport};
+use mail_parser::{HeaderValue, MessageParser};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toml::Value;
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_mailboxes() -> Vec<String> {
+    vec!["INBOX".to_owned()]
+}
+
+fn default_idle_timeout() -> u64 {
+    // servers may drop connections idle for 30 minutes
+    25 * 60
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SmtpSecurity {
+    Tls,
+    #[default]
+    StartTls,
+    None,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EmailConfig {
+    /// The address the messages are sent from
+    address: String,
+    /// The credentials, used for both IMAP and SMTP
+    username: String,
+    password: String,
+    imap_host: String,
+    #[serde(default = "default_imap_port")]
+    imap_port: u16,
+    /// Whether to connect to the IMAP server over TLS
+    #[serde(default = "default_true")]
+    imap_tls: bool,
+    smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    smtp_port: u16,
+    #[serde(default)]
+    smtp_security: SmtpSecurity,
+    /// Mailboxes to watch for new messages
+    #[serde(default = "default_mailboxes")]
+    mailboxes: Vec<String>,
+    /// How long to wait for new messages before renewing IDLE, in seconds
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+}
+
+/// A helper enum for EmailSource
+enum SourceState {
+    Disconnected,
+    /// The threads watching the mailboxes, by mailbox
+    Running(HashMap<String, JoinHandle<()>>),
+}
+
+/// What is needed to reply to a received message
+#[derive(Clone, Debug)]
+struct ReplyInfo {
+    from: String,
+    subject: String,
+    message_id: Option<String>,
+    references: Vec<String>,
+}
+
+/// The last received messages, which the sent messages are replies to
+#[derive(Default)]
+struct Replies {
+    by_mailbox: HashMap<String, ReplyInfo>,
+    by_sender: HashMap<String, ReplyInfo>,
+}
+
+/// An email event source
+pub struct EmailSource {
+    /// the source ID
+    id: SourceId,
+    /// Email configuration data
+    config: EmailConfig,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// Messages to reply to, shared with the IMAP threads
+    replies: Arc<RwLock<Replies>>,
+    /// The mailboxes joined on top of the configured ones
+    joined: Vec<String>,
+    /// The mailboxes whose threads stopped on an error, to be opened again on reconnecting
+    failed: Arc<Mutex<Vec<String>>>,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl EmailSource {
+    /// Creates an EmailSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Email source {:?}!",
+            source_id
+        ));
+        let config: EmailConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Email source {:?}",
+            source_id
+        ));
+
+        Box::new(EmailSource {
+            id: source_id,
+            config,
+            sender,
+            replies: Default::default(),
+            joined: vec![],
+            failed: Default::default(),
+            state: SourceState::Disconnected,
+        })
+    }
+
+    fn error<T: ToString>(&self, err: T) -> SourceError {
+        SourceError::ConnectionError(self.id.clone(), err.to_string())
+    }
+
+    /// Logs into the IMAP server and selects the mailbox, returning the UID of the next message
+    fn open_mailbox(&self, mailbox: &str) -> SourceResult<(ImapConnection, u32)> {
+        let mut conn = ImapConnection::connect(
+            &self.config.imap_host,
+            self.config.imap_port,
+            self.config.imap_tls,
+        )
+        .map_err(|e| self.error(e))?;
+        conn.login(&self.config.username, &self.config.password)
+            .map_err(|e| self.error(e))?;
+        let next_uid = conn.select(mailbox).map_err(|e| self.error(e))?;
+        Ok((conn, next_uid))
+    }
+
+    /// Starts a thread watching the opened mailbox - when the connection fails, the mailbox is
+    /// marked as failed and the source as disconnected
+    fn watch(&self, mailbox: String, conn: ImapConnection, next_uid: u32) -> JoinHandle<()> {
+        let config = self.config.clone();
+        let replies = self.replies.clone();
+        let failed = self.failed.clone();
+        let id = self.id.clone();
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let err = watch_mailbox(conn, next_uid, &mailbox, &config, &replies, &id, &sender);
+            failed.lock().unwrap().push(mailbox.clone());
+            let _ = sender.send(SourceEvent {
+                source: id,
+                event: Event::Disconnected(format!("{}: {}", mailbox, err)),
+            });
+        })
+    }
+
+    fn mailer(&self) -> SourceResult<SmtpTransport> {
+        let host = &self.config.smtp_host;
+        let builder = SmtpTransport::builder_dangerous(host).port(self.config.smtp_port);
+        let builder = match self.config.smtp_security {
+            SmtpSecurity::None => builder.tls(Tls::None),
+            security => {
+                let params = TlsParameters::new(host.clone()).map_err(|e| self.error(e))?;
+                match security {
+                    SmtpSecurity::Tls => builder.tls(Tls::Wrapper(params)),
+                    _ => builder.tls(Tls::Required(params)),
+                }
+            }
+        };
+        Ok(builder
+            .credentials(Credentials::new(
+                self.config.username.clone(),
+                self.config.password.clone(),
+            ))
+            .build())
+    }
+}
+
+/// Watches a mailbox, sending an event for every new message, until the connection fails
+fn watch_mailbox(
+    mut conn: ImapConnection,
+    mut next_uid: u32,
+    mailbox: &str,
+    config: &EmailConfig,
+    replies: &RwLock<Replies>,
+    id: &SourceId,
+    sender: &Sender<SourceEvent>,
+) -> io::Error {
+    let timeout = Duration::from_secs(config.idle_timeout);
+    loop {
+        let messages = match conn.fetch_from(next_uid) {
+            Ok(messages) => messages,
+            Err(e) => return e,
+        };
+        for (uid, raw) in messages {
+            next_uid = next_uid.max(uid + 1);
+            let (msg, reply) = match parse_message(&raw, mailbox, &config.address) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            {
+                let mut replies = replies.write().unwrap();
+                let _ = replies.by_mailbox.insert(mailbox.to_owned(), reply.clone());
+                let _ = replies.by_sender.insert(reply.from.clone(), reply);
+            }
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::ReceivedMessage(msg),
+            });
+        }
+        if let Err(e) = conn.idle(timeout) {
+            return e;
+        }
+    }
+}
+
+/// Returns the address alone out of `Name <address>`
+fn address_part(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// Parses a raw message into the `Message` and the information needed to reply to it
+fn parse_message(raw: &[u8], mailbox: &str, own_address: &str) -> Option<(Message, ReplyInfo)> {
+    let parsed = MessageParser::default().parse(raw)?;
+    let from = parsed.from()?.first()?.address()?.to_owned();
+    let subject = parsed.subject().unwrap_or("").to_owned();
+    let body = parsed
+        .body_text(0)
+        .map(|body| body.trim().to_owned())
+        .unwrap_or_default();
+    let references = match *parsed.references() {
+        HeaderValue::Text(ref id) => vec![id.to_string()],
+        HeaderValue::TextList(ref ids) => ids.iter().map(|id| id.to_string()).collect(),
+        _ => vec![],
+    };
+    let content = if body.is_empty() {
+        subject.clone()
+    } else {
+        format!("{}\n\n{}", subject, body)
+    };
+    let msg = Message {
+        author: from.clone(),
+        channel: Channel::Channel(mailbox.to_owned()),
+        content: MessageContent::Text(content),
+        is_own: from.eq_ignore_ascii_case(address_part(own_address)),
+    };
+    let reply = ReplyInfo {
+        from,
+        subject,
+        message_id: parsed.message_id().map(|id| id.to_owned()),
+        references,
+    };
+    Some((msg, reply))
+}
+
+/// Formats a list of message IDs for the `References` and `In-Reply-To` headers
+fn message_ids<'a, I: IntoIterator<Item = &'a String>>(ids: I) -> String {
+    ids.into_iter()
+        .map(|id| format!("<{}>", id))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl EventSource for EmailSource {
+    fn get_nick(&self) -> String {
+        self.config.address.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        let mut mailboxes = vec![];
+        for mailbox in self.config.mailboxes.iter().chain(&self.joined) {
+            let (conn, next_uid) = self.open_mailbox(mailbox)?;
+            mailboxes.push((mailbox.clone(), conn, next_uid));
+        }
+        self.failed.lock().unwrap().clear();
+        let _ = self.sender.send(SourceEvent {
+            source: self.id.clone(),
+            event: Event::Connected,
+        });
+
+        let handles = mailboxes
+            .into_iter()
+            .map(|(mailbox, conn, next_uid)| (mailbox.clone(), self.watch(mailbox, conn, next_uid)))
+            .collect();
+        self.state = SourceState::Running(handles);
+        Ok(())
+    }
+
+    /// Starts watching another mailbox, also after reconnecting
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        if self
+            .config
+            .mailboxes
+            .iter()
+            .chain(&self.joined)
+            .any(|m| m == channel)
+        {
+            return Ok(());
+        }
+        if let SourceState::Running(_) = self.state {
+            let (conn, next_uid) = self.open_mailbox(channel)?;
+            let handle = self.watch(channel.to_owned(), conn, next_uid);
+            if let SourceState::Running(ref mut handles) = self.state {
+                let _ = handles.insert(channel.to_owned(), handle);
+            }
+        }
+        self.joined.push(channel.to_owned());
+        Ok(())
+    }
+
+    /// Replies to the last message received in a mailbox or from a user; messages to users who
+    /// haven't written yet start a new thread, with the first line of the text as the subject
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let text = match msg {
+            MessageContent::Text(t) | MessageContent::Me(t) => t,
+            MessageContent::Embed(ref embed) => embed.to_text(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let reply = {
+            let replies = self.replies.read().unwrap();
+            match dst {
+                Channel::Channel(ref mailbox) => replies.by_mailbox.get(mailbox).cloned(),
+                Channel::User(ref address) => replies.by_sender.get(address).cloned(),
+                _ => None,
+            }
+        };
+
+        let from = self.config.address.parse().map_err(|e| self.error(e))?;
+        let builder = lettre::Message::builder().from(from);
+        let (to, builder) = match (reply, &dst) {
+            (Some(reply), _) => {
+                let subject = if reply.subject.to_lowercase().starts_with("re:") {
+                    reply.subject.clone()
+                } else {
+                    format!("Re: {}", reply.subject)
+                };
+                let mut builder = builder.subject(subject);
+                if let Some(ref message_id) = reply.message_id {
+                    let references = reply.references.iter().chain(Some(message_id));
+                    builder = builder
+                        .in_reply_to(message_ids(Some(message_id)))
+                        .references(message_ids(references));
+                }
+                (reply.from, builder)
+            }
+            (None, &Channel::User(ref address)) => {
+                let subject = text.lines().next().unwrap_or("").to_owned();
+                (address.clone(), builder.subject(subject))
+            }
+            _ => return Err(SourceError::InvalidChannel(self.id.clone(), dst)),
+        };
+        let to = to
+            .parse()
+            .map_err(|_| SourceError::InvalidChannel(self.id.clone(), dst.clone()))?;
+        let email = builder.to(to).body(text).map_err(|e| self.error(e))?;
+        let _ = self.mailer()?.send(&email).map_err(|e| self.error(e))?;
+        Ok(())
+    }
+
+    /// Opens again the mailboxes whose connections failed - the others are still being watched
+    fn reconnect(&mut self) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return self.connect();
+        }
+        let mut failed = mem::take(&mut *self.failed.lock().unwrap());
+        if failed.is_empty() {
+            return Ok(());
+        }
+        while let Some(mailbox) = failed.pop() {
+            if let SourceState::Running(ref mut handles) = self.state {
+                if let Some(handle) = handles.remove(&mailbox) {
+                    let _ = handle.join();
+                }
+            }
+            let handle = match self.open_mailbox(&mailbox) {
+                Ok((conn, next_uid)) => self.watch(mailbox.clone(), conn, next_uid),
+                Err(e) => {
+                    // left for the next attempt
+                    failed.push(mailbox);
+                    self.failed.lock().unwrap().extend(failed);
+                    return Err(e);
+                }
+            };
+            if let SourceState::Running(ref mut handles) = self.state {
+                let _ = handles.insert(mailbox, handle);
+            }
+        }
+        let _ = self.sender.send(SourceEvent {
+            source: self.id.clone(),
+            event: Event::Connected,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{accept_one, expect_connected, next_message};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::channel;
+
+    const MESSAGE: &str = "From: Alice <alice@example.com>\r\nTo: bot@example.com\r\n\
+                           Subject: Disk full\r\nMessage-ID: <2@example.com>\r\n\
+                           References: <1@example.com>\r\n\r\n/var is at 100%\r\n";
+
+    fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        let _ = reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    /// A scripted IMAP server: a message arrives while the client is idling
+    fn imap_server() -> u16 {
+        let address = accept_one(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"* OK IMAP ready\r\n").unwrap();
+            let mut idles = 0;
+            loop {
+                let line = read_line(&mut reader);
+                let mut parts = line.trim_end().splitn(2, ' ');
+                let tag = parts.next().unwrap().to_owned();
+                let command = parts.next().unwrap_or("");
+                let reply = if command.starts_with("LOGIN") {
+                    assert_eq!(command, "LOGIN \"bot\" \"secret\"");
+                    format!("{} OK logged in\r\n", tag)
+                } else if command.starts_with("SELECT") {
+                    format!("* 1 EXISTS\r\n* OK [UIDNEXT 2]\r\n{} OK selected\r\n", tag)
+                } else if command.starts_with("UID FETCH 2:*") && idles > 0 {
+                    format!(
+                        "* 2 FETCH (UID 2 BODY[] {{{}}}\r\n{})\r\n{} OK fetched\r\n",
+                        MESSAGE.len(),
+                        MESSAGE,
+                        tag
+                    )
+                } else if command.starts_with("UID FETCH") {
+                    // the old message is returned for `n:*`, and must be skipped
+                    format!(
+                        "* 1 FETCH (UID 1 BODY[] {{2}}\r\nhi)\r\n{} OK fetched\r\n",
+                        tag
+                    )
+                } else if command == "IDLE" {
+                    idles += 1;
+                    writer.write_all(b"+ idling\r\n").unwrap();
+                    if idles > 1 {
+                        // keep idling until the client disconnects
+                        let _ = read_line(&mut reader);
+                        return;
+                    }
+                    writer.write_all(b"* 2 EXISTS\r\n").unwrap();
+                    assert_eq!(read_line(&mut reader), "DONE\r\n");
+                    format!("{} OK idle done\r\n", tag)
+                } else {
+                    format!("{} BAD unexpected\r\n", tag)
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        address.port()
+    }
+
+    /// A scripted SMTP server, collecting the received message data
+    fn smtp_server() -> (u16, Arc<Mutex<String>>) {
+        let data = Arc::new(Mutex::new(String::new()));
+        let thread_data = data.clone();
+        let address = accept_one(move |stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            loop {
+                let line = read_line(&mut reader);
+                let reply = if line.starts_with("EHLO") {
+                    "250-localhost\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    "235 authenticated\r\n"
+                } else if line.starts_with("DATA") {
+                    writer.write_all(b"354 go ahead\r\n").unwrap();
+                    loop {
+                        let line = read_line(&mut reader);
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        thread_data.lock().unwrap().push_str(&line);
+                    }
+                    "250 queued\r\n"
+                } else if line.starts_with("QUIT") || line.is_empty() {
+                    let _ = writer.write_all(b"221 bye\r\n");
+                    return;
+                } else {
+                    "250 ok\r\n"
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        (address.port(), data)
+    }
+
+    #[test]
+    fn test_own_address() {
+        let (msg, _) = parse_message(MESSAGE.as_bytes(), "INBOX", "ALICE@example.com").unwrap();
+        assert!(msg.is_own);
+        let (msg, _) =
+            parse_message(MESSAGE.as_bytes(), "INBOX", "Alice <Alice@Example.com>").unwrap();
+        assert!(msg.is_own);
+        let (msg, _) = parse_message(MESSAGE.as_bytes(), "INBOX", "bot@example.com").unwrap();
+        assert!(!msg.is_own);
+    }
+
+    #[test]
+    fn test_receive_and_reply() {
+        let imap_port = imap_server();
+        let (smtp_port, sent) = smtp_server();
+        let config = format!(
+            "address = \"bot@example.com\"\nusername = \"bot\"\npassword = \"secret\"\n\
+             imap_host = \"127.0.0.1\"\nimap_port = {}\nimap_tls = false\n\
+             smtp_host = \"127.0.0.1\"\nsmtp_port = {}\nsmtp_security = \"none\"",
+            imap_port, smtp_port
+        );
+        let (tx, rx) = channel();
+        let mut source = EmailSource::new(
+            SourceId("email".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+
+        expect_connected(&rx);
+        let msg = next_message(&rx);
+        assert_eq!(msg.author, "alice@example.com");
+        assert_eq!(msg.channel, Channel::Channel("INBOX".to_owned()));
+        match msg.content {
+            MessageContent::Text(ref t) => assert_eq!(t, "Disk full\n\n/var is at 100%"),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+
+        source
+            .send(
+                Channel::Channel("INBOX".to_owned()),
+                MessageContent::Text("On it".to_owned()),
+            )
+            .unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(sent.contains("To: alice@example.com\r\n"));
+        assert!(sent.contains("Subject: Re: Disk full\r\n"));
+        assert!(sent.contains("In-Reply-To: <2@example.com>\r\n"));
+        assert!(sent.contains("References: <1@example.com> <2@example.com>\r\n"));
+        assert!(sent.contains("\r\nOn it"));
+    }
+}
@@ -15,7 +15,8 @@ pub enum SourceError {
     Disconnected(SourceId),
     ConnectionError(SourceId, String),
     InvalidChannel(SourceId, Channel),
-    InvalidMessage(SourceId, MessageContent),
+    /// Boxed, as embeds would make every result carrying the error large
+    InvalidMessage(SourceId, Box<MessageContent>),
     #[cfg(feature = "irc")]
     IrcError(IrcError),
     #[cfg(feature = "irc")]
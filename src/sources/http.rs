@@ -0,0 +1,75 @@
+//! Helpers for the sources talking to JSON-over-HTTP APIs
+
+use crate::core::SourceId;
+use crate::sources::{SourceError, SourceResult};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Creates an HTTP agent whose reads time out after `read_timeout`
+/// The timeout has to be longer than the server-side timeout of any long-polling requests.
+pub fn agent(read_timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(10))
+        .timeout_read(read_timeout)
+        .build()
+}
+
+/// Performs the request, optionally with a JSON body, and parses the response as JSON
+pub fn call(
+    source_id: &SourceId,
+    request: ureq::Request,
+    body: Option<Value>,
+) -> SourceResult<Value> {
+    let response = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    parse(source_id, response)
+}
+
+/// Performs the request with a form-encoded body and parses the response as JSON
+pub fn call_form(
+    source_id: &SourceId,
+    request: ureq::Request,
+    form: &[(&str, &str)],
+) -> SourceResult<Value> {
+    parse(source_id, request.send_form(form))
+}
+
+fn parse(
+    source_id: &SourceId,
+    response: Result<ureq::Response, ureq::Error>,
+) -> SourceResult<Value> {
+    response
+        .map_err(|err| SourceError::ConnectionError(source_id.clone(), describe(err)))?
+        .into_json()
+        .map_err(|err| SourceError::ConnectionError(source_id.clone(), err.to_string()))
+}
+
+/// Describes the error without the URL of the request, which can contain credentials (eg.
+/// Telegram's bot tokens)
+pub fn describe(err: ureq::Error) -> String {
+    match err {
+        ureq::Error::Status(code, response) => {
+            format!("status code {} ({})", code, response.status_text())
+        }
+        ureq::Error::Transport(transport) => match transport.message() {
+            Some(message) => format!("{}: {}", transport.kind(), message),
+            None => transport.kind().to_string(),
+        },
+    }
+}
+
+/// Percent-encodes a string so that it can be used as a single URL path segment
+pub fn encode(segment: &str) -> String {
+    let mut result = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(byte as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
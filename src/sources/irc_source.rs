@@ -51,10 +51,10 @@ impl IrcSource {
     }
 }
 
-fn message_to_events(msg: ::irc::client::prelude::Message) -> Vec<Event> {
+fn message_to_events(msg: ::irc::client::prelude::Message, own_nick: &str) -> Vec<Event> {
     use irc::client::prelude::Command::*;
     use irc::client::prelude::Response::*;
-    let sender = msg
+    let sender: String = msg
         .prefix
         .clone()
         .unwrap_or_else(|| "".to_string())
@@ -65,6 +65,8 @@ fn message_to_events(msg: ::irc::client::prelude::Message) -> Vec<Event> {
         PING(_, _) => vec![],
         PONG(_, _) => vec![],
         PRIVMSG(from, txt) => vec![Event::ReceivedMessage(crate::core::Message {
+            // IRC has no stable user IDs, so the nick is the best we can do
+            is_own: sender == own_nick,
             author: sender,
             channel: if from.starts_with("#") {
                 Channel::Channel(from)
@@ -98,6 +100,7 @@ impl EventSource for IrcSource {
         // create clones of some values for the event thread
         let thread_sender = self.sender.clone();
         let source_id = self.id.clone();
+        let nick = self.nick.clone();
 
         let (tx, rx) = channel();
         let config = self.config.clone();
@@ -109,7 +112,7 @@ impl EventSource for IrcSource {
 
             client.identify()?;
             reactor.register_client_with_handler(client.clone(), move |_, message| {
-                let events = message_to_events(message);
+                let events = message_to_events(message, &nick);
                 for event in events {
                     let _ = thread_sender.send(SourceEvent {
                         source: source_id.clone(),
@@ -148,13 +151,16 @@ impl EventSource for IrcSource {
             Channel::User(u) => u,
             _ => return Err(SourceError::InvalidChannel(self.id.clone(), dst)),
         };
-        let msg = match msg {
-            MessageContent::Text(t) => t,
-            MessageContent::Me(t) => t,
-            _ => return Err(SourceError::InvalidMessage(self.id.clone(), msg)),
+        let lines = match msg {
+            MessageContent::Text(t) => vec![t],
+            MessageContent::Me(t) => vec![t],
+            MessageContent::Embed(embed) => embed.to_lines(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
         };
-        let message = ::irc::client::prelude::Command::PRIVMSG(target, msg);
-        state.send(message)?;
+        for line in lines {
+            let message = ::irc::client::prelude::Command::PRIVMSG(target.clone(), line);
+            state.send(message)?;
+        }
         Ok(())
     }
 
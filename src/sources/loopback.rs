@@ -0,0 +1,251 @@
+use crate::core::*;
+use crate::sources::*;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use toml::Value;
+
+fn default_nick() -> String {
+    "bot".to_owned()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LoopbackConfig {
+    #[serde(default = "default_nick")]
+    nick: String,
+    /// Whether the sent messages come back as received ones, flagged as own
+    #[serde(default)]
+    echo: bool,
+}
+
+/// A call made on a loopback source
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoopbackAction {
+    Connect,
+    Reconnect,
+    Join(String),
+    Send {
+        channel: Channel,
+        content: MessageContent,
+        /// Set for the messages sent with `send_as`
+        identity: Option<Identity>,
+    },
+    RegisterCommands(Vec<String>),
+    Respond {
+        command: Command,
+        content: MessageContent,
+        ephemeral: bool,
+    },
+}
+
+struct LoopbackData {
+    config: LoopbackConfig,
+    actions: Vec<LoopbackAction>,
+}
+
+/// Gives access to what has been done with a loopback source, and lets events be injected into it
+#[derive(Clone)]
+pub struct LoopbackHandle {
+    id: SourceId,
+    sender: Sender<SourceEvent>,
+    data: Arc<Mutex<LoopbackData>>,
+}
+
+impl LoopbackHandle {
+    /// Queues an event, as if the source received it
+    pub fn inject(&self, event: Event) {
+        let _ = self.sender.send(SourceEvent {
+            source: self.id.clone(),
+            event,
+        });
+    }
+
+    /// Queues a text message from `author` in `channel`
+    pub fn inject_message(&self, author: &str, channel: Channel, text: &str) {
+        self.inject(Event::ReceivedMessage(Message {
+            author: author.to_owned(),
+            channel,
+            content: MessageContent::Text(text.to_owned()),
+            is_own: false,
+        }));
+    }
+
+    /// All the calls made on the source so far
+    pub fn actions(&self) -> Vec<LoopbackAction> {
+        self.data.lock().unwrap().actions.clone()
+    }
+
+    /// The messages sent so far, as channels and contents
+    pub fn sent(&self) -> Vec<(Channel, MessageContent)> {
+        self.data
+            .lock()
+            .unwrap()
+            .actions
+            .iter()
+            .filter_map(|action| match *action {
+                LoopbackAction::Send {
+                    ref channel,
+                    ref content,
+                    ..
+                } => Some((channel.clone(), content.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The channels joined so far
+    pub fn joined(&self) -> Vec<String> {
+        self.data
+            .lock()
+            .unwrap()
+            .actions
+            .iter()
+            .filter_map(|action| match *action {
+                LoopbackAction::Join(ref channel) => Some(channel.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Forgets the calls made so far
+    pub fn clear(&self) {
+        self.data.lock().unwrap().actions.clear();
+    }
+
+    pub fn set_echo(&self, echo: bool) {
+        self.data.lock().unwrap().config.echo = echo;
+    }
+}
+
+/// An in-memory source recording everything done with it, for testing modules
+pub struct LoopbackSource {
+    handle: LoopbackHandle,
+}
+
+impl LoopbackSource {
+    /// Creates a LoopbackSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        Box::new(Self::with_handle(source_id, sender, config).0)
+    }
+
+    /// Creates a LoopbackSource along with a handle to it
+    pub fn with_handle(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> (LoopbackSource, LoopbackHandle) {
+        let config = config.unwrap_or_else(|| Value::Table(Default::default()));
+        let config: LoopbackConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Loopback source {:?}",
+            source_id
+        ));
+        let handle = LoopbackHandle {
+            id: source_id,
+            sender,
+            data: Arc::new(Mutex::new(LoopbackData {
+                config,
+                actions: vec![],
+            })),
+        };
+        (
+            LoopbackSource {
+                handle: handle.clone(),
+            },
+            handle,
+        )
+    }
+
+    fn record(&self, action: LoopbackAction) {
+        self.handle.data.lock().unwrap().actions.push(action);
+    }
+
+    fn record_message(
+        &self,
+        channel: Channel,
+        content: MessageContent,
+        identity: Option<Identity>,
+    ) {
+        let (echo, nick) = {
+            let data = self.handle.data.lock().unwrap();
+            (data.config.echo, data.config.nick.clone())
+        };
+        if echo {
+            self.handle.inject(Event::ReceivedMessage(Message {
+                author: identity
+                    .as_ref()
+                    .and_then(|identity| identity.username.clone())
+                    .unwrap_or(nick),
+                channel: channel.clone(),
+                content: content.clone(),
+                is_own: true,
+            }));
+        }
+        self.record(LoopbackAction::Send {
+            channel,
+            content,
+            identity,
+        });
+    }
+}
+
+impl EventSource for LoopbackSource {
+    fn get_nick(&self) -> String {
+        self.handle.data.lock().unwrap().config.nick.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        self.record(LoopbackAction::Connect);
+        self.handle.inject(Event::Connected);
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        self.record(LoopbackAction::Join(channel.to_owned()));
+        Ok(())
+    }
+
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        self.record_message(dst, msg, None);
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        self.record(LoopbackAction::Reconnect);
+        self.handle.inject(Event::Connected);
+        Ok(())
+    }
+
+    fn send_as(
+        &mut self,
+        dst: Channel,
+        msg: MessageContent,
+        identity: &Identity,
+    ) -> SourceResult<()> {
+        self.record_message(dst, msg, Some(identity.clone()));
+        Ok(())
+    }
+
+    fn register_commands(&mut self, commands: &[CommandDef]) -> SourceResult<()> {
+        let mut names: Vec<_> = commands.iter().map(|def| def.name.clone()).collect();
+        names.sort();
+        self.record(LoopbackAction::RegisterCommands(names));
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        command: &Command,
+        msg: MessageContent,
+        ephemeral: bool,
+    ) -> SourceResult<()> {
+        self.record(LoopbackAction::Respond {
+            command: command.clone(),
+            content: msg,
+            ephemeral,
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+use crate::core::*;
+use crate::sources::http;
+use crate::sources::*;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toml::Value;
+
+/// The prefix of the client-server API endpoints
+const API: &str = "/_matrix/client/v3";
+
+fn default_sync_timeout() -> u64 {
+    30000
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MatrixConfig {
+    /// The base URL of the homeserver, eg. `https://matrix.org`
+    homeserver: String,
+    /// The access token; if it isn't given, the source logs in with `user` and `password`
+    access_token: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+    /// Rooms (IDs or aliases) to join when connecting
+    #[serde(default)]
+    rooms: Vec<String>,
+    /// Whether to join the rooms the bot is invited to
+    #[serde(default)]
+    accept_invites: bool,
+    /// The server-side timeout of /sync requests, in milliseconds
+    #[serde(default = "default_sync_timeout")]
+    sync_timeout: u64,
+}
+
+/// A helper enum for MatrixSource
+enum SourceState {
+    Disconnected,
+    Running(JoinHandle<()>),
+}
+
+/// Data shared between the source and the sync thread
+#[derive(Default)]
+struct MatrixData {
+    user_id: String,
+    access_token: String,
+    /// Room aliases by room IDs
+    aliases: HashMap<String, String>,
+    /// Direct message rooms - the other user's ID by room ID
+    direct: HashMap<String, String>,
+}
+
+impl MatrixData {
+    fn channel(&self, room_id: &str) -> Channel {
+        match self.direct.get(room_id) {
+            Some(user) => Channel::User(user.clone()),
+            None => Channel::Channel(
+                self.aliases
+                    .get(room_id)
+                    .cloned()
+                    .unwrap_or_else(|| room_id.to_owned()),
+            ),
+        }
+    }
+
+    fn room_by_alias(&self, alias: &str) -> Option<String> {
+        if alias.starts_with('!') {
+            return Some(alias.to_owned());
+        }
+        self.aliases
+            .iter()
+            .find(|&(_, a)| a == alias)
+            .map(|(room_id, _)| room_id.clone())
+    }
+
+    fn room_by_user(&self, user: &str) -> Option<String> {
+        self.direct
+            .iter()
+            .find(|&(_, u)| u == user)
+            .map(|(room_id, _)| room_id.clone())
+    }
+}
+
+/// A client of the Matrix client-server API, shared by the source and the sync thread
+#[derive(Clone)]
+struct MatrixClient {
+    id: SourceId,
+    config: MatrixConfig,
+    agent: ureq::Agent,
+    data: Arc<RwLock<MatrixData>>,
+    txn_counter: Arc<AtomicUsize>,
+}
+
+impl MatrixClient {
+    fn new(id: SourceId, config: MatrixConfig) -> Self {
+        let agent = http::agent(Duration::from_millis(config.sync_timeout + 10000));
+        MatrixClient {
+            id,
+            config,
+            agent,
+            data: Default::default(),
+            txn_counter: Default::default(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let url = format!("{}{}{}", self.config.homeserver, API, path);
+        let token = self.data.read().unwrap().access_token.clone();
+        self.agent
+            .request(method, &url)
+            .set("Authorization", &format!("Bearer {}", token))
+    }
+
+    fn call(&self, request: ureq::Request, body: Option<JsonValue>) -> SourceResult<JsonValue> {
+        http::call(&self.id, request, body)
+    }
+
+    fn login(&self) -> SourceResult<()> {
+        let (access_token, user_id) = match self.config.access_token {
+            Some(ref token) => {
+                self.data.write().unwrap().access_token = token.clone();
+                let resp = self.call(self.request("GET", "/account/whoami"), None)?;
+                (token.clone(), resp["user_id"].clone())
+            }
+            None => {
+                let body = json!({
+                    "type": "m.login.password",
+                    "identifier": { "type": "m.id.user", "user": self.config.user },
+                    "password": self.config.password,
+                });
+                let resp = self.call(self.request("POST", "/login"), Some(body))?;
+                let token = match resp["access_token"].as_str() {
+                    Some(token) => token.to_owned(),
+                    None => {
+                        return Err(SourceError::ConnectionError(
+                            self.id.clone(),
+                            "no access token in the login response".to_owned(),
+                        ))
+                    }
+                };
+                (token, resp["user_id"].clone())
+            }
+        };
+        let mut data = self.data.write().unwrap();
+        data.access_token = access_token;
+        data.user_id = user_id.as_str().unwrap_or("").to_owned();
+        Ok(())
+    }
+
+    /// Joins a room given by its ID or alias and returns the room ID
+    fn join(&self, room: &str) -> SourceResult<String> {
+        let path = format!("/join/{}", http::encode(room));
+        let resp = self.call(self.request("POST", &path), Some(json!({})))?;
+        let room_id = match resp["room_id"].as_str() {
+            Some(room_id) => room_id.to_owned(),
+            None => {
+                return Err(SourceError::InvalidChannel(
+                    self.id.clone(),
+                    self.channel(room),
+                ))
+            }
+        };
+        if room.starts_with('#') {
+            let mut data = self.data.write().unwrap();
+            let _ = data.aliases.insert(room_id.clone(), room.to_owned());
+        }
+        Ok(room_id)
+    }
+
+    fn channel(&self, room_id: &str) -> Channel {
+        self.data.read().unwrap().channel(room_id)
+    }
+
+    /// Creates a direct message room with the user and returns its ID
+    fn create_direct(&self, user: &str) -> SourceResult<String> {
+        let body = json!({
+            "is_direct": true,
+            "invite": [user],
+            "preset": "trusted_private_chat",
+        });
+        let resp = self.call(self.request("POST", "/createRoom"), Some(body))?;
+        let room_id = match resp["room_id"].as_str() {
+            Some(room_id) => room_id.to_owned(),
+            None => {
+                return Err(SourceError::InvalidChannel(
+                    self.id.clone(),
+                    Channel::User(user.to_owned()),
+                ))
+            }
+        };
+
+        // m.direct is the only place the room is marked as direct for the bot itself
+        let (user_id, direct) = {
+            let mut data = self.data.write().unwrap();
+            let _ = data.direct.insert(room_id.clone(), user.to_owned());
+            let mut direct: HashMap<String, Vec<String>> = HashMap::new();
+            for (room, user) in &data.direct {
+                direct.entry(user.clone()).or_default().push(room.clone());
+            }
+            (data.user_id.clone(), direct)
+        };
+        let path = format!("/user/{}/account_data/m.direct", http::encode(&user_id));
+        let _ = self.call(self.request("PUT", &path), Some(json!(direct)))?;
+        Ok(room_id)
+    }
+
+    fn send(&self, room_id: &str, content: JsonValue) -> SourceResult<()> {
+        let txn_id = format!(
+            "{}.{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.txn_counter.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = format!(
+            "/rooms/{}/send/m.room.message/{}",
+            http::encode(room_id),
+            txn_id
+        );
+        let _ = self.call(self.request("PUT", &path), Some(content))?;
+        Ok(())
+    }
+
+    fn sync(&self, since: Option<&str>) -> SourceResult<JsonValue> {
+        let request = match since {
+            Some(since) => self
+                .request("GET", "/sync")
+                .query("since", since)
+                .query("timeout", &self.config.sync_timeout.to_string()),
+            None => self.request("GET", "/sync").query("timeout", "0"),
+        };
+        self.call(request, None)
+    }
+
+    /// Processes a /sync response, updating the room data and returning the received messages
+    fn process_sync(&self, resp: &JsonValue) -> Vec<Event> {
+        let mut events = vec![];
+        let mut data = self.data.write().unwrap();
+        for event in resp["account_data"]["events"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            if event["type"] == "m.direct" {
+                for (user, rooms) in event["content"].as_object().into_iter().flatten() {
+                    for room in rooms.as_array().into_iter().flatten() {
+                        if let Some(room) = room.as_str() {
+                            let _ = data.direct.insert(room.to_owned(), user.clone());
+                        }
+                    }
+                }
+            }
+        }
+        for (room_id, room) in resp["rooms"]["join"].as_object().into_iter().flatten() {
+            let state = room["state"]["events"].as_array().into_iter().flatten();
+            let timeline = room["timeline"]["events"].as_array().into_iter().flatten();
+            for event in state.chain(timeline) {
+                match event["type"].as_str() {
+                    Some("m.room.canonical_alias") => {
+                        if let Some(alias) = event["content"]["alias"].as_str() {
+                            let _ = data.aliases.insert(room_id.clone(), alias.to_owned());
+                        }
+                    }
+                    Some("m.room.message") => {
+                        if let Some(msg) = event_to_message(&data, room_id, event) {
+                            events.push(Event::ReceivedMessage(msg));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        events
+    }
+
+    fn accept_invites(&self, resp: &JsonValue) {
+        for room_id in resp["rooms"]["invite"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|x| x.0)
+        {
+            let _ = self.join(room_id);
+        }
+    }
+}
+
+fn event_to_message(data: &MatrixData, room_id: &str, event: &JsonValue) -> Option<Message> {
+    let sender = event["sender"].as_str()?;
+    let content = &event["content"];
+    let body = content["body"].as_str().unwrap_or("").to_owned();
+    let content = match content["msgtype"].as_str()? {
+        "m.text" | "m.notice" => MessageContent::Text(body),
+        "m.emote" => MessageContent::Me(body),
+        "m.image" => MessageContent::Image,
+        _ => return None,
+    };
+    Some(Message {
+        author: sender.to_owned(),
+        channel: data.channel(room_id),
+        content,
+        is_own: sender == data.user_id,
+    })
+}
+
+/// A Matrix event source
+pub struct MatrixSource {
+    /// the source ID
+    id: SourceId,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// API client, shared with the sync thread
+    client: MatrixClient,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl MatrixSource {
+    /// Creates a MatrixSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Matrix source {:?}!",
+            source_id
+        ));
+        let config: MatrixConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Matrix source {:?}",
+            source_id
+        ));
+
+        Box::new(MatrixSource {
+            id: source_id.clone(),
+            sender,
+            client: MatrixClient::new(source_id, config),
+            state: SourceState::Disconnected,
+        })
+    }
+}
+
+impl EventSource for MatrixSource {
+    fn get_nick(&self) -> String {
+        self.client.data.read().unwrap().user_id.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        self.client.login()?;
+        for room in &self.client.config.rooms {
+            let _ = self.client.join(room)?;
+        }
+        // the initial sync only gathers the state - the history isn't delivered as events
+        let initial = self.client.sync(None)?;
+        let _ = self.client.process_sync(&initial);
+        // including the invites received while the bot was offline
+        if self.client.config.accept_invites {
+            self.client.accept_invites(&initial);
+        }
+        let mut since = initial["next_batch"].as_str().unwrap_or("").to_owned();
+
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let id = self.id.clone();
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            loop {
+                let resp = match client.sync(Some(&since)) {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        let _ = sender.send(SourceEvent {
+                            source: id.clone(),
+                            event: Event::Disconnected(format!("{:?}", e)),
+                        });
+                        return;
+                    }
+                };
+                for event in client.process_sync(&resp) {
+                    let _ = sender.send(SourceEvent {
+                        source: id.clone(),
+                        event,
+                    });
+                }
+                if client.config.accept_invites {
+                    client.accept_invites(&resp);
+                }
+                if let Some(next_batch) = resp["next_batch"].as_str() {
+                    since = next_batch.to_owned();
+                }
+            }
+        });
+
+        self.state = SourceState::Running(handle);
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        let _ = self.client.join(channel)?;
+        Ok(())
+    }
+
+    /// Sends a message to a room, or to a user via a direct message room
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let content = match msg {
+            MessageContent::Text(ref t) => json!({ "msgtype": "m.text", "body": t }),
+            MessageContent::Me(ref t) => json!({ "msgtype": "m.emote", "body": t }),
+            MessageContent::Embed(ref embed) => {
+                json!({ "msgtype": "m.notice", "body": embed.to_text() })
+            }
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let room_id = match dst {
+            Channel::Channel(ref alias) => {
+                let known = self.client.data.read().unwrap().room_by_alias(alias);
+                match known {
+                    Some(room_id) => room_id,
+                    None => self.client.join(alias)?,
+                }
+            }
+            Channel::User(ref user) => {
+                let known = self.client.data.read().unwrap().room_by_user(user);
+                match known {
+                    Some(room_id) => room_id,
+                    None => self.client.create_direct(user)?,
+                }
+            }
+            _ => return Err(SourceError::InvalidChannel(self.id.clone(), dst)),
+        };
+        self.client.send(&room_id, content)
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        // the thread of the previous connection ends after reporting the disconnection
+        let state = mem::replace(&mut self.state, SourceState::Disconnected);
+        if let SourceState::Running(handle) = state {
+            let _ = handle.join();
+        }
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{expect_connected, next_message, MockRequest, MockServer};
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn homeserver(req: &MockRequest) -> String {
+        let path = &req.url[API.len()..];
+        if path.starts_with("/login") {
+            json!({ "access_token": "token", "user_id": "@bot:localhost" }).to_string()
+        } else if path.starts_with("/join/") {
+            json!({ "room_id": "!room:localhost" }).to_string()
+        } else if path.starts_with("/createRoom") {
+            json!({ "room_id": "!new:localhost" }).to_string()
+        } else if path.starts_with("/sync") && !path.contains("since=") {
+            json!({
+                "next_batch": "s1",
+                "account_data": { "events": [{
+                    "type": "m.direct",
+                    "content": { "@alice:localhost": ["!dm:localhost"] },
+                }]},
+                "rooms": {
+                    "join": { "!room:localhost": { "state": { "events": [{
+                        "type": "m.room.canonical_alias",
+                        "content": { "alias": "#room:localhost" },
+                    }]}}},
+                    "invite": { "!invited:localhost": {} },
+                },
+            })
+            .to_string()
+        } else if path.contains("since=s1") {
+            let message = |sender: &str, msgtype: &str, body: &str| {
+                json!({
+                    "type": "m.room.message",
+                    "sender": sender,
+                    "content": { "msgtype": msgtype, "body": body },
+                })
+            };
+            json!({
+                "next_batch": "s2",
+                "rooms": { "join": {
+                    "!room:localhost": { "timeline": { "events": [
+                        message("@alice:localhost", "m.text", "hello"),
+                        message("@bot:localhost", "m.emote", "waves"),
+                    ]}},
+                    "!dm:localhost": { "timeline": { "events": [
+                        message("@alice:localhost", "m.text", "psst"),
+                    ]}},
+                }},
+            })
+            .to_string()
+        } else if path.starts_with("/sync") {
+            thread::sleep(Duration::from_millis(50));
+            json!({ "next_batch": "s2" }).to_string()
+        } else {
+            json!({ "event_id": "$event" }).to_string()
+        }
+    }
+
+    fn connect(server: &MockServer) -> (Box<dyn EventSource>, Receiver<SourceEvent>) {
+        let config = format!(
+            "homeserver = \"{}\"\nuser = \"bot\"\npassword = \"secret\"\n\
+             rooms = [\"#room:localhost\"]\naccept_invites = true",
+            server.url()
+        );
+        let (tx, rx) = channel();
+        let mut source = MatrixSource::new(
+            SourceId("matrix".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+        (source, rx)
+    }
+
+    #[test]
+    fn test_receive() {
+        let server = MockServer::start(homeserver);
+        let (source, rx) = connect(&server);
+        assert_eq!(source.get_nick(), "@bot:localhost");
+        let invite_joins = server.requests(&format!("{}/join/%21invited%3Alocalhost", API));
+        assert_eq!(invite_joins.len(), 1);
+
+        expect_connected(&rx);
+        // the order of the rooms in a sync response is unspecified
+        let mut msgs: Vec<_> = (0..3).map(|_| next_message(&rx)).collect();
+        msgs.sort_by_key(|msg| msg.content.display_with_nick(&msg.author));
+
+        assert_eq!(msgs[0].author, "@bot:localhost");
+        assert!(msgs[0].is_own);
+        match msgs[0].content {
+            MessageContent::Me(ref t) => assert_eq!(t, "waves"),
+            _ => panic!("unexpected content: {:?}", msgs[0].content),
+        }
+
+        assert_eq!(msgs[1].author, "@alice:localhost");
+        assert_eq!(
+            msgs[1].channel,
+            Channel::Channel("#room:localhost".to_owned())
+        );
+        assert!(!msgs[1].is_own);
+        match msgs[1].content {
+            MessageContent::Text(ref t) => assert_eq!(t, "hello"),
+            _ => panic!("unexpected content: {:?}", msgs[1].content),
+        }
+
+        assert_eq!(
+            msgs[2].channel,
+            Channel::User("@alice:localhost".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_send() {
+        let server = MockServer::start(homeserver);
+        let (mut source, _rx) = connect(&server);
+
+        source
+            .send(
+                Channel::Channel("#room:localhost".to_owned()),
+                MessageContent::Text("hi".to_owned()),
+            )
+            .unwrap();
+        source
+            .send(
+                Channel::User("@alice:localhost".to_owned()),
+                MessageContent::Me("nods".to_owned()),
+            )
+            .unwrap();
+        source
+            .send(
+                Channel::User("@carol:localhost".to_owned()),
+                MessageContent::Text("hello".to_owned()),
+            )
+            .unwrap();
+
+        let room_msgs = server.requests(&format!("{}/rooms/%21room%3Alocalhost/send/", API));
+        assert_eq!(room_msgs.len(), 1);
+        assert_eq!(room_msgs[0].method, "PUT");
+        let body: JsonValue = serde_json::from_str(&room_msgs[0].body).unwrap();
+        assert_eq!(body, json!({ "msgtype": "m.text", "body": "hi" }));
+
+        let dm_msgs = server.requests(&format!("{}/rooms/%21dm%3Alocalhost/send/", API));
+        assert_eq!(dm_msgs.len(), 1);
+        assert!(dm_msgs[0].body.contains("m.emote"));
+
+        assert_eq!(server.requests(&format!("{}/createRoom", API)).len(), 1);
+        let new_msgs = server.requests(&format!("{}/rooms/%21new%3Alocalhost/send/", API));
+        assert_eq!(new_msgs.len(), 1);
+    }
+}
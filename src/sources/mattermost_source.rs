@@ -0,0 +1,566 @@
+use crate::core::*;
+use crate::sources::http;
+use crate::sources::*;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toml::Value;
+use tungstenite::Message as WsMessage;
+
+/// The prefix of the REST API endpoints
+const API: &str = "/api/v4";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MattermostConfig {
+    /// The base URL of the server, eg. `https://chat.example.com`
+    url: String,
+    /// The URL of the WebSocket endpoint; defaults to the one of the server at `url`
+    websocket_url: Option<String>,
+    /// A personal access token of the bot's account
+    token: String,
+    /// The team whose channels can be referred to by their names alone - channels of other teams
+    /// are named `team/channel`
+    team: String,
+    /// Channels to join when connecting
+    #[serde(default)]
+    channels: Vec<String>,
+}
+
+/// A helper enum for MattermostSource
+enum SourceState {
+    Disconnected,
+    Running(JoinHandle<()>),
+}
+
+/// Data shared between the source and the WebSocket thread
+#[derive(Default)]
+struct MattermostData {
+    user_id: String,
+    username: String,
+    team_id: String,
+    /// Team names by team IDs
+    teams: HashMap<String, String>,
+    /// Channels by channel IDs
+    channels: HashMap<String, Channel>,
+    /// Usernames by user IDs
+    users: HashMap<String, String>,
+}
+
+/// A client of the REST API, shared by the source and the WebSocket thread
+#[derive(Clone)]
+struct MattermostClient {
+    id: SourceId,
+    config: MattermostConfig,
+    agent: ureq::Agent,
+    data: Arc<RwLock<MattermostData>>,
+}
+
+impl MattermostClient {
+    fn new(id: SourceId, config: MattermostConfig) -> Self {
+        MattermostClient {
+            id,
+            config,
+            agent: http::agent(Duration::from_secs(30)),
+            data: Default::default(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let url = format!("{}{}{}", self.config.url, API, path);
+        self.agent
+            .request(method, &url)
+            .set("Authorization", &format!("Bearer {}", self.config.token))
+    }
+
+    fn call(&self, request: ureq::Request, body: Option<JsonValue>) -> SourceResult<JsonValue> {
+        http::call(&self.id, request, body)
+    }
+
+    /// Returns a string field of an API response, failing if it's missing
+    fn field(&self, resp: &JsonValue, name: &str) -> SourceResult<String> {
+        resp[name].as_str().map(|s| s.to_owned()).ok_or_else(|| {
+            SourceError::ConnectionError(
+                self.id.clone(),
+                format!("missing \"{}\" in response: {}", name, resp),
+            )
+        })
+    }
+
+    fn login(&self) -> SourceResult<()> {
+        let me = self.call(self.request("GET", "/users/me"), None)?;
+        let path = format!("/teams/name/{}", http::encode(&self.config.team));
+        let team = self.call(self.request("GET", &path), None)?;
+        let team_id = self.field(&team, "id")?;
+        let mut data = self.data.write().unwrap();
+        data.user_id = self.field(&me, "id")?;
+        data.username = self.field(&me, "username")?;
+        let _ = data.teams.insert(team_id.clone(), self.config.team.clone());
+        data.team_id = team_id;
+        Ok(())
+    }
+
+    fn user_name(&self, user_id: &str) -> SourceResult<String> {
+        if let Some(name) = self.data.read().unwrap().users.get(user_id) {
+            return Ok(name.clone());
+        }
+        let path = format!("/users/{}", http::encode(user_id));
+        let user = self.call(self.request("GET", &path), None)?;
+        let name = self.field(&user, "username")?;
+        let mut data = self.data.write().unwrap();
+        let _ = data.users.insert(user_id.to_owned(), name.clone());
+        Ok(name)
+    }
+
+    fn team_name(&self, team_id: &str) -> SourceResult<String> {
+        if let Some(name) = self.data.read().unwrap().teams.get(team_id) {
+            return Ok(name.clone());
+        }
+        let path = format!("/teams/{}", http::encode(team_id));
+        let team = self.call(self.request("GET", &path), None)?;
+        let name = self.field(&team, "name")?;
+        let mut data = self.data.write().unwrap();
+        let _ = data.teams.insert(team_id.to_owned(), name.clone());
+        Ok(name)
+    }
+
+    /// Returns the `Channel` corresponding to a channel ID
+    fn channel(&self, channel_id: &str) -> SourceResult<Channel> {
+        if let Some(channel) = self.data.read().unwrap().channels.get(channel_id) {
+            return Ok(channel.clone());
+        }
+        let path = format!("/channels/{}", http::encode(channel_id));
+        let resp = self.call(self.request("GET", &path), None)?;
+        let name = self.field(&resp, "name")?;
+        let channel = if resp["type"] == "D" {
+            // direct channels are named after the IDs of both users
+            let own_id = self.data.read().unwrap().user_id.clone();
+            let other = name.split("__").find(|id| *id != own_id).unwrap_or(&own_id);
+            Channel::User(self.user_name(other)?)
+        } else if resp["type"] == "G" {
+            // group channels don't belong to any team, they are known by their members
+            let path = format!("/channels/{}/members", http::encode(channel_id));
+            let members = self.call(self.request("GET", &path), None)?;
+            let own_id = self.data.read().unwrap().user_id.clone();
+            let mut names = vec![];
+            for member in members.as_array().into_iter().flatten() {
+                match member["user_id"].as_str() {
+                    Some(user_id) if user_id != own_id => names.push(self.user_name(user_id)?),
+                    _ => (),
+                }
+            }
+            names.sort();
+            Channel::Group(names)
+        } else {
+            let team_id = self.field(&resp, "team_id")?;
+            if team_id == self.data.read().unwrap().team_id {
+                Channel::Channel(name)
+            } else {
+                Channel::Channel(format!("{}/{}", self.team_name(&team_id)?, name))
+            }
+        };
+        let mut data = self.data.write().unwrap();
+        let _ = data.channels.insert(channel_id.to_owned(), channel.clone());
+        Ok(channel)
+    }
+
+    /// Returns the ID of a channel, creating a direct channel if the user wasn't talked to yet
+    fn channel_id(&self, channel: &Channel) -> SourceResult<String> {
+        let known = self
+            .data
+            .read()
+            .unwrap()
+            .channels
+            .iter()
+            .find(|&(_, c)| c == channel)
+            .map(|(id, _)| id.clone());
+        if let Some(channel_id) = known {
+            return Ok(channel_id);
+        }
+        let resp = match *channel {
+            Channel::Channel(ref name) => {
+                let (team, name) = match name.find('/') {
+                    Some(pos) => (&name[..pos], &name[pos + 1..]),
+                    None => (&self.config.team as &str, name as &str),
+                };
+                let path = format!(
+                    "/teams/name/{}/channels/name/{}",
+                    http::encode(team),
+                    http::encode(name)
+                );
+                self.call(self.request("GET", &path), None)
+            }
+            Channel::User(ref name) => {
+                let path = format!("/users/username/{}", http::encode(name));
+                let user = self.call(self.request("GET", &path), None)?;
+                let user_id = self.field(&user, "id")?;
+                let own_id = self.data.read().unwrap().user_id.clone();
+                let request = self.request("POST", "/channels/direct");
+                self.call(request, Some(json!([own_id, user_id])))
+            }
+            _ => {
+                return Err(SourceError::InvalidChannel(
+                    self.id.clone(),
+                    channel.clone(),
+                ))
+            }
+        };
+        let channel_id = resp
+            .and_then(|resp| self.field(&resp, "id"))
+            .map_err(|_| SourceError::InvalidChannel(self.id.clone(), channel.clone()))?;
+        let mut data = self.data.write().unwrap();
+        let _ = data.channels.insert(channel_id.clone(), channel.clone());
+        Ok(channel_id)
+    }
+
+    fn join(&self, channel: &str) -> SourceResult<()> {
+        let channel_id = self.channel_id(&Channel::Channel(channel.to_owned()))?;
+        let path = format!("/channels/{}/members", http::encode(&channel_id));
+        let user_id = self.data.read().unwrap().user_id.clone();
+        let _ = self.call(
+            self.request("POST", &path),
+            Some(json!({ "user_id": user_id })),
+        )?;
+        Ok(())
+    }
+
+    fn post(&self, channel_id: &str, message: &str, props: JsonValue) -> SourceResult<()> {
+        let body = json!({ "channel_id": channel_id, "message": message, "props": props });
+        let _ = self.call(self.request("POST", "/posts"), Some(body))?;
+        Ok(())
+    }
+
+    fn websocket_url(&self) -> String {
+        match self.config.websocket_url {
+            Some(ref url) => url.clone(),
+            None => format!(
+                "{}{}/websocket",
+                self.config.url.replacen("http", "ws", 1),
+                API
+            ),
+        }
+    }
+
+    /// Translates a WebSocket event into an event of the source
+    fn process_event(&self, event: &JsonValue) -> Option<Event> {
+        let data = &event["data"];
+        match event["event"].as_str()? {
+            kind @ "posted" | kind @ "post_edited" => {
+                // posts are JSON-encoded within the event
+                let post: JsonValue = serde_json::from_str(data["post"].as_str()?).ok()?;
+                // system messages (joins, header changes...) have a type
+                if post["type"].as_str().is_some_and(|t| !t.is_empty()) {
+                    return None;
+                }
+                let user_id = post["user_id"].as_str()?;
+                let msg = Message {
+                    author: self.user_name(user_id).ok()?,
+                    channel: self.channel(post["channel_id"].as_str()?).ok()?,
+                    content: MessageContent::Text(post["message"].as_str()?.to_owned()),
+                    is_own: user_id == self.data.read().unwrap().user_id,
+                };
+                Some(if kind == "posted" {
+                    Event::ReceivedMessage(msg)
+                } else {
+                    Event::EditedMessage(msg)
+                })
+            }
+            kind @ "reaction_added" | kind @ "reaction_removed" => {
+                let reaction: JsonValue = serde_json::from_str(data["reaction"].as_str()?).ok()?;
+                Some(Event::Reaction(Reaction {
+                    author: self.user_name(reaction["user_id"].as_str()?).ok()?,
+                    channel: self
+                        .channel(event["broadcast"]["channel_id"].as_str()?)
+                        .ok()?,
+                    emoji: reaction["emoji_name"].as_str()?.to_owned(),
+                    removed: kind == "reaction_removed",
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A Mattermost event source
+pub struct MattermostSource {
+    /// the source ID
+    id: SourceId,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// REST API client, shared with the WebSocket thread
+    client: MattermostClient,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl MattermostSource {
+    /// Creates a MattermostSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Mattermost source {:?}!",
+            source_id
+        ));
+        let config: MattermostConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Mattermost source {:?}",
+            source_id
+        ));
+
+        Box::new(MattermostSource {
+            id: source_id.clone(),
+            sender,
+            client: MattermostClient::new(source_id, config),
+            state: SourceState::Disconnected,
+        })
+    }
+}
+
+impl EventSource for MattermostSource {
+    fn get_nick(&self) -> String {
+        self.client.data.read().unwrap().username.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        self.client.login()?;
+        for channel in &self.client.config.channels {
+            self.client.join(channel)?;
+        }
+
+        let (mut socket, _) = tungstenite::connect(self.client.websocket_url())
+            .map_err(|e| SourceError::ConnectionError(self.id.clone(), e.to_string()))?;
+        let auth = json!({
+            "seq": 1,
+            "action": "authentication_challenge",
+            "data": { "token": self.client.config.token },
+        });
+        socket
+            .send(WsMessage::Text(auth.to_string()))
+            .map_err(|e| SourceError::ConnectionError(self.id.clone(), e.to_string()))?;
+
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let id = self.id.clone();
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            let reason = loop {
+                let text = match socket.read() {
+                    Ok(WsMessage::Text(text)) => text,
+                    Ok(WsMessage::Close(_)) => break "connection closed".to_owned(),
+                    Ok(_) => continue,
+                    Err(e) => break e.to_string(),
+                };
+                // anything that isn't an event is ignored
+                let event: JsonValue = match serde_json::from_str(&text) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                if let Some(event) = client.process_event(&event) {
+                    let _ = sender.send(SourceEvent {
+                        source: id.clone(),
+                        event,
+                    });
+                }
+            };
+            let _ = sender.send(SourceEvent {
+                source: id,
+                event: Event::Disconnected(reason),
+            });
+        });
+
+        self.state = SourceState::Running(handle);
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        self.client.join(channel)
+    }
+
+    /// Sends a message to a channel, or to a user via a direct channel
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let (message, props) = match msg {
+            MessageContent::Text(ref t) => (t.clone(), json!({})),
+            MessageContent::Me(ref t) => (format!("*{}*", t), json!({})),
+            MessageContent::Embed(ref embed) => (
+                String::new(),
+                json!({ "attachments": [embed.to_attachment()] }),
+            ),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let channel_id = self.client.channel_id(&dst)?;
+        self.client.post(&channel_id, &message, props)
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        // the thread of the previous connection ends after reporting the disconnection
+        let state = mem::replace(&mut self.state, SourceState::Disconnected);
+        if let SourceState::Running(handle) = state {
+            let _ = handle.join();
+        }
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{
+        accept_one, expect_connected, next_event, MockRequest, MockServer,
+    };
+    use std::sync::mpsc::channel;
+
+    fn server_api(req: &MockRequest) -> String {
+        let path = &req.url[API.len()..];
+        let resp = match path {
+            "/users/me" => json!({ "id": "bot", "username": "bot" }),
+            "/teams/name/team" => json!({ "id": "t1", "name": "team" }),
+            "/teams/t2" => json!({ "id": "t2", "name": "other" }),
+            "/users/u2" => json!({ "id": "u2", "username": "alice" }),
+            "/users/u3" => json!({ "id": "u3", "username": "carol" }),
+            "/users/username/carol" => json!({ "id": "u3", "username": "carol" }),
+            "/channels/c1" => {
+                json!({ "id": "c1", "type": "O", "name": "town-square", "team_id": "t1" })
+            }
+            "/channels/c2" => json!({ "id": "c2", "type": "O", "name": "dev", "team_id": "t2" }),
+            "/channels/d1" => json!({ "id": "d1", "type": "D", "name": "bot__u2", "team_id": "" }),
+            "/channels/g1" => json!({ "id": "g1", "type": "G", "name": "abc", "team_id": "" }),
+            "/channels/g1/members" => {
+                json!([{ "user_id": "u3" }, { "user_id": "bot" }, { "user_id": "u2" }])
+            }
+            "/channels/direct" => json!({ "id": "d2", "type": "D", "name": "bot__u3" }),
+            _ => json!({ "id": "p1" }),
+        };
+        resp.to_string()
+    }
+
+    fn post_event(kind: &str, channel_id: &str, message: &str) -> String {
+        let post =
+            json!({ "user_id": "u2", "channel_id": channel_id, "message": message, "type": "" });
+        json!({ "event": kind, "data": { "post": post.to_string() } }).to_string()
+    }
+
+    /// Starts a WebSocket server which checks the authentication and sends some events
+    fn websocket_server() -> String {
+        let address = accept_one(|stream| {
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let auth = socket.read().unwrap().into_text().unwrap();
+            assert!(auth.contains("\"token\":\"secret\""));
+            let reaction = json!({ "user_id": "u2", "post_id": "p1", "emoji_name": "thumbsup" });
+            let events = vec![
+                json!({ "event": "hello", "data": {} }).to_string(),
+                post_event("posted", "c1", "hi"),
+                post_event("posted", "d1", "psst"),
+                post_event("posted", "g1", "all of us"),
+                post_event("posted", "c2", "elsewhere"),
+                post_event("post_edited", "c1", "hi all"),
+                json!({
+                    "event": "reaction_added",
+                    "data": { "reaction": reaction.to_string() },
+                    "broadcast": { "channel_id": "c1" },
+                })
+                .to_string(),
+            ];
+            for event in events {
+                socket.send(WsMessage::Text(event)).unwrap();
+            }
+            while socket.read().is_ok() {}
+        });
+        format!("ws://{}/", address)
+    }
+
+    fn assert_message(event: Event, channel: Channel, text: &str) -> Message {
+        let msg = match event {
+            Event::ReceivedMessage(msg) | Event::EditedMessage(msg) => msg,
+            event => panic!("unexpected event: {:?}", event),
+        };
+        assert_eq!(msg.author, "alice");
+        assert_eq!(msg.channel, channel);
+        match msg.content {
+            MessageContent::Text(ref t) => assert_eq!(t, text),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+        msg
+    }
+
+    #[test]
+    fn test_events_and_send() {
+        let server = MockServer::start(server_api);
+        let config = format!(
+            "url = \"{}\"\nwebsocket_url = \"{}\"\ntoken = \"secret\"\nteam = \"team\"",
+            server.url(),
+            websocket_server()
+        );
+        let (tx, rx) = channel();
+        let mut source = MattermostSource::new(
+            SourceId("mattermost".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+        assert_eq!(source.get_nick(), "bot");
+
+        expect_connected(&rx);
+        let town_square = Channel::Channel("town-square".to_owned());
+        let _ = assert_message(next_event(&rx), town_square.clone(), "hi");
+        let _ = assert_message(next_event(&rx), Channel::User("alice".to_owned()), "psst");
+        let group = Channel::Group(vec!["alice".to_owned(), "carol".to_owned()]);
+        let _ = assert_message(next_event(&rx), group.clone(), "all of us");
+        let _ = assert_message(
+            next_event(&rx),
+            Channel::Channel("other/dev".to_owned()),
+            "elsewhere",
+        );
+        match next_event(&rx) {
+            event @ Event::EditedMessage(_) => {
+                let _ = assert_message(event, town_square.clone(), "hi all");
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        match next_event(&rx) {
+            Event::Reaction(reaction) => {
+                assert_eq!(reaction.author, "alice");
+                assert_eq!(reaction.channel, town_square);
+                assert_eq!(reaction.emoji, "thumbsup");
+                assert!(!reaction.removed);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        source
+            .send(town_square, MessageContent::Text("hello".to_owned()))
+            .unwrap();
+        source
+            .send(
+                Channel::User("carol".to_owned()),
+                MessageContent::Me("waves".to_owned()),
+            )
+            .unwrap();
+        source
+            .send(group, MessageContent::Text("hi both".to_owned()))
+            .unwrap();
+        let direct = server.requests(&format!("{}/channels/direct", API));
+        assert_eq!(direct[0].body, "[\"bot\",\"u3\"]");
+        let posts: Vec<JsonValue> = server
+            .requests(&format!("{}/posts", API))
+            .iter()
+            .map(|req| serde_json::from_str(&req.body).unwrap())
+            .collect();
+        assert_eq!(posts.len(), 3);
+        assert_eq!(posts[0]["channel_id"], "c1");
+        assert_eq!(posts[0]["message"], "hello");
+        assert_eq!(posts[1]["channel_id"], "d2");
+        assert_eq!(posts[1]["message"], "*waves*");
+        assert_eq!(posts[2]["channel_id"], "g1");
+    }
+}
@@ -0,0 +1,129 @@
+//! Helpers for the tests of the sources: a minimal local HTTP server standing in for the
+//! services' APIs, and the scaffolding around it
+// not every combination of features uses every helper
+#![allow(dead_code)]
+
+use crate::core::{Event, Message, SourceEvent};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Response, Server};
+
+/// How long the tests wait for the events of a source
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for the next event of the source
+pub fn next_event(rx: &Receiver<SourceEvent>) -> Event {
+    rx.recv_timeout(EVENT_TIMEOUT).unwrap().event
+}
+
+/// Waits for the next event, failing unless it's a received message
+pub fn next_message(rx: &Receiver<SourceEvent>) -> Message {
+    match next_event(rx) {
+        Event::ReceivedMessage(msg) => msg,
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+/// Waits for the next event, failing unless it's `Connected`
+pub fn expect_connected(rx: &Receiver<SourceEvent>) {
+    match next_event(rx) {
+        Event::Connected => (),
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+/// Returns a local port nothing listens on
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Accepts a single TCP connection in a background thread and hands it to `handler` - returns
+/// the address listened on
+pub fn accept_one<F>(handler: F) -> SocketAddr
+where
+    F: FnOnce(TcpStream) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let _ = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        handler(stream)
+    });
+    address
+}
+
+/// A request received by the mock server
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    pub url: String,
+    pub body: String,
+}
+
+pub struct MockServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+    /// Starts the server in a background thread; `handler` returns the JSON response for every
+    /// request
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> String + Send + 'static,
+    {
+        Self::start_with_status(move |req| (200, handler(req)))
+    }
+
+    /// Like `start`, but `handler` also returns the status code of the response
+    pub fn start_with_status<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> (u16, String) + Send + 'static,
+    {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let thread_requests = requests.clone();
+        let _ = thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                let _ = request.as_reader().read_to_string(&mut body);
+                let mock_request = MockRequest {
+                    method: request.method().to_string(),
+                    url: request.url().to_owned(),
+                    body,
+                };
+                let (status, response) = handler(&mock_request);
+                thread_requests.lock().unwrap().push(mock_request);
+                let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+                let response = Response::from_string(response)
+                    .with_status_code(status)
+                    .with_header(header);
+                let _ = request.respond(response);
+            }
+        });
+        MockServer { port, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// Returns the requests received so far whose URL starts with `prefix`
+    pub fn requests(&self, prefix: &str) -> Vec<MockRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|req| req.url.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+}
@@ -1,34 +1,110 @@
-use crate::core::{Channel, EventSourceBuilder, MessageContent};
+use crate::core::{Channel, Command, CommandDef, EventSourceBuilder, Identity, MessageContent};
 use std::collections::HashMap;
 
 #[cfg(feature = "discord")]
 pub mod discord_source;
+#[cfg(feature = "email")]
+pub mod email_source;
 mod error;
+#[cfg(any(
+    feature = "discord",
+    feature = "matrix",
+    feature = "mattermost",
+    feature = "telegram",
+    feature = "webhook-sink",
+    feature = "zulip"
+))]
+mod http;
 #[cfg(feature = "irc")]
 pub mod irc_source;
+pub mod loopback;
+#[cfg(feature = "matrix")]
+pub mod matrix_source;
+#[cfg(feature = "mattermost")]
+pub mod mattermost_source;
+#[cfg(test)]
+mod mock_server;
+#[cfg(feature = "plugin")]
+pub mod plugin_source;
 #[cfg(feature = "slack")]
 pub mod slack_source;
+#[cfg(feature = "socket")]
+pub mod socket_source;
 pub mod stdin;
+#[cfg(feature = "telegram")]
+pub mod telegram_source;
+#[cfg(feature = "webhook-sink")]
+pub mod webhook_sink;
+#[cfg(feature = "webhook")]
+pub mod webhook_source;
+#[cfg(any(feature = "plugin", feature = "socket"))]
+mod wire;
+#[cfg(feature = "xmpp")]
+pub mod xmpp_source;
+#[cfg(feature = "zulip")]
+pub mod zulip_source;
 
 #[cfg(feature = "discord")]
 pub use self::discord_source::DiscordSource;
+#[cfg(feature = "email")]
+pub use self::email_source::EmailSource;
 pub use self::error::SourceError;
 #[cfg(feature = "irc")]
 pub use self::irc_source::IrcSource;
+pub use self::loopback::{LoopbackAction, LoopbackHandle, LoopbackSource};
+#[cfg(feature = "matrix")]
+pub use self::matrix_source::MatrixSource;
+#[cfg(feature = "mattermost")]
+pub use self::mattermost_source::MattermostSource;
+#[cfg(feature = "plugin")]
+pub use self::plugin_source::PluginSource;
 #[cfg(feature = "slack")]
 pub use self::slack_source::SlackSource;
+#[cfg(feature = "socket")]
+pub use self::socket_source::SocketSource;
 pub use self::stdin::StdinSource;
+#[cfg(feature = "telegram")]
+pub use self::telegram_source::TelegramSource;
+#[cfg(feature = "webhook-sink")]
+pub use self::webhook_sink::WebhookSink;
+#[cfg(feature = "webhook")]
+pub use self::webhook_source::WebhookSource;
+#[cfg(feature = "xmpp")]
+pub use self::xmpp_source::XmppSource;
+#[cfg(feature = "zulip")]
+pub use self::zulip_source::ZulipSource;
 
 lazy_static! {
     pub static ref BUILDERS: HashMap<String, EventSourceBuilder> = {
         let mut m = HashMap::<String, EventSourceBuilder>::new();
         #[cfg(feature = "discord")]
         m.insert("Discord".to_owned(), DiscordSource::new);
+        #[cfg(feature = "email")]
+        m.insert("Email".to_owned(), EmailSource::new);
         #[cfg(feature = "irc")]
         m.insert("Irc".to_owned(), IrcSource::new);
+        m.insert("Loopback".to_owned(), LoopbackSource::new);
+        #[cfg(feature = "mattermost")]
+        m.insert("Mattermost".to_owned(), MattermostSource::new);
+        #[cfg(feature = "matrix")]
+        m.insert("Matrix".to_owned(), MatrixSource::new);
+        #[cfg(feature = "plugin")]
+        m.insert("Plugin".to_owned(), PluginSource::new);
         #[cfg(feature = "slack")]
         m.insert("Slack".to_owned(), SlackSource::new);
+        #[cfg(feature = "socket")]
+        m.insert("Socket".to_owned(), SocketSource::new);
         m.insert("stdin".to_owned(), StdinSource::new);
+        #[cfg(feature = "telegram")]
+        m.insert("Telegram".to_owned(), TelegramSource::new);
+        #[cfg(feature = "webhook")]
+        m.insert("Webhook".to_owned(), WebhookSource::new);
+        #[cfg(feature = "webhook-sink")]
+        m.insert("WebhookSink".to_owned(), WebhookSink::new);
+        #[cfg(feature = "xmpp")]
+        m.insert("Xmpp".to_owned(), XmppSource::new);
+        #[cfg(feature = "zulip")]
+        m.insert("Zulip".to_owned(), ZulipSource::new);
         m
     };
 }
@@ -48,6 +124,36 @@ pub trait EventSource {
     fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()>;
     /// Reconnects to the source
     fn reconnect(&mut self) -> SourceResult<()>;
+    /// Whether the source delivers any events - send-only sources return `false`
+    fn can_receive(&self) -> bool {
+        true
+    }
+    /// Sends a message under the given name and avatar, if the source allows overriding them
+    fn send_as(
+        &mut self,
+        dst: Channel,
+        msg: MessageContent,
+        _identity: &Identity,
+    ) -> SourceResult<()> {
+        self.send(dst, msg)
+    }
+    /// Registers the commands with the source, if it supports native commands
+    fn register_commands(&mut self, _commands: &[CommandDef]) -> SourceResult<()> {
+        Ok(())
+    }
+    /// Responds to a command invocation; ephemeral responses are only visible to the invoker
+    fn respond(
+        &mut self,
+        command: &Command,
+        msg: MessageContent,
+        ephemeral: bool,
+    ) -> SourceResult<()> {
+        if ephemeral {
+            self.send(Channel::User(command.author.clone()), msg)
+        } else {
+            self.send(command.channel.clone(), msg)
+        }
+    }
 }
 
 #[cfg(test)]
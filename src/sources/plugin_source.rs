@@ -0,0 +1,557 @@
+//! A source running in a child process, talking JSON-RPC 2.0 over its stdin and stdout
+//!
+//! Every line is a single JSON-RPC message. The core sends the requests `connect` (with the
+//! `config` table as `{"config": ...}`), `join` (`{"channel": ...}`), `send`
+//! (`{"channel": ..., "content": ...}`, as described in the `wire` module), `reconnect` and
+//! `get_nick`, which should be answered with the nick as the result. `get_nick` is only sent
+//! once, after `connect`. The core doesn't wait for the responses to `join`, `send` and
+//! `reconnect` - errors returned for them are reported as `Other` events.
+//!
+//! The plugin sends the notifications `connected`, `disconnected` (`{"reason": ...}`), `nick`
+//! (`{"nick": ...}`, when the nick changes) and `event`, with one of the events described in
+//! the `wire` module as the params. Lines which aren't JSON are ignored, so the plugin can log
+//! to its stdout, although stderr is the better place for that.
+
+use crate::core::*;
+use crate::sources::wire::{self, WireEvent};
+use crate::sources::*;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use toml::Value;
+
+fn default_nick() -> String {
+    "plugin".to_owned()
+}
+
+fn default_restart_delay() -> u64 {
+    1000
+}
+
+fn default_max_restart_delay() -> u64 {
+    300
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PluginConfig {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// The nick used when the plugin doesn't respond to `get_nick`
+    #[serde(default = "default_nick")]
+    nick: String,
+    /// The delay before restarting a crashed plugin in milliseconds, doubled with every
+    /// consecutive crash
+    #[serde(default = "default_restart_delay")]
+    restart_delay: u64,
+    /// The limit of the restart delay, in seconds
+    #[serde(default = "default_max_restart_delay")]
+    max_restart_delay: u64,
+    /// How long to wait for the responses during the handshake, in seconds
+    #[serde(default = "default_request_timeout")]
+    request_timeout: u64,
+    /// The configuration passed to the plugin with `connect`
+    config: Option<Value>,
+}
+
+type Response = Result<JsonValue, String>;
+
+/// A request waiting for the response
+struct PendingRequest {
+    method: String,
+    /// Where to pass the response - without it, only errors are reported, as events
+    waiter: Option<Sender<Response>>,
+}
+
+struct PluginData {
+    /// The running process, along with the lines to write to its stdin - `None` while the
+    /// plugin is being restarted
+    process: Option<(Child, Sender<String>)>,
+    /// Requests waiting for the responses, by request IDs
+    pending: HashMap<u64, PendingRequest>,
+    next_id: u64,
+    /// The channels joined so far, joined again after a restart
+    channels: Vec<String>,
+    /// The nick last reported by the plugin
+    nick: Option<String>,
+}
+
+#[derive(Clone)]
+struct PluginClient {
+    id: SourceId,
+    config: PluginConfig,
+    data: Arc<Mutex<PluginData>>,
+}
+
+impl PluginClient {
+    fn new(id: SourceId, config: PluginConfig) -> Self {
+        PluginClient {
+            id,
+            config,
+            data: Arc::new(Mutex::new(PluginData {
+                process: None,
+                pending: HashMap::new(),
+                next_id: 0,
+                channels: vec![],
+                nick: None,
+            })),
+        }
+    }
+
+    fn spawn(&self) -> SourceResult<ChildStdout> {
+        let mut child = Command::new(&self.config.command)
+            .args(&self.config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| SourceError::ConnectionError(self.id.clone(), e.to_string()))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        // written in a separate thread, so that a plugin which stops reading can't block the core
+        let (line_tx, line_rx) = mpsc::channel::<String>();
+        let _ = thread::spawn(move || {
+            for line in line_rx {
+                if writeln!(stdin, "{}", line)
+                    .and_then(|_| stdin.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        self.data.lock().unwrap().process = Some((child, line_tx));
+        Ok(stdout)
+    }
+
+    /// Kills the process, if it's running
+    fn kill(&self) {
+        if let Some((ref mut child, _)) = self.data.lock().unwrap().process {
+            let _ = child.kill();
+        }
+    }
+
+    /// Sends a request to the plugin, whose response goes to `waiter` - returns the request ID
+    fn start_request(
+        &self,
+        method: &str,
+        params: JsonValue,
+        waiter: Option<Sender<Response>>,
+    ) -> SourceResult<u64> {
+        let mut data = self.data.lock().unwrap();
+        data.next_id += 1;
+        let request_id = data.next_id;
+        let line = json!({
+            "jsonrpc": "2.0",
+            "id": request_id,
+            "method": method,
+            "params": params,
+        });
+        let sent = match data.process {
+            Some((_, ref lines)) => lines.send(line.to_string()).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let pending = PendingRequest {
+            method: method.to_owned(),
+            waiter,
+        };
+        let _ = data.pending.insert(request_id, pending);
+        Ok(request_id)
+    }
+
+    /// Calls a method of the plugin without waiting for the result
+    fn post(&self, method: &str, params: JsonValue) -> SourceResult<()> {
+        self.start_request(method, params, None).map(|_| ())
+    }
+
+    /// Calls a method of the plugin and waits for the result
+    fn request(&self, method: &str, params: JsonValue) -> SourceResult<JsonValue> {
+        let (tx, rx) = mpsc::channel();
+        let request_id = self.start_request(method, params, Some(tx))?;
+        match rx.recv_timeout(Duration::from_secs(self.config.request_timeout)) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(message)) => Err(SourceError::ConnectionError(self.id.clone(), message)),
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = self.data.lock().unwrap().pending.remove(&request_id);
+                Err(SourceError::ConnectionError(
+                    self.id.clone(),
+                    format!("{} timed out", method),
+                ))
+            }
+            // the process exited, dropping the pending requests
+            Err(RecvTimeoutError::Disconnected) => Err(SourceError::Disconnected(self.id.clone())),
+        }
+    }
+
+    /// Connects the freshly started plugin and joins the channels joined before
+    fn handshake(&self) -> SourceResult<()> {
+        let config = serde_json::to_value(&self.config.config)
+            .map_err(|e| SourceError::ConnectionError(self.id.clone(), e.to_string()))?;
+        let _ = self.request("connect", json!({ "config": config }))?;
+        // the plugin doesn't have to know its nick
+        if let Ok(JsonValue::String(nick)) = self.request("get_nick", json!({})) {
+            self.data.lock().unwrap().nick = Some(nick);
+        }
+        let channels = self.data.lock().unwrap().channels.clone();
+        for channel in channels {
+            let _ = self.request("join", json!({ "channel": channel }))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the plugin's output until it exits
+    fn read_output(&self, stdout: ChildStdout, sender: &Sender<SourceEvent>) {
+        for line in BufReader::new(stdout).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let msg: JsonValue = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            if let Some(method) = msg["method"].as_str() {
+                let event = match method {
+                    "connected" => Event::Connected,
+                    "disconnected" => Event::Disconnected(
+                        msg["params"]["reason"].as_str().unwrap_or("").to_owned(),
+                    ),
+                    "nick" => {
+                        if let Some(nick) = msg["params"]["nick"].as_str() {
+                            self.data.lock().unwrap().nick = Some(nick.to_owned());
+                        }
+                        continue;
+                    }
+                    "event" => match serde_json::from_value::<WireEvent>(msg["params"].clone()) {
+                        Ok(event) => event.into_event(),
+                        Err(_) => continue,
+                    },
+                    _ => continue,
+                };
+                let _ = sender.send(SourceEvent {
+                    source: self.id.clone(),
+                    event,
+                });
+            } else if let Some(request_id) = msg["id"].as_u64() {
+                let response = match msg.get("error") {
+                    Some(error) => Err(error["message"]
+                        .as_str()
+                        .map_or_else(|| error.to_string(), |message| message.to_owned())),
+                    None => Ok(msg["result"].clone()),
+                };
+                let pending = self.data.lock().unwrap().pending.remove(&request_id);
+                match (pending, response) {
+                    (
+                        Some(PendingRequest {
+                            waiter: Some(tx), ..
+                        }),
+                        response,
+                    ) => {
+                        let _ = tx.send(response);
+                    }
+                    (Some(PendingRequest { method, .. }), Err(message)) => {
+                        let _ = sender.send(SourceEvent {
+                            source: self.id.clone(),
+                            event: Event::Other(format!("{} failed: {}", method, message)),
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Runs the plugin, restarting it whenever it exits, until a stop is requested
+    fn supervise(&self, sender: Sender<SourceEvent>, stop_rx: mpsc::Receiver<()>) {
+        let mut delay = Duration::from_millis(self.config.restart_delay);
+        let max_delay = Duration::from_secs(self.config.max_restart_delay);
+        let stop_requested = || !matches!(stop_rx.try_recv(), Err(mpsc::TryRecvError::Empty));
+        loop {
+            let started = Instant::now();
+            let reason = match self.spawn() {
+                Ok(stdout) => {
+                    // the stop could have come before the process was there to kill
+                    if stop_requested() {
+                        self.kill();
+                        return;
+                    }
+                    let client = self.clone();
+                    let handshake = thread::spawn(move || {
+                        if client.handshake().is_err() {
+                            client.kill();
+                        }
+                    });
+                    self.read_output(stdout, &sender);
+                    // the pending requests won't be answered anymore, and the new ones fail
+                    // right away
+                    let process = {
+                        let mut data = self.data.lock().unwrap();
+                        data.pending.clear();
+                        data.process.take()
+                    };
+                    let _ = handshake.join();
+
+                    match process {
+                        Some((mut child, _)) => match child.wait() {
+                            Ok(status) => format!("plugin exited with {}", status),
+                            Err(e) => format!("plugin exited: {}", e),
+                        },
+                        None => "plugin exited".to_owned(),
+                    }
+                }
+                Err(e) => format!("{:?}", e),
+            };
+            if stop_requested() {
+                return;
+            }
+            let _ = sender.send(SourceEvent {
+                source: self.id.clone(),
+                event: Event::Disconnected(reason),
+            });
+
+            // a plugin that ran for a while isn't crashing in a loop
+            if started.elapsed() > max_delay {
+                delay = Duration::from_millis(self.config.restart_delay);
+            }
+            if stop_rx.recv_timeout(delay) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            delay = (delay * 2).min(max_delay);
+        }
+    }
+}
+
+/// A helper enum for PluginSource
+enum SourceState {
+    Disconnected,
+    Running(Sender<()>, JoinHandle<()>),
+}
+
+/// An event source delegating everything to a supervised child process
+pub struct PluginSource {
+    /// the source ID
+    id: SourceId,
+    /// Plugin client
+    client: PluginClient,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl PluginSource {
+    /// Creates a PluginSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Plugin source {:?}!",
+            source_id
+        ));
+        let config: PluginConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Plugin source {:?}",
+            source_id
+        ));
+
+        Box::new(PluginSource {
+            id: source_id.clone(),
+            client: PluginClient::new(source_id, config),
+            sender,
+            state: SourceState::Disconnected,
+        })
+    }
+
+    fn stop(&mut self) {
+        if let SourceState::Running(stop_tx, handle) =
+            std::mem::replace(&mut self.state, SourceState::Disconnected)
+        {
+            let _ = stop_tx.send(());
+            self.client.kill();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl EventSource for PluginSource {
+    /// Returns the nick last reported by the plugin, or the configured one
+    fn get_nick(&self) -> String {
+        self.client
+            .data
+            .lock()
+            .unwrap()
+            .nick
+            .clone()
+            .unwrap_or_else(|| self.client.config.nick.clone())
+    }
+
+    /// Starts the plugin - failures to start it don't fail the call, they are reported as
+    /// disconnections and retried instead
+    fn connect(&mut self) -> SourceResult<()> {
+        if let SourceState::Running(..) = self.state {
+            return Ok(());
+        }
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let handle = thread::spawn(move || client.supervise(sender, stop_rx));
+        self.state = SourceState::Running(stop_tx, handle);
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        let channel = channel.to_owned();
+        {
+            let mut data = self.client.data.lock().unwrap();
+            if !data.channels.contains(&channel) {
+                data.channels.push(channel.clone());
+            }
+        }
+        match self.client.post("join", json!({ "channel": channel })) {
+            // the plugin will join it after restarting
+            Err(SourceError::Disconnected(_)) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let content = match wire::content_to_wire(msg.clone()) {
+            Some(content) => content,
+            None => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let params = json!({
+            "channel": wire::channel_to_wire(dst),
+            "content": content,
+        });
+        self.client.post("send", params)
+    }
+
+    /// Asks the plugin to reconnect - a plugin that has exited is restarted by the supervisor
+    /// anyway
+    fn reconnect(&mut self) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return self.connect();
+        }
+        match self.client.post("reconnect", json!({})) {
+            Err(SourceError::Disconnected(_)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Drop for PluginSource {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{expect_connected, next_event, next_message};
+    use std::sync::mpsc::channel;
+
+    /// Answers every request with `null`, except for `get_nick`, echoes every sent message back,
+    /// changes the nick on a message saying "rename", fails one saying "fail" and exits on one
+    /// saying "crash"
+    const SCRIPT: &str = r#"
+while read -r line; do
+  id=$(echo "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  case "$line" in
+    *crash*) exit 1 ;;
+    *fail*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"error\":{\"code\":1,\"message\":\"nope\"}}" ;;
+    *get_nick*) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":\"bridge\"}" ;;
+    *) echo "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}" ;;
+  esac
+  case "$line" in
+    *'"connect"'*) echo '{"jsonrpc":"2.0","method":"connected"}' ;;
+    *'"rename"'*) echo '{"jsonrpc":"2.0","method":"nick","params":{"nick":"bridge2"}}' ;;
+    *'"send"'*) echo '{"jsonrpc":"2.0","method":"event","params":{"type":"message","author":"echo","channel":{"channel":"ops"},"content":{"text":"echo"}}}' ;;
+  esac
+done
+"#;
+
+    /// The nick is fetched and updated in the background
+    fn wait_for_nick(source: &dyn EventSource, nick: &str) {
+        for _ in 0..50 {
+            if source.get_nick() == nick {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(source.get_nick(), nick);
+    }
+
+    #[test]
+    fn test_restart() {
+        let (tx, rx) = channel();
+        let mut config = toml::value::Table::new();
+        let _ = config.insert("command".to_owned(), Value::String("sh".to_owned()));
+        let _ = config.insert(
+            "args".to_owned(),
+            Value::Array(vec![
+                Value::String("-c".to_owned()),
+                Value::String(SCRIPT.to_owned()),
+            ]),
+        );
+        let _ = config.insert("restart_delay".to_owned(), Value::Integer(10));
+        let mut source = PluginSource::new(
+            SourceId("plugin".to_owned()),
+            tx,
+            Some(Value::Table(config)),
+        );
+        source.connect().unwrap();
+        expect_connected(&rx);
+        wait_for_nick(&*source, "bridge");
+        source.join("ops").unwrap();
+
+        source
+            .send(
+                Channel::Channel("ops".to_owned()),
+                MessageContent::Text("hi".to_owned()),
+            )
+            .unwrap();
+        assert_eq!(next_message(&rx).author, "echo");
+        source
+            .send(Channel::None, MessageContent::Text("rename".to_owned()))
+            .unwrap();
+        wait_for_nick(&*source, "bridge2");
+        source
+            .send(Channel::None, MessageContent::Text("fail".to_owned()))
+            .unwrap();
+        match next_event(&rx) {
+            Event::Other(text) => assert_eq!(text, "send failed: nope"),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(next_message(&rx).author, "echo");
+
+        // the send doesn't wait for the plugin, which exits without answering
+        source
+            .send(Channel::None, MessageContent::Text("crash".to_owned()))
+            .unwrap();
+        match next_event(&rx) {
+            Event::Disconnected(_) => (),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        expect_connected(&rx);
+        source
+            .send(Channel::None, MessageContent::Text("again".to_owned()))
+            .unwrap();
+    }
+}
@@ -70,6 +70,25 @@ impl SlackSource {
             .and_then(|user| user.id.as_ref().map(|s| s as &str))
             .unwrap_or("[no id]")
     }
+
+    /// Posts an embed as a message attachment - this is not supported by the RTM API, so it
+    /// goes through the Web API
+    fn post_embed(&self, channel_id: &str, embed: &Embed) -> SourceResult<()> {
+        let attachments = serde_json::to_string(&[embed.to_attachment()])
+            .map_err(|err| SourceError::Other(err.to_string()))?;
+        let client = ::slack::api::requests::default_client()
+            .map_err(|err| SourceError::ConnectionError(self.id.clone(), err.to_string()))?;
+        let request = ::slack::api::chat::PostMessageRequest {
+            channel: channel_id,
+            text: "",
+            attachments: Some(&attachments),
+            as_user: Some(true),
+            ..Default::default()
+        };
+        ::slack::api::chat::post_message(&client, &self.config.token, &request)
+            .map_err(|err| SourceError::ConnectionError(self.id.clone(), err.to_string()))?;
+        Ok(())
+    }
 }
 
 impl EventSource for SlackSource {
@@ -128,15 +147,17 @@ impl EventSource for SlackSource {
                 .map(|id| id.to_owned()),
             _ => return Err(SourceError::InvalidChannel(self.id.clone(), dst)),
         };
-        let msg = match msg {
-            MessageContent::Text(t) => t,
-            MessageContent::Me(t) => t,
-            _ => return Err(SourceError::InvalidMessage(self.id.clone(), msg)),
+        let channel_id = match channel_id {
+            Some(cid) => cid,
+            None => return Ok(()),
         };
-        channel_id.and_then(|cid| {
-            let _ = sender.send_message(&cid, &msg);
-            Some(())
-        });
+        match msg {
+            MessageContent::Text(t) | MessageContent::Me(t) => {
+                let _ = sender.send_message(&channel_id, &t);
+            }
+            MessageContent::Embed(embed) => self.post_embed(&channel_id, &embed)?,
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        }
         Ok(())
     }
 
@@ -197,7 +218,9 @@ impl EventHandler for SlackHandler {
                         (msg.user, msg.channel, msg.text)
                     {
                         let resp = client.start_response();
+                        let own_id = resp.slf.as_ref().and_then(|user| user.id.as_ref());
                         let msg = crate::core::Message {
+                            is_own: own_id == Some(&sender),
                             author: get_nick_by_id(resp, &sender)
                                 .unwrap_or("[no author]")
                                 .to_owned(),
@@ -0,0 +1,524 @@
+mod protocol;
+
+use self::protocol::ServerMessage;
+use crate::core::*;
+use crate::sources::wire::{self, WireEvent};
+use crate::sources::*;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use toml::Value;
+
+fn default_nick() -> String {
+    "bot".to_owned()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SocketConfig {
+    /// `host:port` for a TCP socket, or `unix:/path/to/socket` for a Unix one
+    address: String,
+    #[serde(default = "default_nick")]
+    nick: String,
+}
+
+/// A connected client
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = match *self {
+            Stream::Tcp(ref s) => s.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => s.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn bind(address: &str) -> io::Result<Listener> {
+        if let Some(_path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                let path = PathBuf::from(_path);
+                // a socket left behind by a previous run would make binding fail
+                let _ = std::fs::remove_file(&path);
+                return UnixListener::bind(&path).map(|listener| Listener::Unix(listener, path));
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix sockets aren't supported on this platform",
+            ));
+        }
+        TcpListener::bind(address).map(Listener::Tcp)
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(ref l, _) => l.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+
+    /// Makes a blocked `accept` return, by connecting to the socket
+    fn wake(&self) {
+        match *self {
+            Listener::Tcp(ref l) => {
+                if let Ok(mut addr) = l.local_addr() {
+                    if addr.ip().is_unspecified() {
+                        addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port());
+                    }
+                    let _ = TcpStream::connect(addr);
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, ref path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, ref path) = *self {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// How many lines may wait for a client before it's considered stalled and dropped
+const CLIENT_QUEUE_SIZE: usize = 64;
+
+/// A connected client, as seen by the senders
+/// The lines are written by a thread of its own, so that a client that stops reading can't block
+/// the others, or the core.
+struct Client {
+    queue: SyncSender<String>,
+    stream: Stream,
+}
+
+impl Client {
+    /// Starts the writer thread for the stream
+    fn new(stream: Stream) -> io::Result<Client> {
+        let mut writer = stream.try_clone()?;
+        let (queue, lines) = mpsc::sync_channel::<String>(CLIENT_QUEUE_SIZE);
+        let _ = thread::spawn(move || {
+            for line in lines {
+                if writer.write_all(line.as_bytes()).is_err() {
+                    // makes the reading side give up on the client too
+                    writer.shutdown();
+                    break;
+                }
+            }
+        });
+        Ok(Client { queue, stream })
+    }
+
+    /// Queues the line, returning false if the client is gone or isn't keeping up
+    fn push(&self, line: &str) -> bool {
+        self.queue.try_send(line.to_owned()).is_ok()
+    }
+}
+
+/// The connected clients, by connection numbers
+type Clients = Arc<Mutex<HashMap<usize, Client>>>;
+
+/// A helper enum for SocketSource
+enum SourceState {
+    Disconnected,
+    Running(Arc<Listener>, Arc<AtomicBool>, JoinHandle<()>),
+}
+
+/// An event source accepting clients speaking a JSON-lines protocol over a TCP or Unix socket
+/// The protocol is described in the `protocol` module.
+pub struct SocketSource {
+    /// the source ID
+    id: SourceId,
+    /// Socket configuration data
+    config: SocketConfig,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// The connected clients
+    clients: Clients,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl SocketSource {
+    /// Creates a SocketSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Socket source {:?}!",
+            source_id
+        ));
+        let config: SocketConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Socket source {:?}",
+            source_id
+        ));
+
+        Box::new(SocketSource {
+            id: source_id,
+            config,
+            sender,
+            clients: Default::default(),
+            state: SourceState::Disconnected,
+        })
+    }
+
+    /// Queues the message for all the clients, dropping the ones that can't take it
+    /// Returns the number of clients that got the message.
+    fn broadcast(&self, msg: &ServerMessage) -> usize {
+        let line = msg.to_line();
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, client| {
+            let queued = client.push(&line);
+            if !queued {
+                client.stream.shutdown();
+            }
+            queued
+        });
+        clients.len()
+    }
+
+    fn stop(&mut self) {
+        if let SourceState::Running(listener, stop, handle) =
+            std::mem::replace(&mut self.state, SourceState::Disconnected)
+        {
+            stop.store(true, Ordering::SeqCst);
+            listener.wake();
+            let _ = handle.join();
+        }
+        for (_, client) in self.clients.lock().unwrap().drain() {
+            client.stream.shutdown();
+        }
+    }
+}
+
+/// Reads the lines sent by a client and turns them into events
+fn handle_client(
+    conn: usize,
+    stream: Stream,
+    clients: &Clients,
+    id: &SourceId,
+    sender: &Sender<SourceEvent>,
+) {
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<WireEvent>(&line) {
+            Ok(msg) => {
+                let _ = sender.send(SourceEvent {
+                    source: id.clone(),
+                    event: msg.into_event(),
+                });
+            }
+            Err(e) => {
+                let error = ServerMessage::Error {
+                    message: e.to_string(),
+                };
+                if let Some(client) = clients.lock().unwrap().get(&conn) {
+                    let _ = client.push(&error.to_line());
+                }
+            }
+        }
+    }
+    let _ = clients.lock().unwrap().remove(&conn);
+}
+
+impl EventSource for SocketSource {
+    fn get_nick(&self) -> String {
+        self.config.nick.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        let listener = Listener::bind(&self.config.address)
+            .map_err(|e| SourceError::ConnectionError(self.id.clone(), e.to_string()))?;
+        let listener = Arc::new(listener);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_listener = listener.clone();
+        let thread_stop = stop.clone();
+        let clients = self.clients.clone();
+        let id = self.id.clone();
+        let sender = self.sender.clone();
+        let hello = ServerMessage::Hello {
+            nick: self.config.nick.clone(),
+        };
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            let mut next_conn = 0;
+            loop {
+                let result = thread_listener.accept();
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match result {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = sender.send(SourceEvent {
+                            source: id.clone(),
+                            event: Event::Disconnected(format!("{:?}", e)),
+                        });
+                        break;
+                    }
+                };
+                let client = match stream.try_clone().and_then(Client::new) {
+                    Ok(client) => client,
+                    Err(_) => continue,
+                };
+                // queued before the client is registered, so that the greeting comes first
+                if !client.push(&hello.to_line()) {
+                    continue;
+                }
+                next_conn += 1;
+                let _ = clients.lock().unwrap().insert(next_conn, client);
+
+                let conn = next_conn;
+                let clients = clients.clone();
+                let id = id.clone();
+                let sender = sender.clone();
+                let _ = thread::spawn(move || handle_client(conn, stream, &clients, &id, &sender));
+            }
+        });
+
+        self.state = SourceState::Running(listener, stop, handle);
+        Ok(())
+    }
+
+    /// Tells the clients to join the channel - there is nothing to do without any
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        let _ = self.broadcast(&ServerMessage::Join {
+            channel: channel.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let content = match wire::content_to_wire(msg.clone()) {
+            Some(content) => content,
+            None => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let sent = self.broadcast(&ServerMessage::Send {
+            channel: wire::channel_to_wire(dst),
+            content,
+        });
+        if sent == 0 {
+            return Err(SourceError::ConnectionError(
+                self.id.clone(),
+                "no clients connected".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        self.stop();
+        self.connect()
+    }
+}
+
+impl Drop for SocketSource {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{expect_connected, free_port, next_event};
+    use serde_json::{json, Value as JsonValue};
+    use std::sync::mpsc::channel;
+
+    fn read_json<R: BufRead>(reader: &mut R) -> JsonValue {
+        let mut line = String::new();
+        let _ = reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_client() {
+        let port = free_port();
+        let (tx, rx) = channel();
+        let config = toml::from_str(&format!("address = \"127.0.0.1:{}\"", port)).unwrap();
+        let mut source = SocketSource::new(SourceId("socket".to_owned()), tx, Some(config));
+        source.connect().unwrap();
+        expect_connected(&rx);
+        assert!(source
+            .send(Channel::None, MessageContent::Text("hi".to_owned()))
+            .is_err());
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        assert_eq!(
+            read_json(&mut reader),
+            json!({ "type": "hello", "nick": "bot" })
+        );
+
+        client
+            .write_all(
+                b"{\"type\": \"message\", \"author\": \"tool\", \"channel\": {\"channel\": \"ops\"}, \
+                  \"content\": {\"me\": \"deploys\"}}\nnot json\n{\"type\": \"online\", \"user\": \"tool\"}\n",
+            )
+            .unwrap();
+        match next_event(&rx) {
+            Event::ReceivedMessage(msg) => {
+                assert_eq!(msg.author, "tool");
+                assert_eq!(msg.channel, Channel::Channel("ops".to_owned()));
+                match msg.content {
+                    MessageContent::Me(ref text) => assert_eq!(text, "deploys"),
+                    ref content => panic!("unexpected content: {:?}", content),
+                }
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(read_json(&mut reader)["type"], "error");
+        match next_event(&rx) {
+            Event::UserOnline(ref user) => assert_eq!(user, "tool"),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        source
+            .send(
+                Channel::Thread("ops".to_owned(), "deploys".to_owned()),
+                MessageContent::Text("ok".to_owned()),
+            )
+            .unwrap();
+        assert_eq!(
+            read_json(&mut reader),
+            json!({
+                "type": "send",
+                "channel": { "thread": ["ops", "deploys"] },
+                "content": { "text": "ok" },
+            })
+        );
+        source.reconnect().unwrap();
+    }
+
+    #[test]
+    fn test_stalled_client() {
+        let port = free_port();
+        let (tx, rx) = channel();
+        let config = toml::from_str(&format!("address = \"127.0.0.1:{}\"", port)).unwrap();
+        let mut source = SocketSource::new(SourceId("socket".to_owned()), tx, Some(config));
+        source.connect().unwrap();
+        expect_connected(&rx);
+
+        // never reads anything, so that the socket buffers and then its queue fill up
+        let stalled = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let text = "x".repeat(64 * 1024);
+        let mut sent = 0;
+        while source
+            .send(Channel::None, MessageContent::Text(text.clone()))
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent < 10_000, "the stalled client was never dropped");
+        }
+
+        // the others are unaffected
+        let client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut reader = BufReader::new(client);
+        assert_eq!(read_json(&mut reader)["type"], "hello");
+        source.join("ops").unwrap();
+        assert_eq!(read_json(&mut reader)["type"], "join");
+        drop(stalled);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_client() {
+        let path = std::env::temp_dir().join(format!("socket-source-{}.sock", std::process::id()));
+        let (tx, rx) = channel();
+        let config = toml::from_str(&format!("address = \"unix:{}\"", path.display())).unwrap();
+        let mut source = SocketSource::new(SourceId("socket".to_owned()), tx, Some(config));
+        source.connect().unwrap();
+        expect_connected(&rx);
+
+        let client = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(client);
+        assert_eq!(read_json(&mut reader)["type"], "hello");
+        source.join("ops").unwrap();
+        assert_eq!(
+            read_json(&mut reader),
+            json!({ "type": "join", "channel": "ops" })
+        );
+
+        drop(source);
+        assert!(!path.exists());
+    }
+}
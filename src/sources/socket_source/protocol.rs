@@ -0,0 +1,39 @@
+//! The JSON-lines protocol spoken over the socket
+//!
+//! Every line is a single JSON object. Clients send the events described in the `wire` module,
+//! one per line.
+//!
+//! The bot sends `{"type": "hello", "nick": ...}` to every new client, then
+//! `{"type": "send", "channel": ..., "content": ...}` and `{"type": "join", "channel": ...}`
+//! for the calls made on the source, and `{"type": "error", "message": ...}` in response to
+//! lines it couldn't understand.
+
+use crate::sources::wire::{WireChannel, WireContent};
+
+/// A line sent to the clients
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    Hello {
+        nick: String,
+    },
+    Send {
+        channel: Option<WireChannel>,
+        content: WireContent,
+    },
+    Join {
+        channel: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    /// Serializes the message into a single line, including the newline
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("serializing can't fail");
+        line.push('\n');
+        line
+    }
+}
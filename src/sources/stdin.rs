@@ -1,58 +1,326 @@
-use crate::core::{Event, SourceEvent, SourceId};
+use crate::core::{Channel, Event, Message, MessageContent, SourceEvent, SourceId};
 use crate::sources::*;
-use std::io;
+use std::io::{self, BufRead, Write};
+use std::mem;
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use toml::Value;
 
-pub struct StdinSource(JoinHandle<()>);
+fn default_user() -> String {
+    "console".to_owned()
+}
+
+fn default_nick() -> String {
+    "bot".to_owned()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StdinConfig {
+    /// The author of the typed messages
+    #[serde(default = "default_user")]
+    user: String,
+    #[serde(default = "default_nick")]
+    nick: String,
+    /// The channel the typed messages go to at the start
+    channel: Option<String>,
+}
+
+type Output = Arc<Mutex<dyn Write + Send>>;
+
+/// A helper enum for StdinSource
+enum SourceState {
+    Disconnected,
+    Running(JoinHandle<()>),
+}
+
+/// An interactive console on the standard input and output
+///
+/// Lines typed in go to the current channel as messages, and the messages sent to the source are
+/// printed with the channel labels. The console commands are:
+/// - `/join <channel>` - makes the channel the current one
+/// - `/msg <#channel or user> <text>` - sends a single message to a channel or a private
+///   conversation
+/// - `/me <text>` - sends a /me message to the current channel
+///
+/// Before any channel is joined, the lines are passed on as `Event::DirectInput`.
+pub struct StdinSource {
+    /// the source ID
+    id: SourceId,
+    /// Console configuration data
+    config: StdinConfig,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// The input - taken by the reading thread
+    input: Option<Box<dyn BufRead + Send>>,
+    output: Output,
+    /// The channel the typed messages go to
+    current: Arc<Mutex<Channel>>,
+    /// Current state of the source
+    state: SourceState,
+}
 
 impl StdinSource {
-    /// Creates the Stdin source - a simple loop sending lines read from the standard input
+    /// Creates the Stdin source
     pub fn new(
         source_id: SourceId,
         sender: Sender<SourceEvent>,
-        _: Option<Value>,
+        config: Option<Value>,
     ) -> Box<dyn EventSource> {
-        let handle = thread::spawn(move || {
-            let stdin = io::stdin();
-            loop {
-                let mut buffer = String::new();
-                stdin
-                    .read_line(&mut buffer)
-                    .ok()
-                    .expect("stdin.read_line() failed");
-                sender
-                    .send(SourceEvent {
-                        source: source_id.clone(),
-                        event: Event::DirectInput(buffer),
-                    })
-                    .ok()
-                    .expect("Sender::send() failed in stdin");
+        let input = Box::new(io::BufReader::new(io::stdin()));
+        Box::new(Self::with_io(
+            source_id,
+            sender,
+            config,
+            input,
+            Arc::new(Mutex::new(io::stdout())),
+        ))
+    }
+
+    fn with_io(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+        input: Box<dyn BufRead + Send>,
+        output: Output,
+    ) -> StdinSource {
+        let config = config.unwrap_or_else(|| Value::Table(Default::default()));
+        let config: StdinConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Stdin source {:?}",
+            source_id
+        ));
+        let current = match config.channel {
+            Some(ref channel) => Channel::Channel(channel.trim_start_matches('#').to_owned()),
+            None => Channel::None,
+        };
+
+        StdinSource {
+            id: source_id,
+            config,
+            sender,
+            input: Some(input),
+            output,
+            current: Arc::new(Mutex::new(current)),
+            state: SourceState::Disconnected,
+        }
+    }
+}
+
+fn print(output: &Output, text: &str) {
+    let mut output = output.lock().unwrap();
+    let _ = writeln!(output, "{}", text);
+    let _ = output.flush();
+}
+
+/// Parses a console channel name - `#name` is a channel, anything else a user
+fn parse_target(target: &str) -> Channel {
+    match target.strip_prefix('#') {
+        Some(channel) => Channel::Channel(channel.to_owned()),
+        None => Channel::User(target.to_owned()),
+    }
+}
+
+/// Turns a typed line into an event, handling the console commands
+fn handle_line(line: &str, user: &str, current: &Mutex<Channel>, output: &Output) -> Option<Event> {
+    let message = |channel: Channel, content: MessageContent| {
+        Some(Event::ReceivedMessage(Message {
+            author: user.to_owned(),
+            channel,
+            content,
+            is_own: false,
+        }))
+    };
+    let (command, args) = match line.find(' ') {
+        Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+        None => (line, ""),
+    };
+    match command {
+        "/join" if !args.is_empty() => {
+            let channel = Channel::Channel(args.trim_start_matches('#').to_owned());
+            print(output, &format!("[now talking in {}]", channel.as_str()));
+            *current.lock().unwrap() = channel;
+            None
+        }
+        "/msg" => {
+            let (target, text) = match args.find(' ') {
+                Some(pos) => (&args[..pos], args[pos + 1..].trim()),
+                None => {
+                    print(output, "[usage: /msg <#channel or user> <text>]");
+                    return None;
+                }
+            };
+            message(parse_target(target), MessageContent::Text(text.to_owned()))
+        }
+        "/me" => {
+            let channel = current.lock().unwrap().clone();
+            message(channel, MessageContent::Me(args.to_owned()))
+        }
+        _ => {
+            let channel = current.lock().unwrap().clone();
+            match channel {
+                Channel::None => Some(Event::DirectInput(line.to_owned())),
+                channel => message(channel, MessageContent::Text(line.to_owned())),
             }
-        });
-        Box::new(StdinSource(handle))
+        }
     }
 }
 
 impl EventSource for StdinSource {
     fn get_nick(&self) -> String {
-        String::new()
+        self.config.nick.clone()
     }
 
+    /// Starts reading the input - the standard input can only be read once, so reconnecting
+    /// after the end of the input does nothing
     fn connect(&mut self) -> SourceResult<()> {
+        let mut input = match self.input.take() {
+            Some(input) => input,
+            None => return Ok(()),
+        };
+        let id = self.id.clone();
+        let sender = self.sender.clone();
+        let user = self.config.user.clone();
+        let current = self.current.clone();
+        let output = self.output.clone();
+        let handle = thread::spawn(move || {
+            let send = |event| {
+                let _ = sender.send(SourceEvent {
+                    source: id.clone(),
+                    event,
+                });
+            };
+            send(Event::Connected);
+            let mut buffer = vec![];
+            loop {
+                buffer.clear();
+                match input.read_until(b'\n', &mut buffer) {
+                    Ok(0) => {
+                        send(Event::Disconnected("end of input".to_owned()));
+                        break;
+                    }
+                    Ok(_) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        send(Event::Disconnected(format!("{:?}", e)));
+                        break;
+                    }
+                }
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end_matches(['\r', '\n']);
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Some(event) = handle_line(line, &user, &current, &output) {
+                    send(event);
+                }
+            }
+        });
+        self.state = SourceState::Running(handle);
         Ok(())
     }
 
-    fn join(&mut self, _: &str) -> SourceResult<()> {
+    /// Makes the channel the current one, unless there already is one
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        let mut current = self.current.lock().unwrap();
+        if *current == Channel::None {
+            *current = Channel::Channel(channel.trim_start_matches('#').to_owned());
+            print(
+                &self.output,
+                &format!("[now talking in {}]", current.as_str()),
+            );
+        }
         Ok(())
     }
 
-    fn send(&mut self, _: Channel, _: MessageContent) -> SourceResult<()> {
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        let label = dst.as_str();
+        match msg {
+            MessageContent::Embed(ref embed) => {
+                print(&self.output, &format!("[{}] <{}>", label, self.config.nick));
+                for line in embed.to_lines() {
+                    print(&self.output, &format!("[{}]   {}", label, line));
+                }
+            }
+            ref msg => print(
+                &self.output,
+                &format!("[{}] {}", label, msg.display_with_nick(&self.config.nick)),
+            ),
+        }
         Ok(())
     }
 
     fn reconnect(&mut self) -> SourceResult<()> {
-        Ok(())
+        // the thread of the previous connection ends after reporting the disconnection
+        let state = mem::replace(&mut self.state, SourceState::Disconnected);
+        if let SourceState::Running(handle) = state {
+            let _ = handle.join();
+        }
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_console() {
+        let input = "hello\n\n/join #ops\nhi there\r\n/me waves\n/msg alice psst\n/msg #dev yo\n";
+        let output = Arc::new(Mutex::new(vec![]));
+        let (tx, rx) = channel();
+        let mut source = StdinSource::with_io(
+            SourceId("stdin".to_owned()),
+            tx,
+            None,
+            Box::new(io::Cursor::new(input.as_bytes().to_vec())),
+            output.clone(),
+        );
+        source.connect().unwrap();
+
+        let mut events = vec![];
+        loop {
+            let event = rx.recv_timeout(Duration::from_secs(5)).unwrap().event;
+            let end = matches!(event, Event::Disconnected(_));
+            events.push(event);
+            if end {
+                break;
+            }
+        }
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| match *event {
+                Event::ReceivedMessage(ref msg) => format!(
+                    "{} {}",
+                    msg.channel.as_str(),
+                    msg.content.display_with_nick(&msg.author)
+                ),
+                ref event => format!("{:?}", event),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "Connected",
+                "DirectInput(\"hello\")",
+                "#ops <console> hi there",
+                "#ops * console waves",
+                "alice(priv) <console> psst",
+                "#dev <console> yo",
+                "Disconnected(\"end of input\")",
+            ]
+        );
+
+        source
+            .send(
+                Channel::Channel("ops".to_owned()),
+                MessageContent::Text("hi".to_owned()),
+            )
+            .unwrap();
+        // the input has ended, so this is a no-op
+        source.reconnect().unwrap();
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(output, "[now talking in #ops]\n[#ops] <bot> hi\n");
     }
 }
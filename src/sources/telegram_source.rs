@@ -0,0 +1,439 @@
+use crate::core::*;
+use crate::sources::http;
+use crate::sources::*;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toml::Value;
+
+fn default_api_url() -> String {
+    "https://api.telegram.org".to_owned()
+}
+
+fn default_poll_timeout() -> u64 {
+    30
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TelegramConfig {
+    token: String,
+    /// The base URL of the Bot API
+    #[serde(default = "default_api_url")]
+    api_url: String,
+    /// The server-side timeout of getUpdates requests, in seconds
+    #[serde(default = "default_poll_timeout")]
+    poll_timeout: u64,
+}
+
+/// A helper enum for TelegramSource
+enum SourceState {
+    Disconnected,
+    Running(JoinHandle<()>),
+}
+
+/// Data shared between the source and the polling thread
+#[derive(Default)]
+struct TelegramData {
+    bot_id: i64,
+    username: String,
+    /// The IDs of the private chats, by the names used in `Channel::User`s
+    users: HashMap<String, i64>,
+    /// The IDs of the groups and channels, by the names used in `Channel::Channel`s
+    groups: HashMap<String, i64>,
+}
+
+/// A client of the Telegram Bot API, shared by the source and the polling thread
+#[derive(Clone)]
+struct TelegramClient {
+    id: SourceId,
+    config: TelegramConfig,
+    agent: ureq::Agent,
+    data: Arc<RwLock<TelegramData>>,
+}
+
+impl TelegramClient {
+    fn new(id: SourceId, config: TelegramConfig) -> Self {
+        let agent = http::agent(Duration::from_secs(config.poll_timeout + 10));
+        TelegramClient {
+            id,
+            config,
+            agent,
+            data: Default::default(),
+        }
+    }
+
+    /// Calls a Bot API method and returns its result
+    fn call(&self, method: &str, params: JsonValue) -> SourceResult<JsonValue> {
+        let url = format!(
+            "{}/bot{}/{}",
+            self.config.api_url, self.config.token, method
+        );
+        let resp = http::call(&self.id, self.agent.post(&url), Some(params))?;
+        if resp["ok"] != true {
+            return Err(SourceError::ConnectionError(
+                self.id.clone(),
+                format!("{} failed: {}", method, resp["description"]),
+            ));
+        }
+        Ok(resp["result"].clone())
+    }
+
+    fn get_me(&self) -> SourceResult<()> {
+        let me = self.call("getMe", json!({}))?;
+        let mut data = self.data.write().unwrap();
+        data.bot_id = me["id"].as_i64().unwrap_or(0);
+        data.username = me["username"].as_str().unwrap_or("").to_owned();
+        Ok(())
+    }
+
+    fn get_updates(&self, offset: i64) -> SourceResult<Vec<JsonValue>> {
+        let params = json!({
+            "offset": offset,
+            "timeout": self.config.poll_timeout,
+            "allowed_updates": ["message", "edited_message", "channel_post", "edited_channel_post"],
+        });
+        let updates = self.call("getUpdates", params)?;
+        Ok(updates.as_array().cloned().unwrap_or_default())
+    }
+
+    /// Translates an update into an event, remembering the chat it came from
+    fn update_to_event(&self, update: &JsonValue) -> Option<Event> {
+        let (msg, edited) = if update["message"].is_object() {
+            (&update["message"], false)
+        } else if update["channel_post"].is_object() {
+            (&update["channel_post"], false)
+        } else if update["edited_message"].is_object() {
+            (&update["edited_message"], true)
+        } else if update["edited_channel_post"].is_object() {
+            (&update["edited_channel_post"], true)
+        } else {
+            return None;
+        };
+
+        let chat = &msg["chat"];
+        let chat_id = chat["id"].as_i64()?;
+        let channel = if chat["type"] == "private" {
+            Channel::User(display_name(chat))
+        } else {
+            Channel::Channel(chat["title"].as_str().unwrap_or("[no title]").to_owned())
+        };
+        let mut data = self.data.write().unwrap();
+        match channel {
+            Channel::User(ref name) => {
+                let _ = data.users.insert(name.clone(), chat_id);
+            }
+            Channel::Channel(ref name) => {
+                let _ = data.groups.insert(name.clone(), chat_id);
+            }
+            _ => (),
+        }
+
+        let content = if let Some(text) = msg["text"].as_str() {
+            MessageContent::Text(text.to_owned())
+        } else if msg["photo"].is_array() {
+            MessageContent::Image
+        } else {
+            return None;
+        };
+        // channel posts have no author other than the channel itself
+        let (author, is_own) = if msg["from"].is_object() {
+            (
+                display_name(&msg["from"]),
+                msg["from"]["id"].as_i64() == Some(data.bot_id),
+            )
+        } else {
+            (display_name(chat), false)
+        };
+        let msg = Message {
+            author,
+            channel,
+            content,
+            is_own,
+        };
+        Some(if edited {
+            Event::EditedMessage(msg)
+        } else {
+            Event::ReceivedMessage(msg)
+        })
+    }
+
+    /// Finds the chat ID for a `Channel` - chats are known by name once a message was received
+    /// from them; numeric IDs and public `@username`s can be used directly
+    fn chat_id(&self, channel: &Channel) -> Option<JsonValue> {
+        let data = self.data.read().unwrap();
+        let (chats, name) = match *channel {
+            Channel::User(ref name) => (&data.users, name),
+            Channel::Channel(ref name) => (&data.groups, name),
+            _ => return None,
+        };
+        if let Some(id) = chats.get(name) {
+            return Some(json!(id));
+        }
+        if name.starts_with('@') {
+            return Some(json!(name));
+        }
+        name.parse::<i64>().ok().map(|id| json!(id))
+    }
+}
+
+/// Returns the name of a user or a chat - the username if there is one
+fn display_name(user: &JsonValue) -> String {
+    user["username"]
+        .as_str()
+        .or_else(|| user["title"].as_str())
+        .or_else(|| user["first_name"].as_str())
+        .unwrap_or("[unknown]")
+        .to_owned()
+}
+
+/// A Telegram event source
+pub struct TelegramSource {
+    /// the source ID
+    id: SourceId,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// API client, shared with the polling thread
+    client: TelegramClient,
+    /// Current state of the source
+    state: SourceState,
+    /// The ID following the last received update, kept across reconnections so that the
+    /// updates aren't delivered twice
+    offset: Arc<AtomicI64>,
+}
+
+impl TelegramSource {
+    /// Creates a TelegramSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Telegram source {:?}!",
+            source_id
+        ));
+        let config: TelegramConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Telegram source {:?}",
+            source_id
+        ));
+
+        Box::new(TelegramSource {
+            id: source_id.clone(),
+            sender,
+            client: TelegramClient::new(source_id, config),
+            state: SourceState::Disconnected,
+            offset: Arc::new(AtomicI64::new(0)),
+        })
+    }
+}
+
+impl EventSource for TelegramSource {
+    fn get_nick(&self) -> String {
+        self.client.data.read().unwrap().username.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        self.client.get_me()?;
+
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let id = self.id.clone();
+        let offset = self.offset.clone();
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            loop {
+                let updates = match client.get_updates(offset.load(Ordering::SeqCst)) {
+                    Ok(updates) => updates,
+                    Err(e) => {
+                        let _ = sender.send(SourceEvent {
+                            source: id.clone(),
+                            event: Event::Disconnected(format!("{:?}", e)),
+                        });
+                        return;
+                    }
+                };
+                for update in updates {
+                    if let Some(update_id) = update["update_id"].as_i64() {
+                        let _ = offset.fetch_max(update_id + 1, Ordering::SeqCst);
+                    }
+                    if let Some(event) = client.update_to_event(&update) {
+                        let _ = sender.send(SourceEvent {
+                            source: id.clone(),
+                            event,
+                        });
+                    }
+                }
+            }
+        });
+
+        self.state = SourceState::Running(handle);
+        Ok(())
+    }
+
+    /// Bots can't join chats by themselves - they have to be added by users
+    fn join(&mut self, _channel: &str) -> SourceResult<()> {
+        Ok(())
+    }
+
+    /// Sends a message to a group or a private chat
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let chat_id = match self.client.chat_id(&dst) {
+            Some(chat_id) => chat_id,
+            None => return Err(SourceError::InvalidChannel(self.id.clone(), dst)),
+        };
+        let text = match msg {
+            MessageContent::Text(t) | MessageContent::Me(t) => t,
+            MessageContent::Embed(ref embed) => embed.to_text(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let _ = self
+            .client
+            .call("sendMessage", json!({ "chat_id": chat_id, "text": text }))?;
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        // the thread of the previous connection ends after reporting the disconnection
+        let state = mem::replace(&mut self.state, SourceState::Disconnected);
+        if let SourceState::Running(handle) = state {
+            let _ = handle.join();
+        }
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{
+        expect_connected, free_port, next_event, next_message, MockRequest, MockServer,
+    };
+    use std::sync::mpsc::channel;
+
+    fn bot_api(req: &MockRequest) -> String {
+        let result = if req.url.ends_with("/getMe") {
+            json!({ "id": 42, "is_bot": true, "username": "test_bot" })
+        } else if req.url.ends_with("/getUpdates") && req.body.contains("\"offset\":0") {
+            let alice = json!({ "id": 1, "first_name": "Alice", "username": "alice" });
+            let group = json!({ "id": -100, "type": "group", "title": "Team" });
+            let private = json!({ "id": 1, "type": "private", "username": "alice" });
+            let namesake = json!({ "id": -200, "type": "group", "title": "alice" });
+            json!([
+                { "update_id": 10, "message": { "from": alice, "chat": group, "text": "hi" } },
+                {
+                    "update_id": 11,
+                    "message": { "from": alice, "chat": private, "photo": [{ "file_id": "x" }] },
+                },
+                {
+                    "update_id": 12,
+                    "edited_message": { "from": alice, "chat": group, "text": "hi all" },
+                },
+                // a group named like a user
+                { "update_id": 13, "message": { "from": alice, "chat": namesake, "text": "hey" } },
+            ])
+        } else if req.url.ends_with("/getUpdates") {
+            thread::sleep(Duration::from_millis(50));
+            json!([])
+        } else {
+            json!({ "message_id": 1 })
+        };
+        json!({ "ok": true, "result": result }).to_string()
+    }
+
+    #[test]
+    fn test_updates_and_send() {
+        let server = MockServer::start(bot_api);
+        let config = format!("token = \"123:abc\"\napi_url = \"{}\"", server.url());
+        let (tx, rx) = channel();
+        let mut source = TelegramSource::new(
+            SourceId("telegram".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+        assert_eq!(source.get_nick(), "test_bot");
+
+        expect_connected(&rx);
+        let msg = next_message(&rx);
+        assert_eq!(msg.author, "alice");
+        assert_eq!(msg.channel, Channel::Channel("Team".to_owned()));
+        match msg.content {
+            MessageContent::Text(ref t) => assert_eq!(t, "hi"),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+        let msg = next_message(&rx);
+        assert_eq!(msg.channel, Channel::User("alice".to_owned()));
+        match msg.content {
+            MessageContent::Image => (),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+        match next_event(&rx) {
+            Event::EditedMessage(msg) => match msg.content {
+                MessageContent::Text(ref t) => assert_eq!(t, "hi all"),
+                ref content => panic!("unexpected content: {:?}", content),
+            },
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(
+            next_message(&rx).channel,
+            Channel::Channel("alice".to_owned())
+        );
+
+        for channel in &[
+            Channel::Channel("Team".to_owned()),
+            Channel::User("alice".to_owned()),
+            Channel::Channel("alice".to_owned()),
+        ] {
+            source
+                .send(channel.clone(), MessageContent::Text("hello".to_owned()))
+                .unwrap();
+        }
+        let chat_ids: Vec<JsonValue> = server
+            .requests("/bot123:abc/sendMessage")
+            .iter()
+            .map(|req| serde_json::from_str::<JsonValue>(&req.body).unwrap()["chat_id"].clone())
+            .collect();
+        assert_eq!(chat_ids, vec![json!(-100), json!(1), json!(-200)]);
+
+        // the next poll acknowledges the received updates
+        thread::sleep(Duration::from_millis(100));
+        assert!(server
+            .requests("/bot123:abc/getUpdates")
+            .iter()
+            .any(|req| req.body.contains("\"offset\":14")));
+    }
+
+    #[test]
+    fn test_errors_hide_token() {
+        let server = MockServer::start_with_status(|_| {
+            (
+                401,
+                json!({ "ok": false, "description": "Unauthorized" }).to_string(),
+            )
+        });
+        let unreachable = format!("http://127.0.0.1:{}", free_port());
+        for api_url in &[server.url(), unreachable] {
+            let config = format!("token = \"123:abc\"\napi_url = \"{}\"", api_url);
+            let (tx, _rx) = channel();
+            let mut source = TelegramSource::new(
+                SourceId("telegram".to_owned()),
+                tx,
+                Some(toml::from_str(&config).unwrap()),
+            );
+            let err = format!("{:?}", source.connect().unwrap_err());
+            assert!(err.contains("ConnectionError"), "{}", err);
+            assert!(!err.contains("123:abc"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use crate::core::*;
+use crate::sources::http;
+use crate::sources::*;
+use serde_json::{json, Map, Value as JsonValue};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use toml::Value;
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_retry_delay() -> u64 {
+    1000
+}
+
+/// The payload format expected by the target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SinkFormat {
+    /// `{"text", "channel", "username", "avatar_url"}`
+    #[default]
+    Generic,
+    Slack,
+    Discord,
+    Teams,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WebhookSinkConfig {
+    url: String,
+    #[serde(default)]
+    format: SinkFormat,
+    /// A custom payload, used instead of `format` - `{{text}}`, `{{channel}}`, `{{username}}` and
+    /// `{{avatar_url}}` are replaced with JSON strings, or `null`s
+    template: Option<String>,
+    /// The name and avatar the messages are posted under by default
+    username: Option<String>,
+    avatar_url: Option<String>,
+    #[serde(default = "default_retries")]
+    retries: u32,
+    /// The delay before the first retry in milliseconds, doubled with every next one
+    #[serde(default = "default_retry_delay")]
+    retry_delay: u64,
+}
+
+/// A send-only source posting the messages to a webhook URL
+pub struct WebhookSink {
+    /// the source ID
+    id: SourceId,
+    /// Webhook configuration data
+    config: WebhookSinkConfig,
+    agent: ureq::Agent,
+}
+
+/// Replaces the placeholders in the template with the values in a single pass, so that
+/// placeholders within the values are left as they are
+fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|&&(name, _)| rest.starts_with(name)) {
+            Some(&(name, ref value)) => {
+                result.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                result.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+impl WebhookSink {
+    /// Creates a WebhookSink with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        _sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for WebhookSink source {:?}!",
+            source_id
+        ));
+        let config: WebhookSinkConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to WebhookSink source {:?}",
+            source_id
+        ));
+
+        Box::new(WebhookSink {
+            id: source_id,
+            config,
+            agent: http::agent(Duration::from_secs(30)),
+        })
+    }
+
+    fn payload(&self, dst: &Channel, text: &str, identity: &Identity) -> String {
+        let username = identity.username.as_ref().or(self.config.username.as_ref());
+        let avatar_url = identity
+            .avatar_url
+            .as_ref()
+            .or(self.config.avatar_url.as_ref());
+        let channel = match *dst {
+            Channel::Channel(ref name) | Channel::User(ref name) => Some(name),
+            _ => None,
+        };
+
+        if let Some(ref template) = self.config.template {
+            return fill_template(
+                template,
+                &[
+                    ("{{text}}", json!(text).to_string()),
+                    ("{{channel}}", json!(channel).to_string()),
+                    ("{{username}}", json!(username).to_string()),
+                    ("{{avatar_url}}", json!(avatar_url).to_string()),
+                ],
+            );
+        }
+
+        let mut payload = Map::new();
+        let mut set = |key: &str, value: Option<&String>| {
+            if let Some(value) = value {
+                let _ = payload.insert(key.to_owned(), json!(value));
+            }
+        };
+        match self.config.format {
+            SinkFormat::Generic => {
+                set("text", Some(&text.to_owned()));
+                set("channel", channel);
+                set("username", username);
+                set("avatar_url", avatar_url);
+            }
+            SinkFormat::Slack => {
+                let channel = match *dst {
+                    Channel::Channel(ref name) => Some(format!("#{}", name)),
+                    Channel::User(ref name) => Some(format!("@{}", name)),
+                    _ => None,
+                };
+                set("text", Some(&text.to_owned()));
+                set("channel", channel.as_ref());
+                set("username", username);
+                set("icon_url", avatar_url);
+            }
+            SinkFormat::Discord => {
+                set("content", Some(&text.to_owned()));
+                set("username", username);
+                set("avatar_url", avatar_url);
+            }
+            // connectors don't allow overriding the identity
+            SinkFormat::Teams => set("text", Some(&text.to_owned())),
+        }
+        JsonValue::Object(payload).to_string()
+    }
+
+    /// Posts the payload, retrying on server errors and rate limits
+    /// This blocks the caller for the time of the retries.
+    fn post(&self, payload: &str) -> SourceResult<()> {
+        let mut delay = Duration::from_millis(self.config.retry_delay);
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .agent
+                .post(&self.config.url)
+                .set("Content-Type", "application/json")
+                .send_string(payload);
+            let err = match result {
+                Ok(_) => return Ok(()),
+                Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => {
+                    return Err(SourceError::ConnectionError(
+                        self.id.clone(),
+                        format!("the webhook responded with {}", code),
+                    ))
+                }
+                Err(err) => err,
+            };
+            if attempt >= self.config.retries {
+                return Err(SourceError::ConnectionError(
+                    self.id.clone(),
+                    http::describe(err),
+                ));
+            }
+            attempt += 1;
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+}
+
+impl EventSource for WebhookSink {
+    fn get_nick(&self) -> String {
+        self.config
+            .username
+            .clone()
+            .unwrap_or_else(|| "webhook".to_owned())
+    }
+
+    /// There is nothing to connect to - every message is a separate request
+    fn connect(&mut self) -> SourceResult<()> {
+        Ok(())
+    }
+
+    fn join(&mut self, _channel: &str) -> SourceResult<()> {
+        Ok(())
+    }
+
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        self.send_as(dst, msg, &Identity::default())
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        Ok(())
+    }
+
+    fn can_receive(&self) -> bool {
+        false
+    }
+
+    fn send_as(
+        &mut self,
+        dst: Channel,
+        msg: MessageContent,
+        identity: &Identity,
+    ) -> SourceResult<()> {
+        let text = match msg {
+            MessageContent::Text(ref t) | MessageContent::Me(ref t) => t.clone(),
+            MessageContent::Embed(ref embed) => embed.to_text(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        let payload = self.payload(&dst, &text, identity);
+        self.post(&payload)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::MockServer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+
+    fn sink(config: &str) -> Box<dyn EventSource> {
+        let (tx, _) = channel();
+        WebhookSink::new(
+            SourceId("sink".to_owned()),
+            tx,
+            Some(toml::from_str(config).unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_discord_overrides() {
+        let server = MockServer::start(|_| String::new());
+        let mut sink = sink(&format!(
+            "url = \"{}/hook\"\nformat = \"discord\"\nusername = \"bot\"",
+            server.url()
+        ));
+        assert!(!sink.can_receive());
+        let channel = Channel::Channel("alerts".to_owned());
+        sink.send(channel.clone(), MessageContent::Text("hi".to_owned()))
+            .unwrap();
+        let identity = Identity {
+            username: Some("CI".to_owned()),
+            avatar_url: Some("https://example.com/ci.png".to_owned()),
+        };
+        sink.send_as(
+            channel,
+            MessageContent::Text("passed".to_owned()),
+            &identity,
+        )
+        .unwrap();
+
+        let bodies: Vec<JsonValue> = server
+            .requests("/hook")
+            .iter()
+            .map(|req| serde_json::from_str(&req.body).unwrap())
+            .collect();
+        assert_eq!(bodies[0], json!({ "content": "hi", "username": "bot" }));
+        assert_eq!(
+            bodies[1],
+            json!({
+                "content": "passed",
+                "username": "CI",
+                "avatar_url": "https://example.com/ci.png",
+            })
+        );
+    }
+
+    #[test]
+    fn test_template_and_retries() {
+        // the first request fails with a server error, the later ones with a client error
+        let count = Arc::new(AtomicUsize::new(0));
+        let server_count = count.clone();
+        let server = MockServer::start_with_status(move |_| {
+            match server_count.fetch_add(1, Ordering::SeqCst) {
+                0 => (503, String::new()),
+                1 => (200, String::new()),
+                _ => (400, String::new()),
+            }
+        });
+        let mut sink = sink(&format!(
+            "url = \"{}/hook\"\nretry_delay = 10\n\
+             template = '{{\"msg\": {{{{text}}}}, \"to\": {{{{channel}}}}}}'",
+            server.url()
+        ));
+        sink.send(
+            Channel::Channel("ops".to_owned()),
+            MessageContent::Text("say \"hi\"".to_owned()),
+        )
+        .unwrap();
+        let requests = server.requests("/hook");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].body,
+            "{\"msg\": \"say \\\"hi\\\"\", \"to\": \"ops\"}"
+        );
+
+        // client errors aren't retried
+        assert!(sink
+            .send(Channel::None, MessageContent::Text("hi".to_owned()))
+            .is_err());
+        assert_eq!(server.requests("/hook").len(), 3);
+    }
+
+    #[test]
+    fn test_template_placeholders_in_values() {
+        let server = MockServer::start(|_| String::new());
+        let mut sink = sink(&format!(
+            "url = \"{}/hook\"\nusername = \"bot\"\n\
+             template = '{{\"msg\": {{{{text}}}}, \"from\": {{{{username}}}}}}'",
+            server.url()
+        ));
+        sink.send(
+            Channel::Channel("ops".to_owned()),
+            MessageContent::Text("see {{username}}".to_owned()),
+        )
+        .unwrap();
+        assert_eq!(
+            server.requests("/hook")[0].body,
+            "{\"msg\": \"see {{username}}\", \"from\": \"bot\"}"
+        );
+    }
+}
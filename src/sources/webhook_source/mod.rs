@@ -0,0 +1,417 @@
+mod payload;
+
+pub use self::payload::PayloadFormat;
+use crate::core::*;
+use crate::sources::*;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value as JsonValue};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server};
+use toml::Value;
+
+fn default_address() -> String {
+    "0.0.0.0:8080".to_owned()
+}
+
+fn default_nick() -> String {
+    "webhook".to_owned()
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct HookConfig {
+    /// The path the hook is served at, eg. `/ci`
+    path: String,
+    /// The channel the messages appear in
+    channel: String,
+    #[serde(default)]
+    format: PayloadFormat,
+    /// The secret authenticating the requests - either used for an HMAC-SHA256 signature of the
+    /// body, or sent as it is, depending on the format
+    secret: Option<String>,
+    /// If set, the response carries the first message sent to the channel within this many
+    /// milliseconds
+    response_timeout: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct WebhookConfig {
+    /// The address the HTTP server listens on
+    #[serde(default = "default_address")]
+    address: String,
+    #[serde(default = "default_nick")]
+    nick: String,
+    /// The limit of the size of the request bodies, in bytes - larger ones are rejected
+    #[serde(default = "default_max_body_size")]
+    max_body_size: usize,
+    hooks: Vec<HookConfig>,
+}
+
+/// Requests waiting for a response, by channel names
+type Waiters = Arc<Mutex<HashMap<String, Vec<Sender<String>>>>>;
+
+/// A helper enum for WebhookSource
+enum SourceState {
+    Disconnected,
+    Running(Arc<Server>, JoinHandle<()>),
+}
+
+/// An event source receiving messages via HTTP requests
+pub struct WebhookSource {
+    /// the source ID
+    id: SourceId,
+    /// Webhook configuration data
+    config: WebhookConfig,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// Requests waiting for a response
+    waiters: Waiters,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl WebhookSource {
+    /// Creates a WebhookSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Webhook source {:?}!",
+            source_id
+        ));
+        let config: WebhookConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Webhook source {:?}",
+            source_id
+        ));
+
+        Box::new(WebhookSource {
+            id: source_id,
+            config,
+            sender,
+            waiters: Default::default(),
+            state: SourceState::Disconnected,
+        })
+    }
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+/// Checks a `sha256=<hex>` signature of the body
+fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature.trim_start_matches("sha256=")) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key is valid");
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Checks a token against the secret, in constant time like the signatures
+fn verify_token(secret: &str, token: &str) -> bool {
+    let mac = |data: &str| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key is valid");
+        mac.update(data.as_bytes());
+        mac
+    };
+    mac(token)
+        .verify_slice(&mac(secret).finalize().into_bytes())
+        .is_ok()
+}
+
+fn authenticate(hook: &HookConfig, request: &Request, body: &[u8]) -> bool {
+    let secret = match hook.secret {
+        Some(ref secret) => secret,
+        None => return true,
+    };
+    match hook.format {
+        PayloadFormat::Github => header(request, "X-Hub-Signature-256")
+            .is_some_and(|signature| verify_signature(secret, body, signature)),
+        PayloadFormat::Gitlab => {
+            header(request, "X-Gitlab-Token").is_some_and(|token| verify_token(secret, token))
+        }
+        PayloadFormat::Generic => match header(request, "X-Signature-256") {
+            Some(signature) => verify_signature(secret, body, signature),
+            None => {
+                header(request, "X-Webhook-Token").is_some_and(|token| verify_token(secret, token))
+            }
+        },
+    }
+}
+
+fn respond(request: Request, status: u16, body: JsonValue) {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    let _ = request.respond(response);
+}
+
+fn handle_request(
+    mut request: Request,
+    hooks: &[HookConfig],
+    max_body_size: usize,
+    waiters: &Waiters,
+    id: &SourceId,
+    sender: &Sender<SourceEvent>,
+) {
+    let path = request.url().split('?').next().unwrap_or("").to_owned();
+    let hook = match hooks.iter().find(|hook| hook.path == path) {
+        Some(hook) => hook,
+        None => return respond(request, 404, json!({ "error": "unknown hook" })),
+    };
+    if *request.method() != tiny_http::Method::Post {
+        return respond(request, 405, json!({ "error": "POST expected" }));
+    }
+    if request.body_length().is_some_and(|len| len > max_body_size) {
+        return respond(request, 413, json!({ "error": "body too large" }));
+    }
+    // the length isn't always declared up front
+    let mut body = vec![];
+    let mut reader = request.as_reader().take(max_body_size as u64 + 1);
+    if reader.read_to_end(&mut body).is_err() {
+        return respond(request, 400, json!({ "error": "unreadable body" }));
+    }
+    if body.len() > max_body_size {
+        return respond(request, 413, json!({ "error": "body too large" }));
+    }
+    if !authenticate(hook, &request, &body) {
+        return respond(request, 401, json!({ "error": "authentication failed" }));
+    }
+    let payload: JsonValue = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => return respond(request, 400, json!({ "error": e.to_string() })),
+    };
+    let event = header(&request, "X-GitHub-Event").or_else(|| header(&request, "X-Gitlab-Event"));
+    let (author, text) = match payload::to_message(hook.format, event, &payload) {
+        Ok(Some(message)) => message,
+        Ok(None) => return respond(request, 200, json!({ "ok": true })),
+        Err(e) => return respond(request, 400, json!({ "error": e })),
+    };
+
+    // the waiter has to be in place before the event can be handled
+    let response = hook.response_timeout.map(|timeout| {
+        let (tx, rx) = mpsc::channel();
+        waiters
+            .lock()
+            .unwrap()
+            .entry(hook.channel.clone())
+            .or_default()
+            .push(tx);
+        (rx, Duration::from_millis(timeout))
+    });
+    let _ = sender.send(SourceEvent {
+        source: id.clone(),
+        event: Event::ReceivedMessage(Message {
+            author,
+            channel: Channel::Channel(hook.channel.clone()),
+            content: MessageContent::Text(text),
+            is_own: false,
+        }),
+    });
+    match response {
+        Some((rx, timeout)) => {
+            let _ = thread::spawn(move || match rx.recv_timeout(timeout) {
+                Ok(text) => respond(request, 200, json!({ "ok": true, "text": text })),
+                Err(_) => respond(request, 200, json!({ "ok": true })),
+            });
+        }
+        None => respond(request, 200, json!({ "ok": true })),
+    }
+}
+
+impl EventSource for WebhookSource {
+    fn get_nick(&self) -> String {
+        self.config.nick.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        if let SourceState::Running(server, handle) =
+            ::std::mem::replace(&mut self.state, SourceState::Disconnected)
+        {
+            server.unblock();
+            let _ = handle.join();
+        }
+        let server = Server::http(&self.config.address)
+            .map_err(|e| SourceError::ConnectionError(self.id.clone(), e.to_string()))?;
+        let server = Arc::new(server);
+
+        let thread_server = server.clone();
+        let hooks = self.config.hooks.clone();
+        let max_body_size = self.config.max_body_size;
+        let waiters = self.waiters.clone();
+        let sender = self.sender.clone();
+        let id = self.id.clone();
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            for request in thread_server.incoming_requests() {
+                handle_request(request, &hooks, max_body_size, &waiters, &id, &sender);
+            }
+        });
+
+        self.state = SourceState::Running(server, handle);
+        Ok(())
+    }
+
+    /// Hooks are configured statically
+    fn join(&mut self, _channel: &str) -> SourceResult<()> {
+        Ok(())
+    }
+
+    /// Returns the message in the response to a request waiting for one on the channel
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let text = match msg {
+            MessageContent::Text(ref t) | MessageContent::Me(ref t) => t.clone(),
+            MessageContent::Embed(ref embed) => embed.to_text(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        if let Channel::Channel(ref name) = dst {
+            let mut waiters = self.waiters.lock().unwrap();
+            for waiter in waiters.get_mut(name).into_iter().flatten() {
+                // the requests that timed out have dropped their receivers
+                if waiter.send(text.clone()).is_ok() {
+                    return Ok(());
+                }
+            }
+            // every waiter has either been answered or timed out by now
+            let _ = waiters.remove(name);
+        }
+        Err(SourceError::InvalidChannel(self.id.clone(), dst))
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{expect_connected, free_port, next_message};
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn text(msg: &Message) -> &str {
+        match msg.content {
+            MessageContent::Text(ref t) => t,
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+    }
+
+    fn start_source() -> (Box<dyn EventSource>, Receiver<SourceEvent>, String) {
+        let port = free_port();
+        let config = format!(
+            "address = \"127.0.0.1:{}\"\nmax_body_size = 1024\n\
+             [[hooks]]\npath = \"/ci\"\nchannel = \"ci\"\nsecret = \"s3cret\"\n\
+             response_timeout = 5000\n\
+             [[hooks]]\npath = \"/github\"\nchannel = \"dev\"\nformat = \"github\"\n\
+             secret = \"s3cret\"",
+            port
+        );
+        let (tx, rx) = channel();
+        let mut source = WebhookSource::new(
+            SourceId("webhook".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+        expect_connected(&rx);
+        (source, rx, format!("http://127.0.0.1:{}", port))
+    }
+
+    #[test]
+    fn test_generic_with_response() {
+        let (mut source, rx, url) = start_source();
+        let request = thread::spawn(move || {
+            ureq::post(&format!("{}/ci", url))
+                .set("X-Webhook-Token", "s3cret")
+                .send_string("{\"author\": \"jenkins\", \"text\": \"build #7 failed\"}")
+                .unwrap()
+                .into_string()
+                .unwrap()
+        });
+
+        let msg = next_message(&rx);
+        assert_eq!(msg.author, "jenkins");
+        assert_eq!(msg.channel, Channel::Channel("ci".to_owned()));
+        assert_eq!(text(&msg), "build #7 failed");
+        source
+            .send(msg.channel, MessageContent::Text("on it".to_owned()))
+            .unwrap();
+        let response: JsonValue = serde_json::from_str(&request.join().unwrap()).unwrap();
+        assert_eq!(response["text"], "on it");
+    }
+
+    #[test]
+    fn test_github_signature() {
+        let (_source, rx, url) = start_source();
+        let body = "{\"ref\": \"refs/heads/master\", \"commits\": [{}, {}], \
+                    \"compare\": \"https://github.com/o/r/compare/a...b\", \
+                    \"repository\": {\"full_name\": \"o/r\"}, \"sender\": {\"login\": \"alice\"}}";
+        // returns the status code
+        let post = |signature: &str| {
+            let result = ureq::post(&format!("{}/github", url))
+                .set("X-GitHub-Event", "push")
+                .set("X-Hub-Signature-256", signature)
+                .send_string(body);
+            match result {
+                Ok(response) => response.status(),
+                Err(ureq::Error::Status(code, _)) => code,
+                Err(e) => panic!("request failed: {}", e),
+            }
+        };
+
+        assert_eq!(post("sha256=0000"), 401);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(body.as_bytes());
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(post(&signature), 200);
+
+        let msg = next_message(&rx);
+        assert_eq!(msg.author, "alice");
+        assert_eq!(msg.channel, Channel::Channel("dev".to_owned()));
+        assert_eq!(
+            text(&msg),
+            "pushed 2 commit(s) to o/r:master - https://github.com/o/r/compare/a...b"
+        );
+    }
+
+    #[test]
+    fn test_body_size_limit() {
+        let (_source, rx, url) = start_source();
+        let body = format!("{{\"text\": \"{}\"}}", "x".repeat(2000));
+        let post = || ureq::post(&format!("{}/ci", url)).set("X-Webhook-Token", "s3cret");
+        match post().send_string(&body) {
+            Err(ureq::Error::Status(413, _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // chunked, without the length known up front
+        match post().send(body.as_bytes()) {
+            Err(ureq::Error::Status(413, _)) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+}
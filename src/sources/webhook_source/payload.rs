@@ -0,0 +1,157 @@
+//! Conversion of the webhook payloads into messages
+
+use serde_json::Value;
+
+/// The format of the payloads accepted by a hook
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    /// `{"text": "...", "author": "..."}`, the author being optional
+    #[default]
+    Generic,
+    Github,
+    Gitlab,
+}
+
+/// Converts a payload into the author and the text of a message
+/// `event` is the event type given in the headers, if the format has one. `Ok(None)` means that
+/// the payload is valid, but not worth a message - like GitHub's pings.
+pub fn to_message(
+    format: PayloadFormat,
+    event: Option<&str>,
+    payload: &Value,
+) -> Result<Option<(String, String)>, String> {
+    match format {
+        PayloadFormat::Generic => {
+            let text = payload["text"]
+                .as_str()
+                .ok_or_else(|| "missing \"text\"".to_owned())?;
+            let author = payload["author"].as_str().unwrap_or("webhook");
+            Ok(Some((author.to_owned(), text.to_owned())))
+        }
+        PayloadFormat::Github => Ok(github_message(event.unwrap_or(""), payload)),
+        PayloadFormat::Gitlab => Ok(gitlab_message(event.unwrap_or(""), payload)),
+    }
+}
+
+fn str_field<'a>(value: &'a Value, pointer: &str) -> &'a str {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .unwrap_or("?")
+}
+
+/// Strips `refs/heads/` or `refs/tags/` from a git ref - branch names may contain slashes
+fn branch(git_ref: &str) -> &str {
+    ["refs/heads/", "refs/tags/"]
+        .iter()
+        .find(|prefix| git_ref.starts_with(*prefix))
+        .map_or(git_ref, |prefix| &git_ref[prefix.len()..])
+}
+
+fn github_message(event: &str, payload: &Value) -> Option<(String, String)> {
+    let sender = str_field(payload, "/sender/login").to_owned();
+    let repo = str_field(payload, "/repository/full_name");
+    let text = match event {
+        "ping" => return None,
+        "push" => format!(
+            "pushed {} commit(s) to {}:{} - {}",
+            payload["commits"].as_array().map_or(0, |c| c.len()),
+            repo,
+            branch(str_field(payload, "/ref")),
+            str_field(payload, "/compare")
+        ),
+        "pull_request" => format!(
+            "{} pull request {}#{}: {} - {}",
+            str_field(payload, "/action"),
+            repo,
+            payload["number"],
+            str_field(payload, "/pull_request/title"),
+            str_field(payload, "/pull_request/html_url")
+        ),
+        "issues" => format!(
+            "{} issue {}#{}: {} - {}",
+            str_field(payload, "/action"),
+            repo,
+            payload["issue"]["number"],
+            str_field(payload, "/issue/title"),
+            str_field(payload, "/issue/html_url")
+        ),
+        "workflow_run" => format!(
+            "workflow {} on {}:{}: {}",
+            str_field(payload, "/workflow_run/name"),
+            repo,
+            str_field(payload, "/workflow_run/head_branch"),
+            payload["workflow_run"]["conclusion"]
+                .as_str()
+                .unwrap_or_else(|| str_field(payload, "/workflow_run/status"))
+        ),
+        event => format!("{} event on {}", event, repo),
+    };
+    Some((sender, text))
+}
+
+fn gitlab_message(event: &str, payload: &Value) -> Option<(String, String)> {
+    let project = str_field(payload, "/project/path_with_namespace");
+    let (author, text) = match event {
+        "Push Hook" => (
+            str_field(payload, "/user_name"),
+            format!(
+                "pushed {} commit(s) to {}:{}",
+                payload["total_commits_count"],
+                project,
+                branch(str_field(payload, "/ref"))
+            ),
+        ),
+        "Merge Request Hook" => (
+            str_field(payload, "/user/username"),
+            format!(
+                "{} merge request {}!{}: {} - {}",
+                str_field(payload, "/object_attributes/action"),
+                project,
+                payload["object_attributes"]["iid"],
+                str_field(payload, "/object_attributes/title"),
+                str_field(payload, "/object_attributes/url")
+            ),
+        ),
+        "Issue Hook" => (
+            str_field(payload, "/user/username"),
+            format!(
+                "{} issue {}#{}: {} - {}",
+                str_field(payload, "/object_attributes/action"),
+                project,
+                payload["object_attributes"]["iid"],
+                str_field(payload, "/object_attributes/title"),
+                str_field(payload, "/object_attributes/url")
+            ),
+        ),
+        "Pipeline Hook" => (
+            str_field(payload, "/user/username"),
+            format!(
+                "pipeline #{} on {}:{}: {}",
+                payload["object_attributes"]["id"],
+                project,
+                str_field(payload, "/object_attributes/ref"),
+                str_field(payload, "/object_attributes/status")
+            ),
+        ),
+        event => (
+            str_field(payload, "/user/username"),
+            format!("{} on {}", event, project),
+        ),
+    };
+    Some((author.to_owned(), text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_branch() {
+        assert_eq!(branch("refs/heads/master"), "master");
+        assert_eq!(branch("refs/heads/feature/login"), "feature/login");
+        assert_eq!(branch("refs/tags/v1.0"), "v1.0");
+        assert_eq!(branch("release/2.x"), "release/2.x");
+    }
+}
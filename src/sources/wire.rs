@@ -0,0 +1,201 @@
+//! JSON representations of the core types, shared by the sources talking to external programs
+//!
+//! Channels are written as `{"channel": "name"}`, `{"user": "name"}`, `{"group": ["a", "b"]}`,
+//! `{"thread": ["channel", "topic"]}` or `null`, and message contents as `{"text": "..."}`,
+//! `{"me": "..."}` or `{"embed": {"title": ..., "description": ..., "fields": [...], ...}}`.
+//! Events are tagged with a `type` field:
+//! - `{"type": "message", "author": ..., "channel": ..., "content": ...}`
+//! - `{"type": "edited", "author": ..., "channel": ..., "content": ...}`
+//! - `{"type": "reaction", "author": ..., "channel": ..., "emoji": ..., "removed": false}`
+//! - `{"type": "online", "user": ...}`, `{"type": "offline", "user": ..., "reason": ...}`
+//! - `{"type": "typing", "user": ...}`, `{"type": "nick", "old": ..., "new": ...}`
+//! - `{"type": "other", "text": ...}`
+
+use crate::core::*;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireChannel {
+    Channel(String),
+    User(String),
+    Group(Vec<String>),
+    Thread(String, String),
+}
+
+pub fn channel_from_wire(channel: Option<WireChannel>) -> Channel {
+    match channel {
+        None => Channel::None,
+        Some(WireChannel::Channel(name)) => Channel::Channel(name),
+        Some(WireChannel::User(name)) => Channel::User(name),
+        Some(WireChannel::Group(names)) => Channel::Group(names),
+        Some(WireChannel::Thread(channel, thread)) => Channel::Thread(channel, thread),
+    }
+}
+
+pub fn channel_to_wire(channel: Channel) -> Option<WireChannel> {
+    match channel {
+        Channel::None => None,
+        Channel::Channel(name) => Some(WireChannel::Channel(name)),
+        Channel::User(name) => Some(WireChannel::User(name)),
+        Channel::Group(names) => Some(WireChannel::Group(names)),
+        Channel::Thread(channel, thread) => Some(WireChannel::Thread(channel, thread)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WireEmbedField {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WireEmbed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<WireEmbedField>,
+    pub colour: Option<u32>,
+    pub footer: Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireContent {
+    Text(String),
+    Me(String),
+    Embed(WireEmbed),
+}
+
+pub fn content_from_wire(content: WireContent) -> MessageContent {
+    match content {
+        WireContent::Text(text) => MessageContent::Text(text),
+        WireContent::Me(text) => MessageContent::Me(text),
+        WireContent::Embed(embed) => MessageContent::Embed(Embed {
+            title: embed.title,
+            description: embed.description,
+            fields: embed
+                .fields
+                .into_iter()
+                .map(|field| EmbedField {
+                    name: field.name,
+                    value: field.value,
+                    inline: field.inline,
+                })
+                .collect(),
+            colour: embed.colour,
+            footer: embed.footer,
+            thumbnail_url: embed.thumbnail_url,
+        }),
+    }
+}
+
+/// Converts the content into its wire form - images can't be sent, so they give `None`
+pub fn content_to_wire(content: MessageContent) -> Option<WireContent> {
+    match content {
+        MessageContent::Text(text) => Some(WireContent::Text(text)),
+        MessageContent::Me(text) => Some(WireContent::Me(text)),
+        MessageContent::Image => None,
+        MessageContent::Embed(embed) => Some(WireContent::Embed(WireEmbed {
+            title: embed.title,
+            description: embed.description,
+            fields: embed
+                .fields
+                .into_iter()
+                .map(|field| WireEmbedField {
+                    name: field.name,
+                    value: field.value,
+                    inline: field.inline,
+                })
+                .collect(),
+            colour: embed.colour,
+            footer: embed.footer,
+            thumbnail_url: embed.thumbnail_url,
+        })),
+    }
+}
+
+/// An event sent by a client
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WireEvent {
+    Message {
+        author: String,
+        channel: Option<WireChannel>,
+        content: WireContent,
+    },
+    Edited {
+        author: String,
+        channel: Option<WireChannel>,
+        content: WireContent,
+    },
+    Reaction {
+        author: String,
+        channel: Option<WireChannel>,
+        emoji: String,
+        #[serde(default)]
+        removed: bool,
+    },
+    Online {
+        user: String,
+    },
+    Offline {
+        user: String,
+        reason: Option<String>,
+    },
+    Typing {
+        user: String,
+    },
+    Nick {
+        old: String,
+        new: String,
+    },
+    Other {
+        text: String,
+    },
+}
+
+impl WireEvent {
+    pub fn into_event(self) -> Event {
+        match self {
+            WireEvent::Message {
+                author,
+                channel,
+                content,
+            } => Event::ReceivedMessage(Message {
+                author,
+                channel: channel_from_wire(channel),
+                content: content_from_wire(content),
+                is_own: false,
+            }),
+            WireEvent::Edited {
+                author,
+                channel,
+                content,
+            } => Event::EditedMessage(Message {
+                author,
+                channel: channel_from_wire(channel),
+                content: content_from_wire(content),
+                is_own: false,
+            }),
+            WireEvent::Reaction {
+                author,
+                channel,
+                emoji,
+                removed,
+            } => Event::Reaction(Reaction {
+                author,
+                channel: channel_from_wire(channel),
+                emoji,
+                removed,
+            }),
+            WireEvent::Online { user } => Event::UserOnline(user),
+            WireEvent::Offline { user, reason } => Event::UserOffline(user, reason),
+            WireEvent::Typing { user } => Event::UserTyping(user),
+            WireEvent::Nick { old, new } => Event::NickChange(old, new),
+            WireEvent::Other { text } => Event::Other(text),
+        }
+    }
+}
@@ -0,0 +1,644 @@
+mod xml;
+
+use self::xml::{escape, Element, SharedStream, StanzaReader};
+use crate::core::*;
+use crate::sources::*;
+use native_tls::{HandshakeError, TlsConnector};
+use std::mem;
+use std::net::TcpStream;
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toml::Value;
+
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_MUC: &str = "http://jabber.org/protocol/muc";
+
+fn default_port() -> u16 {
+    5222
+}
+
+fn default_resource() -> String {
+    "universal-chat".to_owned()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct XmppConfig {
+    /// The bare JID of the bot, eg. `bot@example.org`
+    jid: String,
+    password: String,
+    /// The host to connect to; the domain of the JID by default
+    server: Option<String>,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default = "default_resource")]
+    resource: String,
+    /// The nick used in multi-user chats; the local part of the JID by default
+    nick: Option<String>,
+    /// Multi-user chat rooms to join when connecting, eg. `room@conference.example.org`
+    #[serde(default)]
+    rooms: Vec<String>,
+    /// Whether to refuse to log in if the server doesn't offer STARTTLS
+    #[serde(default = "default_true")]
+    require_tls: bool,
+}
+
+impl XmppConfig {
+    fn local_part(&self) -> &str {
+        self.jid.split('@').next().unwrap_or("")
+    }
+
+    fn domain(&self) -> &str {
+        self.jid.split('@').nth(1).unwrap_or(&self.jid)
+    }
+
+    fn nick(&self) -> String {
+        self.nick
+            .clone()
+            .unwrap_or_else(|| self.local_part().to_owned())
+    }
+}
+
+/// A helper enum for XmppSource
+enum SourceState {
+    Disconnected,
+    Connected(SharedStream, JoinHandle<()>),
+}
+
+/// An XMPP event source
+pub struct XmppSource {
+    /// the source ID
+    id: SourceId,
+    /// XMPP client configuration data
+    config: XmppConfig,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl XmppSource {
+    /// Creates an XmppSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!("No config given for XMPP source {:?}!", source_id));
+        let config: XmppConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to XMPP source {:?}",
+            source_id
+        ));
+
+        Box::new(XmppSource {
+            id: source_id,
+            config,
+            sender,
+            state: SourceState::Disconnected,
+        })
+    }
+
+    fn error<T: ToString>(&self, err: T) -> SourceError {
+        SourceError::ConnectionError(self.id.clone(), err.to_string())
+    }
+
+    fn send_raw(&self, data: &str) -> SourceResult<()> {
+        match self.state {
+            SourceState::Connected(ref stream, _) => stream.send(data).map_err(|e| self.error(e)),
+            SourceState::Disconnected => Err(SourceError::Disconnected(self.id.clone())),
+        }
+    }
+
+    /// Opens an XML stream and returns the stream features offered by the server
+    fn open_stream(&self, stream: &SharedStream) -> SourceResult<(StanzaReader, Element)> {
+        stream
+            .send(&format!(
+                "<?xml version='1.0'?><stream:stream to='{}' version='1.0' xmlns='jabber:client' \
+                 xmlns:stream='http://etherx.jabber.org/streams'>",
+                escape(self.config.domain())
+            ))
+            .map_err(|e| self.error(e))?;
+        let mut reader = StanzaReader::new(stream.clone());
+        let features = self.expect_stanza(&mut reader, "features")?;
+        Ok((reader, features))
+    }
+
+    fn expect_stanza(&self, reader: &mut StanzaReader, name: &str) -> SourceResult<Element> {
+        match reader.next_stanza().map_err(|e| self.error(e))? {
+            Some(ref stanza) if stanza.name == name => Ok(stanza.clone()),
+            Some(stanza) => Err(self.error(format!("expected <{}>, got {:?}", name, stanza))),
+            None => Err(SourceError::Disconnected(self.id.clone())),
+        }
+    }
+
+    /// Upgrades the connection to TLS
+    fn start_tls(&self, reader: StanzaReader, stream: SharedStream) -> SourceResult<SharedStream> {
+        let mut reader = reader;
+        stream
+            .send(&format!("<starttls xmlns='{}'/>", NS_TLS))
+            .map_err(|e| self.error(e))?;
+        let _ = self.expect_stanza(&mut reader, "proceed")?;
+        drop(reader);
+        let stream = stream
+            .into_inner()
+            .ok_or_else(|| self.error("the stream is still in use"))?;
+        let connector = TlsConnector::new().map_err(|e| self.error(e))?;
+        let mut result = connector.connect(self.config.domain(), stream);
+        loop {
+            match result {
+                Ok(tls) => return Ok(SharedStream::new(Box::new(tls))),
+                // the socket has a read timeout, so the handshake can be interrupted
+                Err(HandshakeError::WouldBlock(mid)) => result = mid.handshake(),
+                Err(HandshakeError::Failure(e)) => return Err(self.error(e)),
+            }
+        }
+    }
+
+    /// Connects to the server and logs in, returning the stream and a reader positioned after
+    /// resource binding
+    fn login(&self) -> SourceResult<(SharedStream, StanzaReader)> {
+        let host = self
+            .config
+            .server
+            .clone()
+            .unwrap_or_else(|| self.config.domain().to_owned());
+        let tcp =
+            TcpStream::connect((&host as &str, self.config.port)).map_err(|e| self.error(e))?;
+        tcp.set_read_timeout(Some(Duration::from_millis(100)))
+            .map_err(|e| self.error(e))?;
+        let mut stream = SharedStream::new(Box::new(tcp));
+
+        let (mut reader, mut features) = self.open_stream(&stream)?;
+        if features.child("starttls").is_some() {
+            stream = self.start_tls(reader, stream)?;
+            let (new_reader, new_features) = self.open_stream(&stream)?;
+            reader = new_reader;
+            features = new_features;
+        } else if self.config.require_tls {
+            return Err(self.error("the server doesn't support STARTTLS"));
+        }
+
+        let supports_plain = features
+            .child("mechanisms")
+            .map(|mechs| mechs.children.iter().any(|mech| mech.text == "PLAIN"))
+            .unwrap_or(false);
+        if !supports_plain {
+            return Err(self.error("the server doesn't support SASL PLAIN"));
+        }
+        let credentials = format!("\0{}\0{}", self.config.local_part(), self.config.password);
+        stream
+            .send(&format!(
+                "<auth xmlns='{}' mechanism='PLAIN'>{}</auth>",
+                NS_SASL,
+                base64::encode(&credentials)
+            ))
+            .map_err(|e| self.error(e))?;
+        let _ = self
+            .expect_stanza(&mut reader, "success")
+            .map_err(|_| self.error("authentication failed"))?;
+        drop(reader);
+
+        let (mut reader, _) = self.open_stream(&stream)?;
+        stream
+            .send(&format!(
+                "<iq type='set' id='bind'><bind xmlns='{}'><resource>{}</resource></bind></iq>",
+                NS_BIND,
+                escape(&self.config.resource)
+            ))
+            .map_err(|e| self.error(e))?;
+        let bind = self.expect_stanza(&mut reader, "iq")?;
+        if bind.attr("type") != Some("result") {
+            return Err(self.error(format!("resource binding failed: {:?}", bind)));
+        }
+        stream.send("<presence/>").map_err(|e| self.error(e))?;
+        Ok((stream, reader))
+    }
+}
+
+/// Splits a JID into the bare JID and the resource
+fn split_jid(jid: &str) -> (&str, &str) {
+    match jid.find('/') {
+        Some(pos) => (&jid[..pos], &jid[pos + 1..]),
+        None => (jid, ""),
+    }
+}
+
+/// Converts a message body into content, taking /me into account
+fn body_to_content(body: &str) -> MessageContent {
+    if body.starts_with("/me ") {
+        MessageContent::Me(body[4..].to_owned())
+    } else {
+        MessageContent::Text(body.to_owned())
+    }
+}
+
+/// Translates a stanza into events, returning the stanza that should be sent in reply, if any
+fn stanza_to_events(stanza: &Element, own_jid: &str, nick: &str) -> (Vec<Event>, Option<String>) {
+    let from = stanza.attr("from").unwrap_or("");
+    let (bare, resource) = split_jid(from);
+    match &stanza.name as &str {
+        "message" => {
+            // messages with a delay are history replayed on joining a room
+            if stanza.child("delay").is_some() {
+                return (vec![], None);
+            }
+            // bounces are only logged, so that the modules don't answer them
+            if stanza.attr("type") == Some("error") {
+                let condition = stanza
+                    .child("error")
+                    .and_then(|error| error.children.first())
+                    .map_or("unknown error", |condition| &condition.name as &str);
+                let text = format!("Message from {} bounced: {}", from, condition);
+                return (vec![Event::Other(text)], None);
+            }
+            let body = match stanza.child("body") {
+                Some(body) => body_to_content(&body.text),
+                None => return (vec![], None),
+            };
+            let msg = if stanza.attr("type") == Some("groupchat") {
+                Message {
+                    author: resource.to_owned(),
+                    channel: Channel::Channel(bare.to_owned()),
+                    content: body,
+                    is_own: resource == nick,
+                }
+            } else {
+                Message {
+                    author: bare.to_owned(),
+                    channel: Channel::User(bare.to_owned()),
+                    content: body,
+                    is_own: bare == own_jid,
+                }
+            };
+            (vec![Event::ReceivedMessage(msg)], None)
+        }
+        "presence" => {
+            let is_muc =
+                stanza.children.iter().any(|child| child.name == "x") && !resource.is_empty();
+            let who = if is_muc { resource } else { bare };
+            let event = match stanza.attr("type") {
+                Some("unavailable") => Event::UserOffline(
+                    who.to_owned(),
+                    stanza.child("status").map(|status| status.text.clone()),
+                ),
+                None => Event::UserOnline(who.to_owned()),
+                Some(_) => return (vec![], None),
+            };
+            (vec![event], None)
+        }
+        "iq" => {
+            let id = escape(stanza.attr("id").unwrap_or(""));
+            let reply = match stanza.attr("type") {
+                Some("get") if stanza.child("ping").is_some() => {
+                    format!("<iq type='result' id='{}' to='{}'/>", id, escape(from))
+                }
+                // requests have to be answered, even if only to say they aren't supported
+                Some("get") | Some("set") => format!(
+                    "<iq type='error' id='{}' to='{}'><error type='cancel'>\
+                     <service-unavailable xmlns='urn:ietf:params:xml:ns:xmpp-stanzas'/>\
+                     </error></iq>",
+                    id,
+                    escape(from)
+                ),
+                _ => return (vec![], None),
+            };
+            (vec![], Some(reply))
+        }
+        // nothing else is of interest
+        _ => (vec![], None),
+    }
+}
+
+impl EventSource for XmppSource {
+    fn get_nick(&self) -> String {
+        self.config.nick()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        let (stream, mut reader) = self.login()?;
+
+        let thread_stream = stream.clone();
+        let sender = self.sender.clone();
+        let id = self.id.clone();
+        let own_jid = self.config.jid.clone();
+        let nick = self.config.nick();
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            let reason = loop {
+                let stanza = match reader.next_stanza() {
+                    Ok(Some(stanza)) => stanza,
+                    Ok(None) => break "stream closed".to_owned(),
+                    Err(e) => break e,
+                };
+                let (events, reply) = stanza_to_events(&stanza, &own_jid, &nick);
+                if let Some(reply) = reply {
+                    let _ = thread_stream.send(&reply);
+                }
+                for event in events {
+                    let _ = sender.send(SourceEvent {
+                        source: id.clone(),
+                        event,
+                    });
+                }
+            };
+            let _ = sender.send(SourceEvent {
+                source: id,
+                event: Event::Disconnected(reason),
+            });
+        });
+
+        self.state = SourceState::Connected(stream, handle);
+        for room in self.config.rooms.clone() {
+            self.join(&room)?;
+        }
+        Ok(())
+    }
+
+    /// Joins a multi-user chat room
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        self.send_raw(&format!(
+            "<presence to='{}/{}'><x xmlns='{}'><history maxstanzas='0'/></x></presence>",
+            escape(channel),
+            escape(&self.config.nick()),
+            NS_MUC
+        ))
+    }
+
+    /// Sends a message to a multi-user chat room or a user
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        let (to, msg_type) = match dst {
+            Channel::Channel(ref room) => (room, "groupchat"),
+            Channel::User(ref user) => (user, "chat"),
+            _ => return Err(SourceError::InvalidChannel(self.id.clone(), dst)),
+        };
+        let body = match msg {
+            MessageContent::Text(ref t) => t.clone(),
+            MessageContent::Me(ref t) => format!("/me {}", t),
+            MessageContent::Embed(ref embed) => embed.to_text(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        self.send_raw(&format!(
+            "<message to='{}' type='{}'><body>{}</body></message>",
+            escape(to),
+            msg_type,
+            escape(&body)
+        ))
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        // the thread of the previous connection ends after reporting the disconnection
+        let state = mem::replace(&mut self.state, SourceState::Disconnected);
+        if let SourceState::Connected(_, handle) = state {
+            let _ = handle.join();
+        }
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{accept_one, expect_connected, next_event, next_message};
+    use std::io::{Read, Write};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    const STREAM_HEADER: &str = "<?xml version='1.0'?><stream:stream from='localhost' \
+                                 id='s1' version='1.0' xmlns='jabber:client' \
+                                 xmlns:stream='http://etherx.jabber.org/streams'>";
+
+    /// A scripted XMPP server: it waits for the client's stanzas and replies with canned ones
+    struct StubServer {
+        stream: TcpStream,
+        received: Arc<Mutex<String>>,
+        pos: usize,
+    }
+
+    impl StubServer {
+        fn wait_for(&mut self, pattern: &str) -> String {
+            loop {
+                {
+                    let received = self.received.lock().unwrap();
+                    if let Some(found) = received[self.pos..].find(pattern) {
+                        let end = self.pos + found + pattern.len();
+                        let result = received[self.pos..end].to_owned();
+                        self.pos = end;
+                        return result;
+                    }
+                }
+                let mut buf = [0; 1024];
+                let n = self.stream.read(&mut buf).unwrap();
+                assert!(n > 0, "the client disconnected");
+                self.received
+                    .lock()
+                    .unwrap()
+                    .push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        }
+
+        fn send(&mut self, data: &str) {
+            self.stream.write_all(data.as_bytes()).unwrap();
+        }
+    }
+
+    fn start_server() -> (u16, Arc<Mutex<String>>) {
+        let received = Arc::new(Mutex::new(String::new()));
+        let thread_received = received.clone();
+        let address = accept_one(move |stream| {
+            let mut server = StubServer {
+                stream,
+                received: thread_received,
+                pos: 0,
+            };
+            let _ = server.wait_for("<stream:stream");
+            server.send(STREAM_HEADER);
+            server.send(
+                "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>\
+                 <mechanism>PLAIN</mechanism></mechanisms></stream:features>",
+            );
+            let auth = server.wait_for("</auth>");
+            assert!(auth.contains(&base64::encode("\0bot\0secret")));
+            server.send("<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>");
+            let _ = server.wait_for("<stream:stream");
+            server.send(STREAM_HEADER);
+            server.send(
+                "<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/>\
+                 </stream:features>",
+            );
+            let _ = server.wait_for("</iq>");
+            server.send(
+                "<iq type='result' id='bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+                 <jid>bot@localhost/universal-chat</jid></bind></iq>",
+            );
+            let _ = server.wait_for("<presence to='room@conference.localhost/bot'>");
+            server.send(
+                "<presence from='room@conference.localhost/alice'>\
+                 <x xmlns='http://jabber.org/protocol/muc#user'/></presence>\
+                 <message from='room@conference.localhost/alice' type='groupchat'>\
+                 <body>old news</body><delay xmlns='urn:xmpp:delay'/></message>\
+                 <message from='room@conference.localhost/alice' type='groupchat'>\
+                 <body>hi &amp; welcome</body></message>\
+                 <message from='room@conference.localhost/bot' type='groupchat'>\
+                 <body>/me waves</body></message>\
+                 <message from='carol@localhost/phone' type='chat'><body>psst</body></message>\
+                 <presence from='room@conference.localhost/alice' type='unavailable'>\
+                 <x xmlns='http://jabber.org/protocol/muc#user'/><status>bye</status>\
+                 </presence>",
+            );
+            // keep reading, so that the test can inspect what the client sends
+            loop {
+                let _ = server.wait_for(">");
+            }
+        });
+        (address.port(), received)
+    }
+
+    #[test]
+    fn test_session() {
+        let (port, received) = start_server();
+        let config = format!(
+            "jid = \"bot@localhost\"\npassword = \"secret\"\nserver = \"127.0.0.1\"\n\
+             port = {}\nrooms = [\"room@conference.localhost\"]\nrequire_tls = false",
+            port
+        );
+        let (tx, rx) = channel();
+        let mut source = XmppSource::new(
+            SourceId("xmpp".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+
+        expect_connected(&rx);
+        match next_event(&rx) {
+            Event::UserOnline(ref nick) => assert_eq!(nick, "alice"),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        let msg = next_message(&rx);
+        assert_eq!(msg.author, "alice");
+        assert_eq!(
+            msg.channel,
+            Channel::Channel("room@conference.localhost".to_owned())
+        );
+        assert!(!msg.is_own);
+        match msg.content {
+            MessageContent::Text(ref t) => assert_eq!(t, "hi & welcome"),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+        let msg = next_message(&rx);
+        assert!(msg.is_own);
+        match msg.content {
+            MessageContent::Me(ref t) => assert_eq!(t, "waves"),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+        let msg = next_message(&rx);
+        assert_eq!(msg.author, "carol@localhost");
+        assert_eq!(msg.channel, Channel::User("carol@localhost".to_owned()));
+        match next_event(&rx) {
+            Event::UserOffline(ref nick, ref status) => {
+                assert_eq!(nick, "alice");
+                assert_eq!(status.as_ref().map(|s| s as &str), Some("bye"));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        source
+            .send(
+                Channel::Channel("room@conference.localhost".to_owned()),
+                MessageContent::Me("nods <3".to_owned()),
+            )
+            .unwrap();
+        source
+            .send(
+                Channel::User("carol@localhost".to_owned()),
+                MessageContent::Text("hello".to_owned()),
+            )
+            .unwrap();
+        let expected = [
+            "<message to='room@conference.localhost' type='groupchat'>\
+             <body>/me nods &lt;3</body></message>",
+            "<message to='carol@localhost' type='chat'><body>hello</body></message>",
+        ];
+        for _ in 0..50 {
+            let received = received.lock().unwrap().clone();
+            if expected.iter().all(|msg| received.contains(msg)) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("messages not received: {}", received.lock().unwrap());
+    }
+
+    #[test]
+    fn test_iq_requests() {
+        let iq = |iq_type: &str| Element {
+            name: "iq".to_owned(),
+            attrs: vec![
+                ("type".to_owned(), iq_type.to_owned()),
+                ("id".to_owned(), "q1".to_owned()),
+                ("from".to_owned(), "localhost".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            ..Element::default()
+        };
+        let (events, reply) = stanza_to_events(&iq("set"), "bot@localhost", "bot");
+        assert!(events.is_empty());
+        assert!(reply.unwrap().contains("<service-unavailable "));
+        let (events, reply) = stanza_to_events(&iq("result"), "bot@localhost", "bot");
+        assert!(events.is_empty() && reply.is_none());
+        let unknown = Element {
+            name: "r".to_owned(),
+            ..Element::default()
+        };
+        let (events, reply) = stanza_to_events(&unknown, "bot@localhost", "bot");
+        assert!(events.is_empty() && reply.is_none());
+    }
+
+    #[test]
+    fn test_bounced_message() {
+        let bounce = Element {
+            name: "message".to_owned(),
+            attrs: vec![
+                ("type".to_owned(), "error".to_owned()),
+                ("from".to_owned(), "room@conference.localhost".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            children: vec![
+                Element {
+                    name: "body".to_owned(),
+                    text: "hi".to_owned(),
+                    ..Element::default()
+                },
+                Element {
+                    name: "error".to_owned(),
+                    children: vec![Element {
+                        name: "forbidden".to_owned(),
+                        ..Element::default()
+                    }],
+                    ..Element::default()
+                },
+            ],
+            ..Element::default()
+        };
+        let (events, reply) = stanza_to_events(&bounce, "bot@localhost", "bot");
+        assert!(reply.is_none());
+        match events.as_slice() {
+            [Event::Other(text)] => assert_eq!(
+                text,
+                "Message from room@conference.localhost bounced: forbidden"
+            ),
+            events => panic!("unexpected events: {:?}", events),
+        }
+    }
+}
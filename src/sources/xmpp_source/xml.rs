@@ -0,0 +1,143 @@
+//! A minimal stanza-level view of an XMPP XML stream
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use xml::reader::{EventReader, XmlEvent};
+
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A connection shared between the reading thread and the writers
+/// The underlying socket must have a read timeout set, so that the reader releases the lock
+/// regularly and lets the writers in.
+#[derive(Clone)]
+pub struct SharedStream(Arc<Mutex<Box<dyn Stream>>>);
+
+impl SharedStream {
+    pub fn new(stream: Box<dyn Stream>) -> Self {
+        SharedStream(Arc::new(Mutex::new(stream)))
+    }
+
+    /// Unwraps the underlying stream, which only succeeds if there are no other references to it
+    pub fn into_inner(self) -> Option<Box<dyn Stream>> {
+        Arc::try_unwrap(self.0)
+            .ok()
+            .and_then(|mutex| mutex.into_inner().ok())
+    }
+
+    pub fn send(&self, data: &str) -> io::Result<()> {
+        let mut stream = self.0.lock().unwrap();
+        stream.write_all(data.as_bytes())?;
+        stream.flush()
+    }
+}
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let result = self.0.lock().unwrap().read(buf);
+            match result {
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A parsed XML element
+#[derive(Clone, Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attrs: HashMap<String, String>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(|value| value as &str)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+/// Reads top-level elements (stanzas) of an XML stream
+pub struct StanzaReader {
+    reader: EventReader<SharedStream>,
+}
+
+impl StanzaReader {
+    pub fn new(stream: SharedStream) -> Self {
+        StanzaReader {
+            reader: EventReader::new(stream),
+        }
+    }
+
+    /// Reads the next stanza; returns `None` when the stream is closed
+    pub fn next_stanza(&mut self) -> Result<Option<Element>, String> {
+        let mut stack: Vec<Element> = vec![];
+        loop {
+            match self.reader.next().map_err(|e| e.to_string())? {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => {
+                    let element = Element {
+                        name: name.local_name,
+                        attrs: attributes
+                            .into_iter()
+                            .map(|attr| (attr.name.local_name, attr.value))
+                            .collect(),
+                        ..Default::default()
+                    };
+                    // the root element of the stream is not a stanza
+                    if element.name != "stream" {
+                        stack.push(element);
+                    }
+                }
+                XmlEvent::EndElement { .. } => {
+                    let element = match stack.pop() {
+                        Some(element) => element,
+                        None => return Ok(None),
+                    };
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(Some(element)),
+                    }
+                }
+                XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(&text);
+                    }
+                }
+                XmlEvent::EndDocument => return Ok(None),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Escapes text so that it can be put into XML character data or attribute values
+pub fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '\'' => result.push_str("&apos;"),
+            '"' => result.push_str("&quot;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
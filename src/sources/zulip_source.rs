@@ -0,0 +1,428 @@
+use crate::core::*;
+use crate::sources::http;
+use crate::sources::*;
+use serde_json::{json, Value as JsonValue};
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toml::Value;
+
+/// The prefix of the REST API endpoints
+const API: &str = "/api/v1";
+
+fn default_topic() -> String {
+    "general".to_owned()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ZulipConfig {
+    /// The URL of the organization, eg. `https://example.zulipchat.com`
+    site: String,
+    /// The email address and the API key of the bot
+    email: String,
+    api_key: String,
+    /// Streams to subscribe to when connecting
+    #[serde(default)]
+    streams: Vec<String>,
+    /// The topic of the messages sent to a stream without one
+    #[serde(default = "default_topic")]
+    default_topic: String,
+}
+
+/// A helper enum for ZulipSource
+enum SourceState {
+    Disconnected,
+    Running(JoinHandle<()>),
+}
+
+/// Data shared between the source and the polling thread
+#[derive(Default)]
+struct ZulipData {
+    full_name: String,
+    queue_id: String,
+    last_event_id: i64,
+}
+
+/// A client of the REST API, shared by the source and the polling thread
+#[derive(Clone)]
+struct ZulipClient {
+    id: SourceId,
+    config: ZulipConfig,
+    agent: ureq::Agent,
+    data: Arc<RwLock<ZulipData>>,
+}
+
+impl ZulipClient {
+    fn new(id: SourceId, config: ZulipConfig) -> Self {
+        ZulipClient {
+            id,
+            config,
+            // the server sends a heartbeat event at least every minute or so
+            agent: http::agent(Duration::from_secs(120)),
+            data: Default::default(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let url = format!("{}{}{}", self.config.site, API, path);
+        let credentials = format!("{}:{}", self.config.email, self.config.api_key);
+        self.agent.request(method, &url).set(
+            "Authorization",
+            &format!("Basic {}", base64::encode(&credentials)),
+        )
+    }
+
+    /// Checks the result of an API call
+    fn check(&self, resp: JsonValue) -> SourceResult<JsonValue> {
+        if resp["result"] != "success" {
+            return Err(SourceError::ConnectionError(
+                self.id.clone(),
+                format!("API call failed: {}", resp["msg"]),
+            ));
+        }
+        Ok(resp)
+    }
+
+    fn call(&self, method: &str, path: &str, form: &[(&str, &str)]) -> SourceResult<JsonValue> {
+        let resp = if method == "GET" {
+            let request = form
+                .iter()
+                .fold(self.request(method, path), |req, &(k, v)| req.query(k, v));
+            http::call(&self.id, request, None)?
+        } else {
+            http::call_form(&self.id, self.request(method, path), form)?
+        };
+        self.check(resp)
+    }
+
+    fn get_profile(&self) -> SourceResult<()> {
+        let me = self.call("GET", "/users/me", &[])?;
+        self.data.write().unwrap().full_name = me["full_name"].as_str().unwrap_or("").to_owned();
+        Ok(())
+    }
+
+    fn subscribe(&self, stream: &str) -> SourceResult<()> {
+        let subscriptions = json!([{ "name": stream }]).to_string();
+        let _ = self.call(
+            "POST",
+            "/users/me/subscriptions",
+            &[("subscriptions", &subscriptions)],
+        )?;
+        Ok(())
+    }
+
+    /// Registers an event queue for the messages
+    fn register(&self) -> SourceResult<()> {
+        let resp = self.call(
+            "POST",
+            "/register",
+            &[
+                ("event_types", "[\"message\"]"),
+                ("apply_markdown", "false"),
+            ],
+        )?;
+        let mut data = self.data.write().unwrap();
+        data.queue_id = resp["queue_id"].as_str().unwrap_or("").to_owned();
+        data.last_event_id = resp["last_event_id"].as_i64().unwrap_or(-1);
+        Ok(())
+    }
+
+    /// Long-polls the event queue and returns the received events
+    fn get_events(&self) -> SourceResult<Vec<Event>> {
+        let (queue_id, last_event_id) = {
+            let data = self.data.read().unwrap();
+            (data.queue_id.clone(), data.last_event_id.to_string())
+        };
+        let resp = self.call(
+            "GET",
+            "/events",
+            &[("queue_id", &queue_id), ("last_event_id", &last_event_id)],
+        )?;
+        let mut events = vec![];
+        for event in resp["events"].as_array().into_iter().flatten() {
+            if let Some(id) = event["id"].as_i64() {
+                let mut data = self.data.write().unwrap();
+                data.last_event_id = data.last_event_id.max(id);
+            }
+            if event["type"] == "message" {
+                if let Some(msg) = self.message(&event["message"]) {
+                    events.push(Event::ReceivedMessage(msg));
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    fn message(&self, msg: &JsonValue) -> Option<Message> {
+        let author = msg["sender_email"].as_str()?.to_owned();
+        let channel = if msg["type"] == "stream" {
+            Channel::Thread(
+                msg["display_recipient"].as_str()?.to_owned(),
+                msg["subject"].as_str()?.to_owned(),
+            )
+        } else {
+            // private messages list all the participants, including the bot
+            let users: Vec<String> = msg["display_recipient"]
+                .as_array()?
+                .iter()
+                .filter_map(|user| user["email"].as_str())
+                .filter(|&email| email != self.config.email)
+                .map(|email| email.to_owned())
+                .collect();
+            match users.len() {
+                0 => Channel::User(author.clone()),
+                1 => Channel::User(users[0].clone()),
+                _ => Channel::Group(users),
+            }
+        };
+        let content = msg["content"].as_str()?;
+        let content = if content.starts_with("/me ") {
+            MessageContent::Me(content[4..].to_owned())
+        } else {
+            MessageContent::Text(content.to_owned())
+        };
+        Some(Message {
+            is_own: author == self.config.email,
+            author,
+            channel,
+            content,
+        })
+    }
+
+    fn send(&self, dst: &Channel, content: &str) -> SourceResult<()> {
+        let (kind, to, topic) = match *dst {
+            Channel::Channel(ref stream) => {
+                ("stream", stream.clone(), Some(&self.config.default_topic))
+            }
+            Channel::Thread(ref stream, ref topic) => ("stream", stream.clone(), Some(topic)),
+            Channel::User(ref user) => ("private", json!([user]).to_string(), None),
+            Channel::Group(ref users) => ("private", json!(users).to_string(), None),
+            _ => return Err(SourceError::InvalidChannel(self.id.clone(), dst.clone())),
+        };
+        let mut form = vec![("type", kind), ("to", &to), ("content", content)];
+        if let Some(topic) = topic {
+            form.push(("topic", topic));
+        }
+        let _ = self.call("POST", "/messages", &form)?;
+        Ok(())
+    }
+}
+
+/// A Zulip event source
+pub struct ZulipSource {
+    /// the source ID
+    id: SourceId,
+    /// Event sender
+    sender: Sender<SourceEvent>,
+    /// API client, shared with the polling thread
+    client: ZulipClient,
+    /// Current state of the source
+    state: SourceState,
+}
+
+impl ZulipSource {
+    /// Creates a ZulipSource with the given configuration
+    pub fn new(
+        source_id: SourceId,
+        sender: Sender<SourceEvent>,
+        config: Option<Value>,
+    ) -> Box<dyn EventSource> {
+        let config = config.expect(&format!(
+            "No config given for Zulip source {:?}!",
+            source_id
+        ));
+        let config: ZulipConfig = config.try_into().ok().expect(&format!(
+            "Invalid configuration supplied to Zulip source {:?}",
+            source_id
+        ));
+
+        Box::new(ZulipSource {
+            id: source_id.clone(),
+            sender,
+            client: ZulipClient::new(source_id, config),
+            state: SourceState::Disconnected,
+        })
+    }
+}
+
+impl EventSource for ZulipSource {
+    fn get_nick(&self) -> String {
+        self.client.data.read().unwrap().full_name.clone()
+    }
+
+    fn connect(&mut self) -> SourceResult<()> {
+        self.client.get_profile()?;
+        for stream in &self.client.config.streams {
+            self.client.subscribe(stream)?;
+        }
+        self.client.register()?;
+
+        let client = self.client.clone();
+        let sender = self.sender.clone();
+        let id = self.id.clone();
+        let handle = thread::spawn(move || {
+            let _ = sender.send(SourceEvent {
+                source: id.clone(),
+                event: Event::Connected,
+            });
+            loop {
+                let events = match client.get_events() {
+                    Ok(events) => events,
+                    Err(e) => {
+                        let _ = sender.send(SourceEvent {
+                            source: id.clone(),
+                            event: Event::Disconnected(format!("{:?}", e)),
+                        });
+                        return;
+                    }
+                };
+                for event in events {
+                    let _ = sender.send(SourceEvent {
+                        source: id.clone(),
+                        event,
+                    });
+                }
+            }
+        });
+
+        self.state = SourceState::Running(handle);
+        Ok(())
+    }
+
+    fn join(&mut self, channel: &str) -> SourceResult<()> {
+        self.client.subscribe(channel)
+    }
+
+    /// Sends a message to a stream topic, or a private message to one or more users
+    fn send(&mut self, dst: Channel, msg: MessageContent) -> SourceResult<()> {
+        if let SourceState::Disconnected = self.state {
+            return Err(SourceError::Disconnected(self.id.clone()));
+        }
+        let content = match msg {
+            MessageContent::Text(ref t) => t.clone(),
+            MessageContent::Me(ref t) => format!("/me {}", t),
+            MessageContent::Embed(ref embed) => embed.to_text(),
+            _ => return Err(SourceError::InvalidMessage(self.id.clone(), Box::new(msg))),
+        };
+        self.client.send(&dst, &content)
+    }
+
+    fn reconnect(&mut self) -> SourceResult<()> {
+        // the thread of the previous connection ends after reporting the disconnection
+        let state = mem::replace(&mut self.state, SourceState::Disconnected);
+        if let SourceState::Running(handle) = state {
+            let _ = handle.join();
+        }
+        self.connect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sources::mock_server::{expect_connected, next_message, MockRequest, MockServer};
+    use std::sync::mpsc::channel;
+
+    fn server_api(req: &MockRequest) -> String {
+        let path = &req.url[API.len()..];
+        let resp = if path.starts_with("/users/me/subscriptions") {
+            json!({ "result": "success" })
+        } else if path.starts_with("/users/me") {
+            json!({ "result": "success", "full_name": "Bot", "email": "bot@example.com" })
+        } else if path.starts_with("/register") {
+            json!({ "result": "success", "queue_id": "q1", "last_event_id": -1 })
+        } else if path.starts_with("/events") && path.contains("last_event_id=-1") {
+            let bot = json!({ "email": "bot@example.com" });
+            let alice = json!({ "email": "alice@example.com" });
+            let bob = json!({ "email": "bob@example.com" });
+            json!({ "result": "success", "events": [
+                { "type": "message", "id": 0, "message": {
+                    "type": "stream", "display_recipient": "dev", "subject": "release",
+                    "sender_email": "alice@example.com", "content": "ship it",
+                } },
+                { "type": "message", "id": 1, "message": {
+                    "type": "private", "display_recipient": [alice, bot],
+                    "sender_email": "alice@example.com", "content": "/me waves",
+                } },
+                { "type": "message", "id": 2, "message": {
+                    "type": "private", "display_recipient": [alice, bob, bot],
+                    "sender_email": "alice@example.com", "content": "hi both",
+                } },
+            ] })
+        } else if path.starts_with("/events") {
+            thread::sleep(Duration::from_millis(50));
+            json!({ "result": "success", "events": [{ "type": "heartbeat", "id": 3 }] })
+        } else {
+            json!({ "result": "success", "id": 42 })
+        };
+        resp.to_string()
+    }
+
+    #[test]
+    fn test_messages_and_send() {
+        let server = MockServer::start(server_api);
+        let config = format!(
+            "site = \"{}\"\nemail = \"bot@example.com\"\napi_key = \"key\"\nstreams = [\"dev\"]",
+            server.url()
+        );
+        let (tx, rx) = channel();
+        let mut source = ZulipSource::new(
+            SourceId("zulip".to_owned()),
+            tx,
+            Some(toml::from_str(&config).unwrap()),
+        );
+        source.connect().unwrap();
+        assert_eq!(source.get_nick(), "Bot");
+        expect_connected(&rx);
+
+        let msg = next_message(&rx);
+        let release = Channel::Thread("dev".to_owned(), "release".to_owned());
+        assert_eq!(msg.author, "alice@example.com");
+        assert_eq!(msg.channel, release);
+        let msg = next_message(&rx);
+        assert_eq!(msg.channel, Channel::User("alice@example.com".to_owned()));
+        match msg.content {
+            MessageContent::Me(ref t) => assert_eq!(t, "waves"),
+            ref content => panic!("unexpected content: {:?}", content),
+        }
+        let msg = next_message(&rx);
+        assert_eq!(
+            msg.channel,
+            Channel::Group(vec![
+                "alice@example.com".to_owned(),
+                "bob@example.com".to_owned()
+            ])
+        );
+
+        source
+            .send(release, MessageContent::Text("on it".to_owned()))
+            .unwrap();
+        source
+            .send(
+                Channel::User("alice@example.com".to_owned()),
+                MessageContent::Text("hello".to_owned()),
+            )
+            .unwrap();
+        let sent = server.requests(&format!("{}/messages", API));
+        assert_eq!(sent.len(), 2);
+        assert_eq!(
+            sent[0].body,
+            "type=stream&to=dev&content=on+it&topic=release"
+        );
+        assert_eq!(
+            sent[1].body,
+            "type=private&to=%5B%22alice%40example.com%22%5D&content=hello"
+        );
+        let subscriptions = server.requests(&format!("{}/users/me/subscriptions", API));
+        assert_eq!(subscriptions.len(), 1);
+
+        // the next poll acknowledges the received events
+        thread::sleep(Duration::from_millis(100));
+        assert!(server
+            .requests(&format!("{}/events", API))
+            .iter()
+            .any(|req| req.url.contains("last_event_id=2")));
+    }
+}